@@ -52,6 +52,7 @@ struct emu_data {
 	int emu_modified;	/* is the image modified since reading it? */
 	uint8_t emu_status[3];
 	uint8_t emu_status_len;	/* number of emulated status registers */
+	char *emu_status_file;	/* persists emu_status between runs */
 	/* If "freq" parameter is passed in from command line, commands will delay
 	 * for this period before returning. */
 	unsigned long int delay_us;
@@ -911,7 +912,14 @@ static int dummy_shutdown(void *data)
 					  emu_data->emu_chip_size,
 					  emu_data->emu_persistent_image);
 		}
+		if (emu_data->emu_status_file && emu_data->emu_status_len) {
+			msg_pdbg("Writing %s\n", emu_data->emu_status_file);
+			write_buf_to_file(emu_data->emu_status,
+					  emu_data->emu_status_len,
+					  emu_data->emu_status_file);
+		}
 		free(emu_data->emu_persistent_image);
+		free(emu_data->emu_status_file);
 		free(emu_data->flashchip_contents);
 	}
 	free(data);
@@ -1370,6 +1378,28 @@ static int dummy_init(void)
 			data->erase_to_zero ? 0x00 : 0xff, data->emu_chip_size);
 	memset(data->flashchip_contents, data->erase_to_zero ? 0x00 : 0xff, data->emu_chip_size);
 
+	/* Will be freed by shutdown function if necessary. */
+	data->emu_status_file = extract_programmer_param_str("spi_status_file");
+	/* Like the image, silently ignore a missing status file or one that doesn't match the chip. */
+	if (data->emu_status_file && !stat(data->emu_status_file, &image_stat)) {
+		if (data->emu_status_len &&
+		    (uintmax_t)image_stat.st_size == data->emu_status_len) {
+			msg_pdbg("Reading %s\n", data->emu_status_file);
+			if (read_buf_from_file(data->emu_status, data->emu_status_len,
+					       data->emu_status_file)) {
+				msg_perr("Unable to read %s\n", data->emu_status_file);
+				free(data->emu_status_file);
+				free(data->flashchip_contents);
+				free(data);
+				return 1;
+			}
+			update_write_protection(data);
+		} else {
+			msg_pdbg("Status register file %s doesn't match the emulated chip.\n",
+				 data->emu_status_file);
+		}
+	}
+
 	/* Will be freed by shutdown function if necessary. */
 	data->emu_persistent_image = extract_programmer_param_str("image");
 	if (!data->emu_persistent_image) {
@@ -1388,6 +1418,7 @@ static int dummy_init(void)
 					   data->emu_persistent_image)) {
 				msg_perr("Unable to read %s\n", data->emu_persistent_image);
 				free(data->emu_persistent_image);
+				free(data->emu_status_file);
 				free(data->flashchip_contents);
 				free(data);
 				return 1;
@@ -1400,6 +1431,7 @@ static int dummy_init(void)
 dummy_init_out:
 	if (register_shutdown(dummy_shutdown, data)) {
 		free(data->emu_persistent_image);
+		free(data->emu_status_file);
 		free(data->flashchip_contents);
 		free(data);
 		return 1;
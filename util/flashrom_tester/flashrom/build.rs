@@ -0,0 +1,4 @@
+fn main() {
+    #[cfg(feature = "libflashrom")]
+    pkg_config::probe_library("flashrom").expect("Failed to find libflashrom with pkg-config");
+}
@@ -0,0 +1,398 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+//! The flash chips flashrom supports, and how well their support is tested.
+//!
+//! flashrom lists neither the JEDEC IDs of the chips it supports nor, before
+//! it gained a column for it, whether their write protection was tested. IDs
+//! are only known for chips which have been probed, and the write protect
+//! status is None where flashrom does not report it.
+
+use crate::{ChipInfo, FlashromError};
+
+use std::fmt;
+
+/// Whether an operation on a flash chip is known to work, as recorded by
+/// flashrom.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TestStatus {
+    Ok,
+    Untested,
+    /// The operation is known not to work.
+    Bad,
+    /// The operation does not apply to the chip.
+    NotApplicable,
+}
+
+impl fmt::Display for TestStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TestStatus::Ok => "tested",
+            TestStatus::Untested => "untested",
+            TestStatus::Bad => "known to be broken",
+            TestStatus::NotApplicable => "not applicable",
+        })
+    }
+}
+
+/// The test status of each operation on a flash chip.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tested {
+    pub probe: TestStatus,
+    pub read: TestStatus,
+    pub erase: TestStatus,
+    pub write: TestStatus,
+    /// Write protection, if flashrom records whether it is tested.
+    pub wp: Option<TestStatus>,
+}
+
+/// A flash chip definition from flashrom's list of supported chips.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SupportedChip {
+    pub vendor: String,
+    pub name: String,
+    /// Size of the chip in bytes.
+    pub size: i64,
+    /// The buses the chip can be attached by, such as `SPI` or `LPC`, if
+    /// known.
+    pub buses: Vec<String>,
+    pub tested: Tested,
+    /// JEDEC manufacturer and model ID, if known from probing.
+    pub id: Option<(u32, u32)>,
+}
+
+/// The flash chips flashrom supports, queryable by name or ID.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChipDatabase {
+    chips: Vec<SupportedChip>,
+}
+
+impl ChipDatabase {
+    pub fn new(chips: Vec<SupportedChip>) -> Self {
+        ChipDatabase { chips }
+    }
+
+    /// Parse the list of supported chips printed by `flashrom -L`.
+    ///
+    /// Each chip is a row of fixed width columns, where long names continue
+    /// on the following lines:
+    ///
+    /// ```text
+    /// Supported flash chips (total: 576):
+    ///
+    /// Vendor      Device          Test  Known   Size   Type
+    ///                             OK    Broken  [kB]
+    ///
+    /// (P = PROBE, R = READ, E = ERASE, W = WRITE, - = N/A)
+    ///
+    /// AMIC        A25LQ032/                      4096  SPI
+    ///             A25LQ32A
+    /// Winbond     W25Q128.V       PREW          16384  SPI
+    /// ```
+    ///
+    /// The operations in the test columns are those in the legend above the
+    /// chips, so write protection is included once flashrom lists it there.
+    pub fn parse(output: &str) -> Result<Self, FlashromError> {
+        const TITLE: &str = "Supported flash chips (total: ";
+
+        let mut lines = output.lines().skip_while(|l| !l.starts_with(TITLE));
+        let total = lines
+            .next()
+            .and_then(|l| l[TITLE.len()..].split(')').next()?.parse::<usize>().ok())
+            .ok_or_else(|| parse_error("Didn't find a list of supported chips"))?;
+
+        let header = lines
+            .find(|l| l.starts_with("Vendor"))
+            .ok_or_else(|| parse_error("Didn't find the header of the chip list"))?;
+        let column = |name| {
+            header
+                .find(name)
+                .ok_or_else(|| parse_error(&format!("No {} column in the chip list", name)))
+        };
+        let (device, test, known, size, bus) = (
+            column("Device")?,
+            column("Test")?,
+            column("Known")?,
+            // Sizes are right-aligned under the space before the heading.
+            column(" Size")?,
+            column("Type")?,
+        );
+
+        let legend = lines
+            .find(|l| l.starts_with('('))
+            .ok_or_else(|| parse_error("Didn't find the legend of the chip list"))?;
+        let ops = parse_legend(legend);
+
+        let mut chips: Vec<SupportedChip> = Vec::new();
+        for line in lines {
+            if line.starts_with("Supported ") {
+                // The start of the next list.
+                break;
+            }
+            let (vendor, name) = (field(line, 0, device), field(line, device, test));
+            if field(line, test, line.len()).is_empty() {
+                // The rest of the names of the previous chip.
+                if let Some(chip) = chips.last_mut() {
+                    chip.vendor.push_str(vendor);
+                    chip.name.push_str(name);
+                }
+                continue;
+            }
+
+            let kb: i64 = field(line, size, bus)
+                .parse()
+                .map_err(|_| parse_error(&format!("Bad chip size in {:?}", line)))?;
+            let status = |op: Op| {
+                let i = ops.iter().position(|o| *o == Some(op))?;
+                let ok = line.get(test..known)?.chars().nth(i);
+                let bad = line.get(known..size)?.chars().nth(i);
+                Some(match (ok, bad) {
+                    (_, Some(c)) if c != ' ' => TestStatus::Bad,
+                    (Some('-'), _) => TestStatus::NotApplicable,
+                    (Some(c), _) if c != ' ' => TestStatus::Ok,
+                    _ => TestStatus::Untested,
+                })
+            };
+            let status_of = |op: Op| status(op).unwrap_or(TestStatus::Untested);
+            chips.push(SupportedChip {
+                vendor: vendor.into(),
+                name: name.into(),
+                size: kb * 1024,
+                buses: field(line, bus, line.len())
+                    .split(", ")
+                    .map(String::from)
+                    .collect(),
+                tested: Tested {
+                    probe: status_of(Op::Probe),
+                    read: status_of(Op::Read),
+                    erase: status_of(Op::Erase),
+                    write: status_of(Op::Write),
+                    wp: status(Op::Wp),
+                },
+                id: None,
+            });
+        }
+
+        if chips.len() != total {
+            return Err(parse_error(&format!(
+                "Found {} chips in the list, but flashrom says there are {}",
+                chips.len(),
+                total
+            )));
+        }
+        Ok(ChipDatabase { chips })
+    }
+
+    /// Return every supported chip.
+    pub fn chips(&self) -> &[SupportedChip] {
+        &self.chips
+    }
+
+    /// Return the chips with definitions named `name`, as chosen with `-c`.
+    pub fn by_name(&self, name: &str) -> Vec<&SupportedChip> {
+        self.chips.iter().filter(|c| c.name == name).collect()
+    }
+
+    /// Return the chips with the JEDEC manufacturer and model `id`.
+    ///
+    /// Only chips which have been probed have a known ID.
+    pub fn by_id(&self, id: (u32, u32)) -> Vec<&SupportedChip> {
+        self.chips.iter().filter(|c| c.id == Some(id)).collect()
+    }
+
+    /// Return the definition of a chip found by probing.
+    pub fn find(&self, chip: &ChipInfo) -> Option<&SupportedChip> {
+        self.chips
+            .iter()
+            .find(|c| c.vendor == chip.vendor && c.name == chip.name)
+    }
+
+    /// Record the IDs of `probed` chips, so they can be found by ID.
+    pub fn add_probed(&mut self, probed: &[ChipInfo]) {
+        for chip in probed.iter().filter(|c| c.id.is_some()) {
+            for c in self
+                .chips
+                .iter_mut()
+                .filter(|c| c.vendor == chip.vendor && c.name == chip.name)
+            {
+                c.id = chip.id;
+            }
+        }
+    }
+}
+
+/// Operations listed in the test columns.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Op {
+    Probe,
+    Read,
+    Erase,
+    Write,
+    Wp,
+}
+
+/// Parse the operations of the test columns, in order, from a legend like
+/// `(P = PROBE, R = READ, E = ERASE, W = WRITE, - = N/A)`.
+fn parse_legend(legend: &str) -> Vec<Option<Op>> {
+    legend
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(", ")
+        .filter(|entry| !entry.starts_with("- "))
+        .map(|entry| {
+            let name = entry.split(" = ").nth(1)?.to_ascii_uppercase();
+            Some(match name.as_str() {
+                "PROBE" => Op::Probe,
+                "READ" => Op::Read,
+                "ERASE" => Op::Erase,
+                "WRITE" => Op::Write,
+                "WP" => Op::Wp,
+                _ if name.contains("PROTECT") => Op::Wp,
+                _ => return None,
+            })
+        })
+        .collect()
+}
+
+/// Whether a chip definition is a placeholder for unidentified chips, which
+/// flashrom leaves out of its list of supported chips.
+#[cfg(feature = "libflashrom")]
+pub(crate) fn is_generic(vendor: &str, name: &str) -> bool {
+    vendor.starts_with("Unknown") || vendor.starts_with("Programmer") || name.starts_with("unknown")
+}
+
+/// Return the trimmed text of `line` between the columns `start` and `end`.
+fn field(line: &str, start: usize, end: usize) -> &str {
+    let end = end.min(line.len());
+    line.get(start.min(end)..end).unwrap_or("").trim()
+}
+
+fn parse_error(message: &str) -> FlashromError {
+    FlashromError::Parse(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST: &str = "\
+flashrom v1.4.0 on Linux 6.1.0 (x86_64)
+Supported flash chips (total: 3):
+
+Vendor      Device          Test  Known   Size   Type
+                            OK    Broken  [kB]
+
+(P = PROBE, R = READ, E = ERASE, W = WRITE, - = N/A)
+
+AMIC        A25LQ032/                W     4096  SPI
+            A25LQ32A
+Winbond     W25Q128.V       PREW          16384  SPI
+SST         SST49LF040      PR-             512  LPC, FWH
+
+Supported programmers:
+";
+
+    #[test]
+    fn parse() {
+        let db = ChipDatabase::parse(LIST).unwrap();
+        assert_eq!(
+            db.by_name("A25LQ032/A25LQ32A"),
+            vec![&SupportedChip {
+                vendor: "AMIC".into(),
+                name: "A25LQ032/A25LQ32A".into(),
+                size: 4096 * 1024,
+                buses: vec!["SPI".into()],
+                tested: Tested {
+                    probe: TestStatus::Untested,
+                    read: TestStatus::Untested,
+                    erase: TestStatus::Untested,
+                    write: TestStatus::Bad,
+                    wp: None,
+                },
+                id: None,
+            }]
+        );
+
+        let w25q = db.by_name("W25Q128.V")[0];
+        assert_eq!(w25q.tested.write, TestStatus::Ok);
+        assert_eq!(w25q.size, 16 << 20);
+
+        let sst = db.by_name("SST49LF040")[0];
+        assert_eq!(sst.buses, vec!["LPC", "FWH"]);
+        assert_eq!(sst.tested.read, TestStatus::Ok);
+        assert_eq!(sst.tested.erase, TestStatus::NotApplicable);
+        assert_eq!(sst.tested.write, TestStatus::Untested);
+
+        assert!(ChipDatabase::parse(&LIST.replace("total: 3", "total: 4")).is_err());
+        assert!(ChipDatabase::parse("flashrom v1.4.0 on Linux").is_err());
+    }
+
+    #[test]
+    fn parse_wp_column() {
+        let list = "\
+Supported flash chips (total: 2):
+
+Vendor      Device          Test   Known    Size   Type
+                            OK     Broken   [kB]
+
+(P = PROBE, R = READ, E = ERASE, W = WRITE, T = WRITE PROTECT, - = N/A)
+
+Winbond     W25Q128.V       PREWT           16384  SPI
+Winbond     W25Q64.V        PREW             8192  SPI
+";
+        let db = ChipDatabase::parse(list).unwrap();
+        assert_eq!(db.by_name("W25Q128.V")[0].tested.wp, Some(TestStatus::Ok));
+        assert_eq!(
+            db.by_name("W25Q64.V")[0].tested.wp,
+            Some(TestStatus::Untested)
+        );
+    }
+
+    #[test]
+    fn query_probed() {
+        let mut db = ChipDatabase::parse(LIST).unwrap();
+        let probed = ChipInfo {
+            vendor: "Winbond".into(),
+            name: "W25Q128.V".into(),
+            size: 16 << 20,
+            id: Some((0xef, 0x4018)),
+        };
+        assert!(db.by_id((0xef, 0x4018)).is_empty());
+
+        db.add_probed(std::slice::from_ref(&probed));
+        assert_eq!(db.by_id((0xef, 0x4018)), vec![db.find(&probed).unwrap()]);
+        assert!(db.by_name("SST49LF040")[0].id.is_none());
+    }
+}
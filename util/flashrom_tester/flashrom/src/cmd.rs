@@ -33,43 +33,351 @@
 // Software Foundation.
 //
 
-use crate::{FlashChip, FlashromError, ROMWriteSpecifics};
+use crate::dryrun::dry_run;
+use crate::progress::ProgressParser;
+use crate::{
+    Capabilities, ChipDatabase, ChipInfo, Flashrom, FlashromError, FlashromVersion, LayoutSource,
+    Programmer, ProgressCallback, ROMWriteSpecifics, RegionFile, WpMode, WpRange, WpStatus,
+};
+
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Options to run flashrom with.
+///
+/// Use `FlashromOpt::builder()` to construct these, which rejects options that
+/// contradict each other.
+#[derive(Default)]
+pub struct FlashromOpt<'a> {
+    pub(crate) wp_opt: WPOpt<'a>,
+    pub(crate) io_opt: IOOpt<'a>,
+
+    pub(crate) layout: Option<&'a Path>,    // -l <file>
+    pub(crate) fmap: bool,                  // --fmap
+    pub(crate) fmap_file: Option<&'a Path>, // --fmap-file <file>
+    pub(crate) ifd: bool,                   // --ifd
+    pub(crate) image: Vec<OsString>,        // -i <name>...
+
+    pub(crate) chip: Option<&'a str>,    // -c <chip>
+    pub(crate) force: bool,              // --force
+    pub(crate) flash_name: bool,         // --flash-name
+    pub(crate) flash_size: bool,         // --flash-size
+    pub(crate) progress: bool,           // --progress
+    pub(crate) output: Option<&'a Path>, // -o <logfile>
+    pub(crate) verbose: bool,            // -V
+}
 
-use std::process::Command;
+#[derive(Default)]
+pub struct WPOpt<'a> {
+    pub(crate) range: Option<(i64, i64)>, // --wp-range x0 x1
+    pub(crate) region: Option<&'a str>,   // --wp-region <name>
+    pub(crate) status: bool,              // --wp-status
+    pub(crate) list: bool,                // --wp-list
+    pub(crate) enable: bool,              // --wp-enable
+    pub(crate) disable: bool,             // --wp-disable
+}
 
 #[derive(Default)]
-pub struct FlashromOpt<'a> {
-    pub wp_opt: WPOpt,
-    pub io_opt: IOOpt<'a>,
+pub struct IOOpt<'a> {
+    pub(crate) read: Option<&'a Path>,           // -r <file>
+    pub(crate) write: Option<&'a Path>,          // -w <file>
+    pub(crate) write_regions: bool,              // -w, from the files given with -i
+    pub(crate) verify: Option<&'a Path>,         // -v <file>
+    pub(crate) erase: bool,                      // -E
+    pub(crate) extract: bool,                    // --extract
+    pub(crate) noverify: bool,                   // --noverify
+    pub(crate) noverify_all: bool,               // --noverify-all
+    pub(crate) flash_contents: Option<&'a Path>, // --flash-contents <file>
+}
 
-    pub layout: Option<&'a str>, // -l <file>
-    pub image: Option<&'a str>,  // -i <name>
+impl IOOpt<'_> {
+    /// Return true if an operation on the flash contents is set.
+    fn any_op(&self) -> bool {
+        self.read.is_some()
+            || self.write.is_some()
+            || self.write_regions
+            || self.verify.is_some()
+            || self.erase
+    }
+}
+
+impl<'a> FlashromOpt<'a> {
+    pub fn builder() -> FlashromOptBuilder<'a> {
+        FlashromOptBuilder::default()
+    }
+
+    /// Check for options which flashrom would refuse, or which make no sense
+    /// together.
+    fn validate(&self) -> Result<(), FlashromError> {
+        let io = &self.io_opt;
+        let wp = &self.wp_opt;
+
+        // flashrom performs a single operation on the flash contents.
+        exclusive(&[
+            ("-r", io.read.is_some()),
+            ("-w", io.write.is_some() || io.write_regions),
+            ("-v", io.verify.is_some()),
+            ("-E", io.erase),
+            ("--extract", io.extract),
+            ("--flash-name", self.flash_name),
+            ("--flash-size", self.flash_size),
+        ])?;
+        exclusive(&[
+            ("--wp-status", wp.status),
+            ("--wp-list", wp.list),
+            ("--wp-enable", wp.enable),
+            ("--wp-disable", wp.disable),
+        ])?;
+        exclusive(&[
+            ("--wp-range", wp.range.is_some()),
+            ("--wp-region", wp.region.is_some()),
+            ("--wp-list", wp.list),
+        ])?;
+        exclusive(&[
+            ("-l", self.layout.is_some()),
+            ("--fmap", self.fmap),
+            ("--fmap-file", self.fmap_file.is_some()),
+            ("--ifd", self.ifd),
+        ])?;
+        exclusive(&[("-v", io.verify.is_some()), ("--noverify", io.noverify)])?;
+
+        let has_layout = self.layout.is_some() || self.fmap || self.fmap_file.is_some() || self.ifd;
+        if wp.region.is_some() && !has_layout {
+            return Err(FlashromError::Other(
+                "--wp-region needs a layout to find the region in".into(),
+            ));
+        }
+
+        let writing = io.write.is_some() || io.write_regions;
+        if io.flash_contents.is_some() && !writing {
+            return Err(FlashromError::Other(
+                "--flash-contents only applies to writes".into(),
+            ));
+        }
+        if (io.noverify || io.noverify_all) && !writing && !io.erase {
+            return Err(FlashromError::Other(
+                "--noverify and --noverify-all only apply to writes and erases".into(),
+            ));
+        }
+        Ok(())
+    }
+}
 
-    pub flash_name: bool, // --flash-name
-    pub verbose: bool,    // -V
+/// Return an error naming the options if more than one is set.
+fn exclusive(opts: &[(&str, bool)]) -> Result<(), FlashromError> {
+    let set: Vec<&str> = opts.iter().filter(|(_, s)| *s).map(|(n, _)| *n).collect();
+    if set.len() > 1 {
+        Err(FlashromError::Other(format!(
+            "Conflicting flashrom options: {}",
+            set.join(", ")
+        )))
+    } else {
+        Ok(())
+    }
 }
 
+/// Builds a `FlashromOpt`, checking that the options can be used together.
 #[derive(Default)]
-pub struct WPOpt {
-    pub range: Option<(i64, i64)>, // --wp-range x0 x1
-    pub status: bool,              // --wp-status
-    pub list: bool,                // --wp-list
-    pub enable: bool,              // --wp-enable
-    pub disable: bool,             // --wp-disable
+pub struct FlashromOptBuilder<'a> {
+    opt: FlashromOpt<'a>,
 }
 
-#[derive(Default)]
-pub struct IOOpt<'a> {
-    pub read: Option<&'a str>,   // -r <file>
-    pub write: Option<&'a str>,  // -w <file>
-    pub verify: Option<&'a str>, // -v <file>
-    pub erase: bool,             // -E
+impl<'a> FlashromOptBuilder<'a> {
+    pub fn build(self) -> Result<FlashromOpt<'a>, FlashromError> {
+        self.opt.validate()?;
+        Ok(self.opt)
+    }
+
+    pub fn wp_range(mut self, range: (i64, i64)) -> Self {
+        self.opt.wp_opt.range = Some(range);
+        self
+    }
+
+    /// Protect the range of the region `name` of the layout.
+    pub fn wp_region(mut self, name: &'a str) -> Self {
+        self.opt.wp_opt.region = Some(name);
+        self
+    }
+
+    pub fn wp_status(mut self) -> Self {
+        self.opt.wp_opt.status = true;
+        self
+    }
+
+    pub fn wp_list(mut self) -> Self {
+        self.opt.wp_opt.list = true;
+        self
+    }
+
+    pub fn wp_enable(mut self) -> Self {
+        self.opt.wp_opt.enable = true;
+        self
+    }
+
+    pub fn wp_disable(mut self) -> Self {
+        self.opt.wp_opt.disable = true;
+        self
+    }
+
+    pub fn read<P: AsRef<Path> + ?Sized>(mut self, path: &'a P) -> Self {
+        self.opt.io_opt.read = Some(path.as_ref());
+        self
+    }
+
+    pub fn write<P: AsRef<Path> + ?Sized>(mut self, path: &'a P) -> Self {
+        self.opt.io_opt.write = Some(path.as_ref());
+        self
+    }
+
+    /// Write the regions given to `image` from their own files.
+    pub fn write_regions(mut self) -> Self {
+        self.opt.io_opt.write_regions = true;
+        self
+    }
+
+    pub fn verify<P: AsRef<Path> + ?Sized>(mut self, path: &'a P) -> Self {
+        self.opt.io_opt.verify = Some(path.as_ref());
+        self
+    }
+
+    pub fn erase(mut self) -> Self {
+        self.opt.io_opt.erase = true;
+        self
+    }
+
+    pub fn extract(mut self) -> Self {
+        self.opt.io_opt.extract = true;
+        self
+    }
+
+    pub fn noverify(mut self) -> Self {
+        self.opt.io_opt.noverify = true;
+        self
+    }
+
+    pub fn noverify_all(mut self) -> Self {
+        self.opt.io_opt.noverify_all = true;
+        self
+    }
+
+    /// Assume the flash holds the contents of `path`, rather than reading it
+    /// before writing.
+    pub fn flash_contents<P: AsRef<Path> + ?Sized>(mut self, path: &'a P) -> Self {
+        self.opt.io_opt.flash_contents = Some(path.as_ref());
+        self
+    }
+
+    pub fn layout(mut self, source: LayoutSource<'a>) -> Self {
+        match source {
+            LayoutSource::File(path) => self.opt.layout = Some(path),
+            LayoutSource::Fmap => self.opt.fmap = true,
+            LayoutSource::FmapFile(path) => self.opt.fmap_file = Some(path),
+            LayoutSource::Ifd => self.opt.ifd = true,
+        }
+        self
+    }
+
+    /// Add regions of the layout to operate on, each either `name` or
+    /// `name:file`.
+    pub fn image<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.opt
+            .image
+            .extend(names.into_iter().map(|name| name.as_ref().to_os_string()));
+        self
+    }
+
+    pub fn chip(mut self, name: &'a str) -> Self {
+        self.opt.chip = Some(name);
+        self
+    }
+
+    pub fn force(mut self) -> Self {
+        self.opt.force = true;
+        self
+    }
+
+    pub fn flash_name(mut self) -> Self {
+        self.opt.flash_name = true;
+        self
+    }
+
+    pub fn flash_size(mut self) -> Self {
+        self.opt.flash_size = true;
+        self
+    }
+
+    pub fn progress(mut self) -> Self {
+        self.opt.progress = true;
+        self
+    }
+
+    pub fn output<P: AsRef<Path> + ?Sized>(mut self, path: &'a P) -> Self {
+        self.opt.output = Some(path.as_ref());
+        self
+    }
+
+    pub fn verbose(mut self) -> Self {
+        self.opt.verbose = true;
+        self
+    }
+}
+
+/// How long flashrom may run for each kind of operation before it is killed.
+///
+/// `None` lets an operation run for as long as it takes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Reading, verifying or extracting the flash contents.
+    pub read: Option<Duration>,
+    /// Writing the flash, including the erasing and verifying that goes with it.
+    pub write: Option<Duration>,
+    pub erase: Option<Duration>,
+    /// Anything else, such as probing or changing write protection.
+    pub other: Option<Duration>,
+}
+
+impl Timeouts {
+    /// The timeout for running flashrom with `io_opt`.
+    fn for_op(&self, io_opt: &IOOpt) -> Option<Duration> {
+        if io_opt.write.is_some() || io_opt.write_regions {
+            self.write
+        } else if io_opt.erase {
+            self.erase
+        } else if io_opt.read.is_some() || io_opt.verify.is_some() || io_opt.extract {
+            self.read
+        } else {
+            self.other
+        }
+    }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Debug)]
 pub struct FlashromCmd {
-    pub path: String,
-    pub fc: FlashChip,
+    pub path: PathBuf,
+    pub programmer: Programmer,
+    /// Chip definition to use (`-c`), for a flash matched by several of them.
+    pub chip: Option<String>,
+    pub progress: ProgressCallback,
+    pub timeouts: Timeouts,
+    /// Setting this kills flashrom if it is running. Runs started after it is
+    /// set are unaffected, so the flash can still be restored.
+    pub terminate: Option<&'static AtomicBool>,
+    /// What the flashrom binary supports. Found by running `flashrom
+    /// --version` when first needed, unless set beforehand.
+    pub capabilities: OnceLock<Capabilities>,
+    /// Hardware write protect signal emulated by the dummy programmer. It is
+    /// read when flashrom starts, so changes apply from the next run.
+    pub emulated_hw_wp: AtomicBool,
 }
 
 /// Attempt to determine the Flash size given stdout from `flashrom --flash-size`
@@ -79,367 +387,973 @@ fn flashrom_extract_size(stdout: &str) -> Result<i64, FlashromError> {
     // to stdout.
     match stdout
         .lines()
-        .filter(|line| line.chars().all(|c| c.is_ascii_digit()))
-        .last()
+        .rfind(|line| line.chars().all(|c| c.is_ascii_digit()))
         .map(str::parse::<i64>)
     {
-        None => return Err("Found no purely-numeric lines in flashrom output".into()),
-        Some(Err(e)) => {
-            return Err(format!("Failed to parse flashrom size output as integer: {}", e).into())
-        }
+        None => Err(FlashromError::Parse(
+            "Found no purely-numeric lines in flashrom output".into(),
+        )),
+        Some(Err(e)) => Err(FlashromError::Parse(format!(
+            "Failed to parse flashrom size output as integer: {}",
+            e
+        ))),
         Some(Ok(sz)) => Ok(sz),
     }
 }
 
+/// An operation `FlashromCmd` runs flashrom for.
+pub(crate) enum Op<'a> {
+    Size,
+    Name,
+    /// Probe for every chip definition matching the flash.
+    Probe,
+    WriteLayout(&'a ROMWriteSpecifics<'a>),
+    WpRange((i64, i64), bool),
+    WpRegion(&'a str, LayoutSource<'a>, bool),
+    WpList,
+    WpStatus,
+    /// Enable write protect over a flash of the given size, or disable it.
+    WpToggle(Option<i64>),
+    ReadRegion(LayoutSource<'a>, &'a [&'a str], &'a Path),
+    EraseRegion(LayoutSource<'a>, &'a [&'a str]),
+    Read(&'a Path),
+    Write(&'a Path),
+    Verify(&'a Path),
+    Erase,
+}
+
+impl<'a> Op<'a> {
+    /// The options flashrom is run with for this operation.
+    pub(crate) fn opts(&self) -> Result<FlashromOpt<'a>, FlashromError> {
+        let opts = FlashromOpt::builder();
+        match *self {
+            Op::Size => opts.flash_size(),
+            Op::Name => opts.flash_name(),
+            // Without an operation flashrom only probes.
+            Op::Probe => opts.verbose(),
+            Op::WriteLayout(rws) => {
+                let mut opts = opts.image(rws.regions.iter().map(RegionFile::to_arg));
+                opts = match rws.write_file {
+                    Some(file) => opts.write(file),
+                    None => opts.write_regions(),
+                };
+                match rws.layout {
+                    Some(source) => opts.layout(source),
+                    None => opts,
+                }
+            }
+            Op::WpRange(range, true) => opts.wp_range(range).wp_enable(),
+            Op::WpRange(range, false) => opts.wp_range(range),
+            Op::WpRegion(name, layout, true) => opts.wp_region(name).layout(layout).wp_enable(),
+            Op::WpRegion(name, layout, false) => opts.wp_region(name).layout(layout),
+            Op::WpList => opts.wp_list(),
+            Op::WpStatus => opts.wp_status(),
+            // For MTD, --wp-range and --wp-enable must be used simultaneously.
+            Op::WpToggle(Some(rom_sz)) => opts.wp_range((0, rom_sz)).wp_enable(),
+            Op::WpToggle(None) => opts.wp_disable(),
+            Op::ReadRegion(layout, regions, path) => opts.read(path).layout(layout).image(regions),
+            Op::EraseRegion(layout, regions) => opts.erase().layout(layout).image(regions),
+            Op::Read(path) => opts.read(path),
+            Op::Write(path) => opts.write(path),
+            Op::Verify(path) => opts.verify(path),
+            Op::Erase => opts.erase(),
+        }
+        .build()
+    }
+}
+
 impl FlashromCmd {
-    fn dispatch(&self, fropt: FlashromOpt) -> Result<(Vec<u8>, Vec<u8>), FlashromError> {
-        let params = flashrom_decode_opts(fropt);
-        flashrom_dispatch(self.path.as_str(), &params, self.fc)
+    /// Run flashrom with `fropt`, returning its stdout and stderr.
+    ///
+    /// The chip pinned by `chip` is used unless `fropt` names another, and
+    /// progress is reported to `progress` if it is set. flashrom is killed if
+    /// it runs out of the matching timeout or `terminate` becomes set.
+    pub fn dispatch(&self, fropt: FlashromOpt) -> Result<(Vec<u8>, Vec<u8>), FlashromError> {
+        let limits = self.limits(self.timeouts.for_op(&fropt.io_opt));
+        let params = self.params(fropt, true)?;
+
+        let mut parser = ProgressParser::default();
+        let programmer = self.programmer_arg();
+        flashrom_dispatch(&self.path, &params, &programmer, limits, |data| {
+            parser.feed(data, |p| self.progress.report(p))
+        })
+    }
+
+    /// Return the argument to `-p` selecting the programmer.
+    fn programmer_arg(&self) -> String {
+        programmer_string(&self.programmer, self.emulated_hw_wp())
+    }
+
+    /// Return the command line flashrom would be run with for `op`.
+    pub(crate) fn command_line(&self, op: &Op) -> Result<String, FlashromError> {
+        let params = self.params(op.opts()?, !matches!(op, Op::Probe))?;
+        let mut cmdline = format!("{} -p {}", self.path.display(), self.programmer_arg());
+        for param in params {
+            cmdline.push(' ');
+            cmdline.push_str(&param.to_string_lossy());
+        }
+        Ok(cmdline)
+    }
+
+    /// The parameters flashrom is run with for `fropt`, after the programmer,
+    /// in the dialect of the flashrom binary.
+    ///
+    /// The chip pinned by `chip` is added if `pin` is set. Fails with an error
+    /// of kind `ErrorKind::Unsupported` if the binary lacks an option.
+    fn params(&self, fropt: FlashromOpt, pin: bool) -> Result<Vec<OsString>, FlashromError> {
+        let caps = self.capabilities();
+        let wp = &fropt.wp_opt;
+        let wp_set = wp.range.is_some()
+            || wp.region.is_some()
+            || wp.status
+            || wp.list
+            || wp.enable
+            || wp.disable;
+        let needed = Capabilities {
+            fmap: fropt.fmap || fropt.fmap_file.is_some(),
+            wp: wp_set,
+            wp_region: wp.region.is_some(),
+            ..Capabilities::NONE
+        };
+        if let Some(missing) = caps.missing(&needed).first() {
+            return Err(FlashromError::Unsupported(format!(
+                "{} does not support {}",
+                caps, missing
+            )));
+        }
+
+        let mut fropt = fropt;
+        fropt.progress |= caps.progress && self.progress.is_set() && fropt.io_opt.any_op();
+
+        let pinned = self.chip.as_ref().filter(|_| pin && fropt.chip.is_none());
+        let mut params = flashrom_decode_opts(fropt);
+        if !caps.flash_size {
+            for param in params.iter_mut().filter(|p| *p == "--flash-size") {
+                *param = "--get-size".into();
+            }
+        }
+        if let Some(chip) = pinned {
+            params.push("-c".into());
+            params.push(chip.into());
+        }
+        Ok(params)
+    }
+
+    /// Find out what the flashrom binary supports from its version.
+    fn detect_capabilities(&self) -> Capabilities {
+        let limits = self.limits(self.timeouts.other);
+        let version = match run_command(&self.path, &["--version"], limits) {
+            Ok((stdout, _)) => FlashromVersion::parse(&String::from_utf8_lossy(&stdout)),
+            Err(e) => {
+                warn!("Failed to find the version of flashrom: {}", e);
+                None
+            }
+        };
+        let caps = Capabilities::of_version(version);
+        info!("Using {}: {:?}", caps, caps);
+        caps
+    }
+
+    fn limits(&self, timeout: Option<Duration>) -> Limits<'static> {
+        Limits {
+            timeout,
+            cancel: self.terminate,
+            group: false,
+        }
     }
 }
 
 impl crate::Flashrom for FlashromCmd {
     fn get_size(&self) -> Result<i64, FlashromError> {
-        let (stdout, _) = flashrom_dispatch(self.path.as_str(), &["--flash-size"], self.fc)?;
+        let (stdout, _) = self.dispatch(Op::Size.opts()?)?;
         let sz = String::from_utf8_lossy(&stdout);
 
         flashrom_extract_size(&sz)
     }
 
     fn name(&self) -> Result<(String, String), FlashromError> {
-        let opts = FlashromOpt {
-            io_opt: IOOpt {
-                ..Default::default()
-            },
-
-            flash_name: true,
-
-            ..Default::default()
-        };
-
-        let (stdout, stderr) = self.dispatch(opts)?;
+        let (stdout, _) = self.dispatch(Op::Name.opts()?)?;
         let output = String::from_utf8_lossy(stdout.as_slice());
-        let eoutput = String::from_utf8_lossy(stderr.as_slice());
-        debug!("name()'stdout: {:#?}.", output);
-        debug!("name()'stderr: {:#?}.", eoutput);
 
         match extract_flash_name(&output) {
-            None => Err("Didn't find chip vendor/name in flashrom output".into()),
+            None => Err(FlashromError::Parse(
+                "Didn't find chip vendor/name in flashrom output".into(),
+            )),
             Some((vendor, name)) => Ok((vendor.into(), name.into())),
         }
     }
 
-    fn write_file_with_layout(&self, rws: &ROMWriteSpecifics) -> Result<bool, FlashromError> {
-        let opts = FlashromOpt {
-            io_opt: IOOpt {
-                write: rws.write_file,
-                ..Default::default()
-            },
-
-            layout: rws.layout_file,
-            image: rws.name_file,
-
-            ..Default::default()
+    fn probe(&self) -> Result<Vec<ChipInfo>, FlashromError> {
+        // Any pinned chip is left out, so every matching definition is found.
+        let params = self.params(Op::Probe.opts()?, false)?;
+        let limits = self.limits(self.timeouts.other);
+        let programmer = self.programmer_arg();
+        let stdout = match flashrom_dispatch(&self.path, &params, &programmer, limits, |_| ()) {
+            Ok((stdout, _)) => String::from_utf8_lossy(&stdout).into_owned(),
+            // flashrom fails if several definitions match, but still prints them.
+            Err(FlashromError::Exit { ref stdout, .. }) if !parse_probe(stdout).is_empty() => {
+                stdout.clone()
+            }
+            Err(e) => return Err(e),
         };
 
-        let (stdout, stderr) = self.dispatch(opts)?;
-        let output = String::from_utf8_lossy(stdout.as_slice());
-        let eoutput = String::from_utf8_lossy(stderr.as_slice());
-        debug!("write_file_with_layout()'stdout:\n{}.", output);
-        debug!("write_file_with_layout()'stderr:\n{}.", eoutput);
+        let chips = parse_probe(&stdout);
+        debug!("probe(): {:?}", chips);
+        if chips.is_empty() {
+            return Err(FlashromError::Parse(
+                "Didn't find any flash chip in flashrom output".into(),
+            ));
+        }
+        Ok(chips)
+    }
+
+    fn write_file_with_layout(&self, rws: &ROMWriteSpecifics) -> Result<bool, FlashromError> {
+        self.dispatch(Op::WriteLayout(rws).opts()?)?;
         Ok(true)
     }
 
     fn wp_range(&self, range: (i64, i64), wp_enable: bool) -> Result<bool, FlashromError> {
-        let opts = FlashromOpt {
-            wp_opt: WPOpt {
-                range: Some(range),
-                enable: wp_enable,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        let (stdout, stderr) = self.dispatch(opts)?;
-        let output = String::from_utf8_lossy(stdout.as_slice());
-        let eoutput = String::from_utf8_lossy(stderr.as_slice());
-        debug!("wp_range()'stdout:\n{}.", output);
-        debug!("wp_range()'stderr:\n{}.", eoutput);
+        self.dispatch(Op::WpRange(range, wp_enable).opts()?)?;
         Ok(true)
     }
 
-    fn wp_list(&self) -> Result<String, FlashromError> {
-        let opts = FlashromOpt {
-            wp_opt: WPOpt {
-                list: true,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        let (stdout, _) = self.dispatch(opts)?;
-        let output = String::from_utf8_lossy(stdout.as_slice());
-        if output.len() == 0 {
-            return Err(
-                "wp_list isn't supported on platforms using the Linux kernel SPI driver wp_list"
-                    .into(),
-            );
-        }
-        Ok(output.to_string())
+    fn wp_region(
+        &self,
+        name: &str,
+        layout: LayoutSource,
+        wp_enable: bool,
+    ) -> Result<bool, FlashromError> {
+        self.dispatch(Op::WpRegion(name, layout, wp_enable).opts()?)?;
+        Ok(true)
     }
 
-    fn wp_status(&self, en: bool) -> Result<bool, FlashromError> {
-        let status = if en { "en" } else { "dis" };
-        info!("See if chip write protect is {}abled", status);
+    fn wp_list(&self) -> Result<Vec<WpRange>, FlashromError> {
+        let (stdout, _) = self.dispatch(Op::WpList.opts()?)?;
+        let output = String::from_utf8_lossy(stdout.as_slice());
 
-        let opts = FlashromOpt {
-            wp_opt: WPOpt {
-                status: true,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
+        parse_wp_list(&output)
+    }
 
-        let (stdout, _) = self.dispatch(opts)?;
+    fn get_wp_status(&self) -> Result<WpStatus, FlashromError> {
+        let (stdout, _) = self.dispatch(Op::WpStatus.opts()?)?;
         let output = String::from_utf8_lossy(stdout.as_slice());
 
-        debug!("wp_status():\n{}", output);
-
-        let s = std::format!("write protect is {}abled", status);
-        Ok(output.contains(&s))
+        parse_wp_status(&output)
     }
 
     fn wp_toggle(&self, en: bool) -> Result<bool, FlashromError> {
-        let status = if en { "en" } else { "dis" };
-
-        // For MTD, --wp-range and --wp-enable must be used simultaneously.
-        let range = if en {
-            let rom_sz: i64 = self.get_size()?;
-            Some((0, rom_sz)) // (start, len)
-        } else {
-            None
-        };
-
-        let opts = FlashromOpt {
-            wp_opt: WPOpt {
-                range: range,
-                enable: en,
-                disable: !en,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        let (stdout, stderr) = self.dispatch(opts)?;
-        let output = String::from_utf8_lossy(stdout.as_slice());
-        let eoutput = String::from_utf8_lossy(stderr.as_slice());
+        let rom_sz = if en { Some(self.get_size()?) } else { None };
+        self.dispatch(Op::WpToggle(rom_sz).opts()?)?;
+        check_wp_toggled(self, en)
+    }
 
-        debug!("wp_toggle()'stdout:\n{}.", output);
-        debug!("wp_toggle()'stderr:\n{}.", eoutput);
+    fn read_region(
+        &self,
+        layout: LayoutSource,
+        regions: &[&str],
+        path: &Path,
+    ) -> Result<(), FlashromError> {
+        self.dispatch(Op::ReadRegion(layout, regions, path).opts()?)?;
+        Ok(())
+    }
 
-        match self.wp_status(true) {
-            Ok(_ret) => {
-                info!("Successfully {}abled write-protect", status);
-                Ok(true)
-            }
-            Err(e) => Err(format!("Cannot {}able write-protect: {}", status, e).into()),
-        }
+    fn erase_region(&self, layout: LayoutSource, regions: &[&str]) -> Result<(), FlashromError> {
+        self.dispatch(Op::EraseRegion(layout, regions).opts()?)?;
+        Ok(())
     }
 
-    fn read(&self, path: &str) -> Result<(), FlashromError> {
-        let opts = FlashromOpt {
-            io_opt: IOOpt {
-                read: Some(path),
-                ..Default::default()
-            },
-            ..Default::default()
-        };
+    fn read(&self, path: &Path) -> Result<(), FlashromError> {
+        self.dispatch(Op::Read(path).opts()?)?;
+        Ok(())
+    }
 
-        let (stdout, _) = self.dispatch(opts)?;
-        let output = String::from_utf8_lossy(stdout.as_slice());
-        debug!("read():\n{}", output);
+    fn write(&self, path: &Path) -> Result<(), FlashromError> {
+        self.dispatch(Op::Write(path).opts()?)?;
         Ok(())
     }
 
-    fn write(&self, path: &str) -> Result<(), FlashromError> {
-        let opts = FlashromOpt {
-            io_opt: IOOpt {
-                write: Some(path),
-                ..Default::default()
-            },
-            ..Default::default()
-        };
+    fn verify(&self, path: &Path) -> Result<(), FlashromError> {
+        self.dispatch(Op::Verify(path).opts()?)?;
+        Ok(())
+    }
 
-        let (stdout, _) = self.dispatch(opts)?;
-        let output = String::from_utf8_lossy(stdout.as_slice());
-        debug!("write():\n{}", output);
+    fn erase(&self) -> Result<(), FlashromError> {
+        self.dispatch(Op::Erase.opts()?)?;
         Ok(())
     }
 
-    fn verify(&self, path: &str) -> Result<(), FlashromError> {
-        let opts = FlashromOpt {
-            io_opt: IOOpt {
-                verify: Some(path),
-                ..Default::default()
-            },
-            ..Default::default()
-        };
+    fn programmer(&self) -> &Programmer {
+        &self.programmer
+    }
 
-        let (stdout, _) = self.dispatch(opts)?;
-        let output = String::from_utf8_lossy(stdout.as_slice());
-        debug!("verify():\n{}", output);
+    fn emulated_hw_wp(&self) -> Option<bool> {
+        if self.programmer.caps().emulated {
+            Some(self.emulated_hw_wp.load(Ordering::SeqCst))
+        } else {
+            None
+        }
+    }
+
+    fn set_emulated_hw_wp(&self, en: bool) -> Result<(), FlashromError> {
+        if !self.programmer.caps().emulated {
+            return Err(FlashromError::Unsupported(format!(
+                "the {} programmer has no emulated hardware write protect",
+                self.programmer.name
+            )));
+        }
+        info!("Setting emulated hwwp={}", en);
+        self.emulated_hw_wp.store(en, Ordering::SeqCst);
         Ok(())
     }
 
-    fn erase(&self) -> Result<(), FlashromError> {
-        let opts = FlashromOpt {
-            io_opt: IOOpt {
-                erase: true,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
+    fn capabilities(&self) -> Capabilities {
+        *self.capabilities.get_or_init(|| self.detect_capabilities())
+    }
 
-        let (stdout, _) = self.dispatch(opts)?;
-        let output = String::from_utf8_lossy(stdout.as_slice());
-        debug!("erase():\n{}", output);
-        Ok(())
+    fn supported_chips(&self) -> Result<ChipDatabase, FlashromError> {
+        let (stdout, _) = run_command(&self.path, &["-L"], self.limits(self.timeouts.other))?;
+        ChipDatabase::parse(&String::from_utf8_lossy(&stdout))
     }
 
-    fn can_control_hw_wp(&self) -> bool {
-        self.fc.can_control_hw_wp()
+    fn set_progress(&mut self, callback: ProgressCallback) {
+        self.progress = callback;
     }
 }
 
-fn flashrom_decode_opts(opts: FlashromOpt) -> Vec<String> {
-    let mut params = Vec::<String>::new();
+/// Check that write protect is `en`abled after toggling it through `flashrom`.
+pub(crate) fn check_wp_toggled(flashrom: &dyn Flashrom, en: bool) -> Result<bool, FlashromError> {
+    let status = if en { "en" } else { "dis" };
+    match flashrom.get_wp_status() {
+        Ok(wp) if wp.enabled() == en => {
+            info!("Successfully {}abled write-protect", status);
+            Ok(true)
+        }
+        Ok(wp) => Err(format!(
+            "Cannot {}able write-protect: status is {:?} after toggling",
+            status, wp
+        )
+        .into()),
+        Err(e) => {
+            error!("Cannot {}able write-protect: {}", status, e);
+            Err(e)
+        }
+    }
+}
 
-    // ------------ WARNING !!! ------------
-    // each param must NOT contain spaces!
-    // -------------------------------------
+fn flashrom_decode_opts(opts: FlashromOpt) -> Vec<OsString> {
+    let mut params = Vec::<OsString>::new();
 
     // wp_opt
-    if opts.wp_opt.range.is_some() {
-        let (x0, x1) = opts.wp_opt.range.unwrap();
-        params.push("--wp-range".to_string());
-        params.push(hex_range_string(x0, x1));
+    if let Some((x0, x1)) = opts.wp_opt.range {
+        params.push("--wp-range".into());
+        params.push(hex_range_string(x0, x1).into());
+    }
+    if let Some(region) = opts.wp_opt.region {
+        params.push("--wp-region".into());
+        params.push(region.into());
     }
     if opts.wp_opt.status {
-        params.push("--wp-status".to_string());
-    } else if opts.wp_opt.list {
-        params.push("--wp-list".to_string());
-    } else if opts.wp_opt.enable {
-        params.push("--wp-enable".to_string());
-    } else if opts.wp_opt.disable {
-        params.push("--wp-disable".to_string());
+        params.push("--wp-status".into());
+    }
+    if opts.wp_opt.list {
+        params.push("--wp-list".into());
+    }
+    if opts.wp_opt.enable {
+        params.push("--wp-enable".into());
+    }
+    if opts.wp_opt.disable {
+        params.push("--wp-disable".into());
     }
 
     // io_opt
-    if opts.io_opt.read.is_some() {
-        params.push("-r".to_string());
-        params.push(opts.io_opt.read.unwrap().to_string());
-    } else if opts.io_opt.write.is_some() {
-        params.push("-w".to_string());
-        params.push(opts.io_opt.write.unwrap().to_string());
-    } else if opts.io_opt.verify.is_some() {
-        params.push("-v".to_string());
-        params.push(opts.io_opt.verify.unwrap().to_string());
-    } else if opts.io_opt.erase {
-        params.push("-E".to_string());
+    if let Some(read) = opts.io_opt.read {
+        params.push("-r".into());
+        params.push(read.into());
+    }
+    if let Some(write) = opts.io_opt.write {
+        params.push("-w".into());
+        params.push(write.into());
+    } else if opts.io_opt.write_regions {
+        params.push("-w".into());
+    }
+    if let Some(verify) = opts.io_opt.verify {
+        params.push("-v".into());
+        params.push(verify.into());
+    }
+    if opts.io_opt.erase {
+        params.push("-E".into());
+    }
+    if opts.io_opt.extract {
+        params.push("--extract".into());
+    }
+    if opts.io_opt.noverify {
+        params.push("--noverify".into());
+    }
+    if opts.io_opt.noverify_all {
+        params.push("--noverify-all".into());
+    }
+    if let Some(flash_contents) = opts.io_opt.flash_contents {
+        params.push("--flash-contents".into());
+        params.push(flash_contents.into());
     }
 
     // misc_opt
-    if opts.layout.is_some() {
-        params.push("-l".to_string());
-        params.push(opts.layout.unwrap().to_string());
+    if let Some(layout) = opts.layout {
+        params.push("-l".into());
+        params.push(layout.into());
+    }
+    if opts.fmap {
+        params.push("--fmap".into());
+    }
+    if let Some(fmap_file) = opts.fmap_file {
+        params.push("--fmap-file".into());
+        params.push(fmap_file.into());
     }
-    if opts.image.is_some() {
-        params.push("-i".to_string());
-        params.push(opts.image.unwrap().to_string());
+    if opts.ifd {
+        params.push("--ifd".into());
+    }
+    for image in opts.image {
+        params.push("-i".into());
+        params.push(image);
     }
 
+    if let Some(chip) = opts.chip {
+        params.push("-c".into());
+        params.push(chip.into());
+    }
+    if opts.force {
+        params.push("--force".into());
+    }
     if opts.flash_name {
-        params.push("--flash-name".to_string());
+        params.push("--flash-name".into());
+    }
+    if opts.flash_size {
+        params.push("--flash-size".into());
+    }
+    if opts.progress {
+        params.push("--progress".into());
+    }
+    if let Some(output) = opts.output {
+        params.push("-o".into());
+        params.push(output.into());
     }
     if opts.verbose {
-        params.push("-V".to_string());
+        params.push("-V".into());
     }
 
     params
 }
 
-fn flashrom_dispatch<S: AsRef<str>>(
-    path: &str,
-    params: &[S],
-    fc: FlashChip,
+/// Run flashrom with `params`, passing its stdout to `on_stdout` as it is read.
+fn flashrom_dispatch<S, F>(
+    path: &Path,
+    params: &[S],
+    programmer: &str,
+    limits: Limits,
+    on_stdout: F,
+) -> Result<(Vec<u8>, Vec<u8>), FlashromError>
+where
+    S: AsRef<OsStr>,
+    F: FnMut(&[u8]) + Send,
+{
+    // from man page:
+    //  ' -p, --programmer <name>[:parameter[,parameter[,parameter]]] '
+    let mut args: Vec<&OsStr> = vec!["-p".as_ref(), programmer.as_ref()];
+    args.extend(params.iter().map(S::as_ref));
+
+    info!("flashrom_dispatch() running: {} {:?}", path.display(), args);
+
+    run_command_streaming(path, &args, limits, on_stdout)
+}
+
+/// When to give up on a command and kill it.
+#[derive(Copy, Clone, Debug, Default)]
+struct Limits<'a> {
+    timeout: Option<Duration>,
+    /// Kill the command if this becomes set while it runs.
+    cancel: Option<&'a AtomicBool>,
+    /// Run the command in its own process group, which is killed along with
+    /// it, so children it started can't keep its output open.
+    group: bool,
+}
+
+/// Why a command was killed.
+enum Stopped {
+    Timeout(Duration),
+    Cancelled,
+}
+
+/// Longest time between checks on a running command.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Run `program` with `args`, returning its stdout and stderr if it succeeds.
+fn run_command<P, S>(
+    program: P,
+    args: &[S],
+    limits: Limits,
+) -> Result<(Vec<u8>, Vec<u8>), FlashromError>
+where
+    P: AsRef<OsStr>,
+    S: AsRef<OsStr>,
+{
+    run_command_streaming(program, args, limits, |_| ())
+}
+
+/// Like `run_command`, also passing stdout to `on_stdout` as it is read.
+fn run_command_streaming<P, S, F>(
+    program: P,
+    args: &[S],
+    limits: Limits,
+    mut on_stdout: F,
+) -> Result<(Vec<u8>, Vec<u8>), FlashromError>
+where
+    P: AsRef<OsStr>,
+    S: AsRef<OsStr>,
+    F: FnMut(&[u8]) + Send,
+{
+    let program = program.as_ref();
+    // Only for messages, so file names which aren't UTF-8 may be mangled.
+    let cmdline = std::iter::once(program)
+        .chain(args.iter().map(S::as_ref))
+        .map(OsStr::to_string_lossy)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // A flag which is already set must be for an earlier command, so only
+    // cancel if it becomes set from here on.
+    let cancel = limits.cancel.filter(|c| !c.load(Ordering::SeqCst));
+    let started = Instant::now();
+
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if limits.group {
+        command.process_group(0);
+    }
+    let mut child = match command.spawn() {
+        Ok(x) => x,
+        Err(source) => return Err(FlashromError::Spawn { cmdline, source }),
+    };
+
+    let name = Path::new(program).file_name().unwrap_or(program);
+    let stdout_source = format!("{} stdout", name.to_string_lossy());
+    let stderr_source = format!("{} stderr", name.to_string_lossy());
+    let mut child_stdout = child.stdout.take().expect("stdout is piped");
+    let mut child_stderr = child.stderr.take().expect("stderr is piped");
+    let (status, output_stdout, output_stderr) = std::thread::scope(|scope| {
+        // Read both pipes while waiting, so neither can fill up and block
+        // the child.
+        let stdout_reader = scope.spawn(move || {
+            let mut buf = Vec::new();
+            let mut lines = OutputLines::default();
+            let mut chunk = [0u8; 4096];
+            loop {
+                match child_stdout.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        on_stdout(&chunk[..n]);
+                        lines.feed(&chunk[..n], |l| log_output(&stdout_source, l));
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            lines.finish(|l| log_output(&stdout_source, l));
+            Ok(buf)
+        });
+        let stderr_reader = scope.spawn(move || {
+            let mut buf = Vec::new();
+            let mut lines = OutputLines::default();
+            let mut chunk = [0u8; 4096];
+            loop {
+                match child_stderr.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        lines.feed(&chunk[..n], |l| log_output(&stderr_source, l));
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            lines.finish(|l| log_output(&stderr_source, l));
+            Ok(buf)
+        });
+
+        let mut interval = Duration::from_millis(1);
+        let mut wait = || loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Ok(status));
+            }
+            if cancel.is_some_and(|c| c.load(Ordering::SeqCst)) {
+                return Ok(Err(Stopped::Cancelled));
+            }
+            if let Some(timeout) = limits.timeout.filter(|&t| started.elapsed() >= t) {
+                return Ok(Err(Stopped::Timeout(timeout)));
+            }
+            std::thread::sleep(interval);
+            interval = (interval * 2).min(MAX_POLL_INTERVAL);
+        };
+        let status: io::Result<_> = wait();
+        if !matches!(status, Ok(Ok(_))) {
+            // The readers only finish once the child exits and closes its
+            // pipes, so it must not outlive a failure to wait for it.
+            if limits.group {
+                // SAFETY: kill() has no memory safety requirements, and the
+                // group is the child's until it is reaped below.
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let status = status?;
+
+        let stdout = stdout_reader.join().expect("stdout reader panicked")?;
+        let stderr = stderr_reader.join().expect("stderr reader panicked")?;
+        Ok::<_, io::Error>((status, stdout, stderr))
+    })?;
+
+    let status = match status {
+        Ok(status) => status,
+        Err(stopped) => {
+            let stdout = String::from_utf8_lossy(&output_stdout).into_owned();
+            let stderr = String::from_utf8_lossy(&output_stderr).into_owned();
+            warn!("Killed `{}` after {:?}", cmdline, started.elapsed());
+            return Err(match stopped {
+                Stopped::Timeout(timeout) => FlashromError::Timeout {
+                    cmdline,
+                    timeout,
+                    stdout,
+                    stderr,
+                },
+                Stopped::Cancelled => FlashromError::Cancelled {
+                    cmdline,
+                    stdout,
+                    stderr,
+                },
+            });
+        }
+    };
+
+    if !status.success() {
+        let stdout = String::from_utf8_lossy(&output_stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output_stderr).into_owned();
+        // There is two cases on failure;
+        //  i. ) A bad exit code,
+        //  ii.) A SIG killed us.
+        return Err(match status.code() {
+            Some(code) => FlashromError::Exit {
+                cmdline,
+                code,
+                stdout,
+                stderr,
+            },
+            None => FlashromError::Signal {
+                cmdline,
+                signal: status.signal().unwrap_or_default(),
+                stdout,
+                stderr,
+            },
+        });
+    }
+
+    Ok((output_stdout, output_stderr))
+}
+
+/// Splits output from a command into lines, as it is read.
+#[derive(Default)]
+struct OutputLines {
+    pending: Vec<u8>,
+}
+
+impl OutputLines {
+    /// Longest line to hold back waiting for its end. Progress reports aren't
+    /// followed by newlines, so lines can grow for as long as flashrom runs.
+    const MAX_LINE: usize = 1024;
+
+    /// Add `data`, passing each line it completes to `line`.
+    fn feed<F: FnMut(&str)>(&mut self, data: &[u8], mut line: F) {
+        self.pending.extend_from_slice(data);
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let complete: Vec<u8> = self.pending.drain(..=end).collect();
+            line(&String::from_utf8_lossy(&complete[..end]));
+        }
+        if self.pending.len() > Self::MAX_LINE {
+            self.finish(line);
+        }
+    }
+
+    /// Pass any partial line to `line`.
+    fn finish<F: FnMut(&str)>(&mut self, mut line: F) {
+        if !self.pending.is_empty() {
+            line(&String::from_utf8_lossy(&std::mem::take(&mut self.pending)));
+        }
+    }
+}
+
+/// Log a line of output from `source`, at a level matching how flashrom marks
+/// errors and warnings.
+fn log_output(source: &str, line: &str) {
+    let line = line.trim_end();
+    if line.is_empty() {
+        return;
+    }
+    let lower = line.trim_start().to_ascii_lowercase();
+    let level = if lower.starts_with("error") || line.contains("FAILED") {
+        log::Level::Error
+    } else if lower.starts_with("warning") {
+        log::Level::Warn
+    } else {
+        log::Level::Debug
+    };
+    log!(level, "{}: {}", source, line);
+}
+
+/// Format `programmer` for flashrom, applying the emulated hardware write
+/// protect `hw_wp` if there is one.
+fn programmer_string(programmer: &Programmer, hw_wp: Option<bool>) -> String {
+    match hw_wp {
+        Some(en) => programmer
+            .clone()
+            .with_param("hwwp", if en { "yes" } else { "no" })
+            .to_string(),
+        None => programmer.to_string(),
+    }
+}
+
+/// Assert or deassert the hardware write protect through servo with
+/// `dut-control`, which is killed if `cancel` becomes set while it runs.
+pub fn dut_ctrl_toggle_wp(
+    en: bool,
+    cancel: Option<&AtomicBool>,
+) -> Result<(Vec<u8>, Vec<u8>), FlashromError> {
+    let args = if en {
+        ["fw_wp_en:off", "fw_wp:on"]
+    } else {
+        ["fw_wp_en:on", "fw_wp:off"]
+    };
+    dut_ctrl(&args, cancel)
+}
+
+/// Run the shell command `command`, which asserts or deasserts the hardware
+/// write protect, killing it if it hangs or `cancel` becomes set while it runs.
+pub fn shell_toggle_wp(
+    command: &str,
+    cancel: Option<&AtomicBool>,
 ) -> Result<(Vec<u8>, Vec<u8>), FlashromError> {
-    // from man page:
-    //  ' -p, --programmer <name>[:parameter[,parameter[,parameter]]] '
-    let mut args: Vec<&str> = vec!["-p", FlashChip::to(fc)];
-    args.extend(params.iter().map(S::as_ref));
+    if dry_run() {
+        crate::dryrun::dry_run_step(format!("sh -c {:?}", command));
+        return Ok(Default::default());
+    }
+    let limits = Limits {
+        timeout: Some(WP_CONTROL_TIMEOUT),
+        cancel,
+        group: true,
+    };
+    run_command("sh", &["-c", command], limits)
+}
 
-    info!("flashrom_dispatch() running: {} {:?}", path, args);
+/// How long a command controlling the hardware write protect, such as
+/// `dut-control`, may take before it is assumed to be hung.
+const WP_CONTROL_TIMEOUT: Duration = Duration::from_secs(60);
 
-    let output = match Command::new(path).args(&args).output() {
-        Ok(x) => x,
-        Err(e) => return Err(format!("Failed to run flashrom: {}", e).into()),
+fn dut_ctrl(
+    args: &[&str],
+    cancel: Option<&AtomicBool>,
+) -> Result<(Vec<u8>, Vec<u8>), FlashromError> {
+    if dry_run() {
+        crate::dryrun::dry_run_step(format!("dut-control {}", args.join(" ")));
+        return Ok(Default::default());
+    }
+    let limits = Limits {
+        timeout: Some(WP_CONTROL_TIMEOUT),
+        cancel,
+        group: false,
     };
-    if !output.status.success() {
-        // There is two cases on failure;
-        //  i. ) A bad exit code,
-        //  ii.) A SIG killed us.
-        match output.status.code() {
-            Some(code) => {
-                return Err(format!(
-                    "{}\nExited with error code: {}",
-                    String::from_utf8_lossy(&output.stderr),
-                    code
-                )
-                .into());
-            }
-            None => return Err("Process terminated by a signal".into()),
+    run_command("dut-control", args, limits)
+}
+
+/// Parse the chips flashrom found while probing, from lines like
+/// `Found Winbond flash chip "W25Q128.V" (16384 kB, SPI) on dummy.`
+///
+/// With `-V`, the preceding `Probing for` message also gives the ID of the chip.
+pub(crate) fn parse_probe(stdout: &str) -> Vec<ChipInfo> {
+    const PROBING: &str = "Probing for ";
+
+    let mut chips = Vec::new();
+    // Messages for chips which fail to probe don't end their lines, so several
+    // can be on one line and only the last is of interest.
+    let mut probing = "";
+    for line in stdout.lines() {
+        if let Some(i) = line.rfind(PROBING) {
+            probing = &line[i + PROBING.len()..];
+            continue;
+        }
+        let parse = || -> Option<ChipInfo> {
+            let line = line.strip_prefix("Found ")?;
+            let (vendor, line) = line.split_at(line.find(" flash chip \"")?);
+            let line = line.strip_prefix(" flash chip \"")?;
+            let (name, line) = line.split_at(line.find("\" (")?);
+            let line = line.strip_prefix("\" (")?;
+            let kb: i64 = line.get(..line.find(" kB")?)?.parse().ok()?;
+
+            let id = probing
+                .strip_prefix(&format!("{} {}, ", vendor, name))
+                .and_then(|probe| {
+                    let id = |key: &str| {
+                        let value = &probe[probe.find(key)? + key.len()..];
+                        let end = value.find(|c: char| !c.is_ascii_alphanumeric());
+                        parse_hex(&value[..end.unwrap_or(value.len())])
+                    };
+                    Some((id("id1 ")? as u32, id("id2 ")? as u32))
+                });
+            Some(ChipInfo {
+                vendor: vendor.into(),
+                name: name.into(),
+                size: kb * 1024,
+                id,
+            })
+        };
+        // With -V, flashrom repeats the message for a single match.
+        match parse() {
+            Some(chip) if !chips.iter().any(|c: &ChipInfo| c.name == chip.name) => chips.push(chip),
+            _ => {}
         }
     }
+    chips
+}
 
-    Ok((output.stdout, output.stderr))
+/// Parse a hexadecimal number printed by flashrom as `0x...`.
+fn parse_hex(s: &str) -> Option<i64> {
+    i64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
 }
 
-pub fn dut_ctrl_toggle_wp(en: bool) -> Result<(Vec<u8>, Vec<u8>), FlashromError> {
-    let args = if en {
-        ["fw_wp_en:off", "fw_wp:on"]
-    } else {
-        ["fw_wp_en:on", "fw_wp:off"]
-    };
-    dut_ctrl(&args)
+/// Parse a range printed by flashrom as `start=0x.. len=0x..`, with `len`
+/// spelled `length` by newer versions, optionally comma-separated and with
+/// `: ` in place of `=` in the legacy range list.
+fn parse_wp_range(s: &str) -> Option<(i64, i64)> {
+    let (mut start, mut len) = (None, None);
+    let s = s.replace(": ", "=");
+    for tok in s.split([' ', ',']) {
+        if let Some(v) = tok.strip_prefix("start=") {
+            start = parse_hex(v);
+        } else if let Some(v) = tok
+            .strip_prefix("len=")
+            .or_else(|| tok.strip_prefix("length="))
+        {
+            len = parse_hex(v);
+        }
+    }
+    Some((start?, len?))
 }
 
-pub fn dut_ctrl_servo_type() -> Result<(Vec<u8>, Vec<u8>), FlashromError> {
-    let args = ["servo_type"];
-    dut_ctrl(&args)
+/// Parse the output of `flashrom --wp-list`.
+///
+/// Ranges follow one of several headers depending on the flashrom version
+/// and the chip:
+///
+/// ```text
+/// Available protection ranges:
+///         start=0x00000000 length=0x00000000 (none)
+/// Valid write protection ranges:
+/// start: 0x000000, length: 0x001000
+/// Supported write protect range:
+///   disable: start=0x000000 len=0x000000
+///   enable:  start=0x000000 len=0x020000
+/// ```
+fn parse_wp_list(stdout: &str) -> Result<Vec<WpRange>, FlashromError> {
+    const HEADERS: &[&str] = &[
+        "Available protection ranges:",
+        "Valid write protection ranges:",
+        "Supported write protect range:",
+    ];
+
+    let mut lines = stdout
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !HEADERS.contains(line));
+    if lines.next().is_none() {
+        return Err(FlashromError::Parse(
+            "Didn't find a list of protection ranges in flashrom output".into(),
+        ));
+    }
+
+    let mut ranges = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (mode, range) = if let Some(s) = line.strip_prefix("disable:") {
+            (Some(WpMode::Disabled), s)
+        } else if let Some(s) = line.strip_prefix("enable:") {
+            (Some(WpMode::Hardware), s)
+        } else {
+            (None, line)
+        };
+        match parse_wp_range(range.trim()) {
+            Some((start, len)) => ranges.push(WpRange { start, len, mode }),
+            // Anything after the list, such as a status message.
+            None => break,
+        }
+    }
+    Ok(ranges)
 }
 
-fn dut_ctrl(args: &[&str]) -> Result<(Vec<u8>, Vec<u8>), FlashromError> {
-    let output = match Command::new("dut-control").args(args).output() {
-        Ok(x) => x,
-        Err(e) => return Err(format!("Failed to run dut-control: {}", e).into()),
-    };
-    if !output.status.success() {
-        // There is two cases on failure;
-        //  i. ) A bad exit code,
-        //  ii.) A SIG killed us.
-        match output.status.code() {
-            Some(code) => {
-                return Err(format!("Exited with error code: {}", code).into());
-            }
-            None => return Err("Process terminated by a signal".into()),
+/// Parse the output of `flashrom --wp-status`.
+///
+/// The legacy format only reports whether protection is enabled, which is
+/// taken to mean hardware mode:
+///
+/// ```text
+/// WP: write protect is enabled.
+/// WP: write protect range: start=0x00000000, len=0x01000000
+/// ```
+///
+/// Newer versions report the mode:
+///
+/// ```text
+/// Protection range: start=0x00000000 length=0x01000000 (all)
+/// Protection mode: hardware
+/// ```
+fn parse_wp_status(stdout: &str) -> Result<WpStatus, FlashromError> {
+    let (mut mode, mut range) = (None, None);
+    for line in stdout.lines().map(str::trim) {
+        if let Some(s) = line.strip_prefix("WP: write protect is ") {
+            mode = match s.trim_end_matches('.') {
+                "enabled" => Some(WpMode::Hardware),
+                "disabled" => Some(WpMode::Disabled),
+                _ => None,
+            };
+        } else if let Some(s) = line.strip_prefix("Protection mode: ") {
+            mode = match s {
+                "disabled" => Some(WpMode::Disabled),
+                "hardware" => Some(WpMode::Hardware),
+                "power_cycle" => Some(WpMode::PowerCycle),
+                "permanent" => Some(WpMode::Permanent),
+                _ => None,
+            };
+        } else if let Some(s) = line
+            .strip_prefix("WP: write protect range: ")
+            .or_else(|| line.strip_prefix("Protection range: "))
+        {
+            range = parse_wp_range(s);
         }
     }
 
-    Ok((output.stdout, output.stderr))
+    match (mode, range) {
+        (Some(mode), Some(range)) => Ok(WpStatus { mode, range }),
+        (None, _) => Err(FlashromError::Parse(
+            "Didn't find write protect mode in flashrom output".into(),
+        )),
+        (_, None) => Err(FlashromError::Parse(
+            "Didn't find write protect range in flashrom output".into(),
+        )),
+    }
 }
 
 fn hex_range_string(s: i64, l: i64) -> String {
-    format!("{:#08X},{:#08X}", s, l).to_string()
+    format!("{:#08X},{:#08X}", s, l)
 }
 
 /// Get a flash vendor and name from the first matching line of flashrom output.
@@ -468,7 +1382,12 @@ fn extract_flash_name(stdout: &str) -> Option<(&str, &str)> {
 #[cfg(test)]
 mod tests {
     use super::flashrom_decode_opts;
-    use super::{FlashromOpt, IOOpt, WPOpt};
+    use super::{FlashromOpt, FlashromOptBuilder, IOOpt, WPOpt};
+    use crate::{FlashromError, LayoutSource, RegionFile};
+    use proptest::prelude::*;
+    use std::ffi::OsString;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::Path;
 
     #[test]
     fn decode_wp_opt() {
@@ -512,6 +1431,14 @@ mod tests {
             },
             &["--wp-disable"],
         );
+        test_wp_opt(
+            WPOpt {
+                region: Some("WP_RO"),
+                enable: true,
+                ..Default::default()
+            },
+            &["--wp-region", "WP_RO", "--wp-enable"],
+        );
     }
 
     #[test]
@@ -528,21 +1455,36 @@ mod tests {
 
         test_io_opt(
             IOOpt {
-                read: Some("foo.bin"),
+                read: Some(Path::new("foo.bin")),
                 ..Default::default()
             },
             &["-r", "foo.bin"],
         );
         test_io_opt(
             IOOpt {
-                write: Some("bar.bin"),
+                write: Some(Path::new("bar.bin")),
+                ..Default::default()
+            },
+            &["-w", "bar.bin"],
+        );
+        test_io_opt(
+            IOOpt {
+                write_regions: true,
+                ..Default::default()
+            },
+            &["-w"],
+        );
+        test_io_opt(
+            IOOpt {
+                write: Some(Path::new("bar.bin")),
+                write_regions: true,
                 ..Default::default()
             },
             &["-w", "bar.bin"],
         );
         test_io_opt(
             IOOpt {
-                verify: Some("/tmp/baz.bin"),
+                verify: Some(Path::new("/tmp/baz.bin")),
                 ..Default::default()
             },
             &["-v", "/tmp/baz.bin"],
@@ -561,7 +1503,7 @@ mod tests {
         //use Default::default;
         assert_eq!(
             flashrom_decode_opts(FlashromOpt {
-                layout: Some("TestLayout"),
+                layout: Some(Path::new("TestLayout")),
                 ..Default::default()
             }),
             &["-l", "TestLayout"]
@@ -569,12 +1511,50 @@ mod tests {
 
         assert_eq!(
             flashrom_decode_opts(FlashromOpt {
-                image: Some("TestImage"),
+                fmap: true,
+                image: vec!["RW_SECTION_A".into()],
+                ..Default::default()
+            }),
+            &["--fmap", "-i", "RW_SECTION_A"]
+        );
+
+        assert_eq!(
+            flashrom_decode_opts(FlashromOpt {
+                fmap_file: Some(Path::new("TestImage.bin")),
+                ..Default::default()
+            }),
+            &["--fmap-file", "TestImage.bin"]
+        );
+
+        assert_eq!(
+            flashrom_decode_opts(FlashromOpt {
+                ifd: true,
+                image: vec!["bios".into()],
+                ..Default::default()
+            }),
+            &["--ifd", "-i", "bios"]
+        );
+
+        assert_eq!(
+            flashrom_decode_opts(FlashromOpt {
+                image: vec!["TestImage".into()],
                 ..Default::default()
             }),
             &["-i", "TestImage"]
         );
 
+        assert_eq!(
+            flashrom_decode_opts(
+                FlashromOpt::builder()
+                    .erase()
+                    .layout(LayoutSource::Fmap)
+                    .image(["RW_SECTION_A", "RW_SECTION_B"])
+                    .build()
+                    .unwrap()
+            ),
+            &["-E", "--fmap", "-i", "RW_SECTION_A", "-i", "RW_SECTION_B"]
+        );
+
         assert_eq!(
             flashrom_decode_opts(FlashromOpt {
                 flash_name: true,
@@ -585,6 +1565,192 @@ mod tests {
         );
     }
 
+    #[test]
+    fn decode_builder_opts() {
+        fn test_builder(builder: FlashromOptBuilder, expected: &[&str]) {
+            assert_eq!(flashrom_decode_opts(builder.build().unwrap()), expected);
+        }
+
+        test_builder(FlashromOpt::builder(), &[]);
+        test_builder(
+            FlashromOpt::builder().wp_range((0, 1234)).wp_enable(),
+            &["--wp-range", "0x000000,0x0004D2", "--wp-enable"],
+        );
+        test_builder(FlashromOpt::builder().wp_status(), &["--wp-status"]);
+        test_builder(FlashromOpt::builder().wp_list(), &["--wp-list"]);
+        test_builder(FlashromOpt::builder().wp_disable(), &["--wp-disable"]);
+        test_builder(FlashromOpt::builder().read("foo.bin"), &["-r", "foo.bin"]);
+        test_builder(FlashromOpt::builder().write("bar.bin"), &["-w", "bar.bin"]);
+        test_builder(
+            FlashromOpt::builder()
+                .write_regions()
+                .image(["RO_VPD:vpd.bin"]),
+            &["-w", "-i", "RO_VPD:vpd.bin"],
+        );
+        test_builder(FlashromOpt::builder().verify("baz.bin"), &["-v", "baz.bin"]);
+        test_builder(FlashromOpt::builder().erase(), &["-E"]);
+        test_builder(
+            FlashromOpt::builder().extract().layout(LayoutSource::Fmap),
+            &["--extract", "--fmap"],
+        );
+        test_builder(
+            FlashromOpt::builder().write("bar.bin").noverify(),
+            &["-w", "bar.bin", "--noverify"],
+        );
+        test_builder(
+            FlashromOpt::builder().erase().noverify_all(),
+            &["-E", "--noverify-all"],
+        );
+        test_builder(
+            FlashromOpt::builder()
+                .write("bar.bin")
+                .flash_contents("old.bin"),
+            &["-w", "bar.bin", "--flash-contents", "old.bin"],
+        );
+        test_builder(
+            FlashromOpt::builder().layout(LayoutSource::File(Path::new("TestLayout"))),
+            &["-l", "TestLayout"],
+        );
+        test_builder(
+            FlashromOpt::builder().layout(LayoutSource::FmapFile(Path::new("TestImage.bin"))),
+            &["--fmap-file", "TestImage.bin"],
+        );
+        test_builder(FlashromOpt::builder().layout(LayoutSource::Ifd), &["--ifd"]);
+        test_builder(
+            FlashromOpt::builder().read("foo.bin").chip("W25Q128.V"),
+            &["-r", "foo.bin", "-c", "W25Q128.V"],
+        );
+        test_builder(
+            FlashromOpt::builder().write("bar.bin").force(),
+            &["-w", "bar.bin", "--force"],
+        );
+        test_builder(FlashromOpt::builder().flash_name(), &["--flash-name"]);
+        test_builder(FlashromOpt::builder().flash_size(), &["--flash-size"]);
+        test_builder(
+            FlashromOpt::builder().read("foo.bin").progress(),
+            &["-r", "foo.bin", "--progress"],
+        );
+        test_builder(
+            FlashromOpt::builder().read("foo.bin").output("log.txt"),
+            &["-r", "foo.bin", "-o", "log.txt"],
+        );
+        test_builder(FlashromOpt::builder().verbose(), &["-V"]);
+    }
+
+    #[test]
+    fn builder_rejects_conflicts() {
+        fn test_conflict(builder: FlashromOptBuilder, expected: &str) {
+            match builder.build() {
+                Ok(opts) => panic!(
+                    "expected an error, got options {:?}",
+                    flashrom_decode_opts(opts)
+                ),
+                Err(e) => assert_eq!(e.to_string(), expected),
+            }
+        }
+
+        test_conflict(
+            FlashromOpt::builder().read("foo.bin").write("bar.bin"),
+            "Conflicting flashrom options: -r, -w",
+        );
+        test_conflict(
+            FlashromOpt::builder().erase().flash_name(),
+            "Conflicting flashrom options: -E, --flash-name",
+        );
+        test_conflict(
+            FlashromOpt::builder().flash_name().flash_size(),
+            "Conflicting flashrom options: --flash-name, --flash-size",
+        );
+        test_conflict(
+            FlashromOpt::builder().wp_list().wp_enable(),
+            "Conflicting flashrom options: --wp-list, --wp-enable",
+        );
+        test_conflict(
+            FlashromOpt::builder().wp_enable().wp_disable(),
+            "Conflicting flashrom options: --wp-enable, --wp-disable",
+        );
+        test_conflict(
+            FlashromOpt::builder().wp_range((0, 0)).wp_list(),
+            "Conflicting flashrom options: --wp-range, --wp-list",
+        );
+        test_conflict(
+            FlashromOpt::builder()
+                .wp_range((0, 0))
+                .wp_region("WP_RO")
+                .layout(LayoutSource::Fmap),
+            "Conflicting flashrom options: --wp-range, --wp-region",
+        );
+        test_conflict(
+            FlashromOpt::builder().wp_region("WP_RO"),
+            "--wp-region needs a layout to find the region in",
+        );
+        test_conflict(
+            FlashromOpt::builder()
+                .layout(LayoutSource::File(Path::new("TestLayout")))
+                .layout(LayoutSource::Fmap)
+                .layout(LayoutSource::FmapFile(Path::new("TestImage.bin")))
+                .layout(LayoutSource::Ifd),
+            "Conflicting flashrom options: -l, --fmap, --fmap-file, --ifd",
+        );
+        test_conflict(
+            FlashromOpt::builder().verify("baz.bin").noverify(),
+            "Conflicting flashrom options: -v, --noverify",
+        );
+        test_conflict(
+            FlashromOpt::builder().read("foo.bin").noverify_all(),
+            "--noverify and --noverify-all only apply to writes and erases",
+        );
+        test_conflict(
+            FlashromOpt::builder().flash_contents("old.bin"),
+            "--flash-contents only applies to writes",
+        );
+    }
+
+    #[test]
+    fn dummy_programmer() {
+        use super::{programmer_string, FlashromCmd};
+        use crate::{Capabilities, ErrorKind, Flashrom, Programmer};
+
+        let dummy = Programmer::from_alias("dummy").unwrap();
+        assert_eq!(programmer_string(&Programmer::new("host"), None), "host");
+        assert_eq!(
+            programmer_string(&dummy, Some(true)),
+            "dummy:emulate=W25Q128FV,hwwp=yes"
+        );
+        assert!(programmer_string(&dummy, Some(false)).ends_with(",hwwp=no"));
+
+        // Each FlashromCmd emulates its own signal.
+        let cmd = |programmer| FlashromCmd {
+            path: "echo".into(),
+            programmer,
+            chip: None,
+            progress: Default::default(),
+            timeouts: Default::default(),
+            terminate: None,
+            capabilities: Capabilities::ALL.into(),
+            emulated_hw_wp: Default::default(),
+        };
+        let (a, b) = (cmd(dummy.clone()), cmd(dummy));
+        a.set_emulated_hw_wp(true).unwrap();
+        assert_eq!(
+            (a.emulated_hw_wp(), b.emulated_hw_wp()),
+            (Some(true), Some(false))
+        );
+        let size = || FlashromOpt::builder().flash_size().build().unwrap();
+        assert_eq!(
+            a.dispatch(size()).unwrap().0,
+            b"-p dummy:emulate=W25Q128FV,hwwp=yes --flash-size\n"
+        );
+
+        let host = cmd(Programmer::new("host"));
+        assert_eq!(host.emulated_hw_wp(), None);
+        assert_eq!(
+            host.set_emulated_hw_wp(true).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(host.dispatch(size()).unwrap().0, b"-p host --flash-size\n");
+    }
+
     #[test]
     fn flashrom_extract_size() {
         use super::flashrom_extract_size;
@@ -594,13 +1760,403 @@ mod tests {
                 "coreboot table found at 0x7cc13000.\n\
                  Found chipset \"Intel Braswell\". Enabling flash write... OK.\n\
                  8388608\n"
+            )
+            .ok(),
+            Some(8388608)
+        );
+
+        match flashrom_extract_size("There was a catastrophic error.") {
+            Err(FlashromError::Parse(msg)) => {
+                assert_eq!(msg, "Found no purely-numeric lines in flashrom output")
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_wp_status() {
+        use super::parse_wp_status;
+        use crate::{WpMode, WpStatus};
+
+        assert_eq!(
+            parse_wp_status(
+                "Found Winbond flash chip \"W25Q128.V\" (16384 kB, SPI) on dummy.\n\
+                 WP: write protect is enabled.\n\
+                 WP: write protect range: start=0x00000000, len=0x00400000\n"
+            )
+            .ok(),
+            Some(WpStatus {
+                mode: WpMode::Hardware,
+                range: (0, 0x400000)
+            })
+        );
+
+        assert_eq!(
+            parse_wp_status(
+                "WP: status: 0x00\n\
+                 WP: status.srp0: 0\n\
+                 WP: write protect is disabled.\n\
+                 WP: write protect range: start=0x00000000, len=0x00000000\n"
+            )
+            .ok(),
+            Some(WpStatus {
+                mode: WpMode::Disabled,
+                range: (0, 0)
+            })
+        );
+
+        assert_eq!(
+            parse_wp_status(
+                "Protection range: start=0x00c00000 length=0x00400000 (upper 1/4)\n\
+                 Protection mode: power_cycle\n"
+            )
+            .ok(),
+            Some(WpStatus {
+                mode: WpMode::PowerCycle,
+                range: (0xc00000, 0x400000)
+            })
+        );
+
+        assert!(parse_wp_status(
+            "WP: write protect is enabled.\n\
+             WP: write protect range: (cannot resolve the range)\n"
+        )
+        .is_err());
+        assert!(parse_wp_status("Protection mode: unknown\n").is_err());
+        assert!(parse_wp_status("").is_err());
+    }
+
+    #[test]
+    fn parse_wp_list() {
+        use super::parse_wp_list;
+        use crate::{WpMode, WpRange};
+
+        fn range(start: i64, len: i64) -> WpRange {
+            WpRange {
+                start,
+                len,
+                mode: None,
+            }
+        }
+
+        assert_eq!(
+            parse_wp_list(
+                "Found Winbond flash chip \"W25Q128.V\" (16384 kB, SPI) on dummy.\n\
+                 Available protection ranges:\n\
+                 \tstart=0x00000000 length=0x00000000 (none)\n\
+                 \tstart=0x00fc0000 length=0x00040000 (upper 1/64)\n\
+                 \tstart=0x00000000 length=0x01000000 (all)\n"
+            )
+            .ok(),
+            Some(vec![
+                range(0, 0),
+                range(0xfc0000, 0x40000),
+                range(0, 0x1000000)
+            ])
+        );
+
+        assert_eq!(
+            parse_wp_list(
+                "Valid write protection ranges:\n\
+                 start: 0x000000, length: 0x000000\n\
+                 start: 0x7e0000, length: 0x020000\n\
+                 SUCCESS\n"
+            )
+            .ok(),
+            Some(vec![range(0, 0), range(0x7e0000, 0x20000)])
+        );
+
+        assert_eq!(
+            parse_wp_list(
+                "Supported write protect range:\n  \
+                 disable: start=0x000000 len=0x000000\n  \
+                 enable:  start=0x000000 len=0x020000\n"
+            )
+            .ok(),
+            Some(vec![
+                WpRange {
+                    start: 0,
+                    len: 0,
+                    mode: Some(WpMode::Disabled)
+                },
+                WpRange {
+                    start: 0,
+                    len: 0x20000,
+                    mode: Some(WpMode::Hardware)
+                },
+            ])
+        );
+
+        assert_eq!(
+            parse_wp_list("Available protection ranges:\n").ok(),
+            Some(vec![])
+        );
+        assert!(parse_wp_list("").is_err());
+    }
+
+    #[test]
+    fn run_command() {
+        use super::run_command;
+
+        match run_command(
+            "sh",
+            &["-c", "echo out; echo err >&2; exit 3"],
+            Default::default(),
+        ) {
+            Err(FlashromError::Exit {
+                cmdline,
+                code,
+                stdout,
+                stderr,
+            }) => {
+                assert_eq!(cmdline, "sh -c echo out; echo err >&2; exit 3");
+                assert_eq!(code, 3);
+                assert_eq!(stdout, "out\n");
+                assert_eq!(stderr, "err\n");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match run_command("sh", &["-c", "kill -9 $$"], Default::default()) {
+            Err(FlashromError::Signal { signal, .. }) => assert_eq!(signal, 9),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match run_command::<_, &str>("/nonexistent/flashrom", &[], Default::default()) {
+            Err(FlashromError::Spawn { source, .. }) => {
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound)
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        assert_eq!(
+            run_command("sh", &["-c", "echo ok"], Default::default()).ok(),
+            Some((b"ok\n".to_vec(), vec![]))
+        );
+    }
+
+    #[test]
+    fn output_lines() {
+        use super::OutputLines;
+
+        let mut lines = OutputLines::default();
+        let mut seen = Vec::new();
+        for chunk in ["Reading ", "flash... done.\nVer", "ifying...\n\nErasing"] {
+            lines.feed(chunk.as_bytes(), |l| seen.push(l.to_string()));
+        }
+        assert_eq!(seen, ["Reading flash... done.", "Verifying...", ""]);
+        lines.finish(|l| seen.push(l.to_string()));
+        assert_eq!(seen.last().unwrap(), "Erasing");
+
+        // Lines without an end are passed on before they grow too long.
+        seen.clear();
+        lines.feed(&[b'#'; 1500], |l| seen.push(l.to_string()));
+        assert_eq!(seen, ["#".repeat(1500)]);
+        lines.finish(|_| panic!("Nothing should be left"));
+    }
+
+    #[test]
+    fn run_command_limits() {
+        use super::{run_command, Limits};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::{Duration, Instant};
+
+        let sleep = ["-c", "echo started; exec sleep 10"];
+
+        let started = Instant::now();
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        match run_command("sh", &sleep, limits) {
+            Err(FlashromError::Timeout {
+                timeout, stdout, ..
+            }) => {
+                assert_eq!(timeout, Duration::from_millis(100));
+                assert_eq!(stdout, "started\n");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let cancel = AtomicBool::new(false);
+        let limits = Limits {
+            cancel: Some(&cancel),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::SeqCst);
+            });
+            match run_command("sh", &sleep, limits) {
+                Err(FlashromError::Cancelled { .. }) => {}
+                r => panic!("Unexpected result {:?}", r),
+            }
+        });
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Commands started after cancelling, such as cleanup, still run.
+        assert_eq!(
+            run_command("sh", &["-c", "echo ok"], limits).ok(),
+            Some((b"ok\n".to_vec(), vec![]))
+        );
+
+        // Children of a command run in its own group are killed with it.
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(100)),
+            group: true,
+            ..Default::default()
+        };
+        let started = Instant::now();
+        assert!(run_command("sh", &["-c", "sleep 10; true"], limits).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn dispatch_pinned_chip() {
+        use super::FlashromCmd;
+        use crate::Programmer;
+
+        // echo prints the arguments flashrom would be run with.
+        let cmd = FlashromCmd {
+            path: "echo".into(),
+            programmer: Programmer::new("host"),
+            chip: Some("W25Q128.V".into()),
+            progress: Default::default(),
+            timeouts: Default::default(),
+            terminate: None,
+            capabilities: Default::default(),
+            emulated_hw_wp: Default::default(),
+        };
+        let args = |opts| String::from_utf8(cmd.dispatch(opts).unwrap().0).unwrap();
+
+        assert_eq!(
+            args(FlashromOpt::builder().flash_name().build().unwrap()),
+            "-p host --flash-name -c W25Q128.V\n"
+        );
+        assert_eq!(
+            args(
+                FlashromOpt::builder()
+                    .flash_name()
+                    .chip("W25Q128.V..M")
+                    .build()
+                    .unwrap()
+            ),
+            "-p host -c W25Q128.V..M --flash-name\n"
+        );
+    }
+
+    #[test]
+    fn dispatch_capabilities() {
+        use super::FlashromCmd;
+        use crate::{Capabilities, ErrorKind, FlashromVersion, LayoutSource, Programmer};
+
+        let cmd = |version| FlashromCmd {
+            path: "echo".into(),
+            programmer: Programmer::new("host"),
+            chip: None,
+            progress: Default::default(),
+            timeouts: Default::default(),
+            terminate: None,
+            capabilities: Capabilities::of_version(FlashromVersion::parse(version)).into(),
+            emulated_hw_wp: Default::default(),
+        };
+        let size = FlashromOpt::builder().flash_size().build().unwrap();
+        let fmap = || {
+            FlashromOpt::builder()
+                .read("/tmp/f")
+                .layout(LayoutSource::Fmap)
+        };
+
+        let chromeos = cmd("flashrom v0.9.9  : 8c7a2e4 : Jun 10 2021 on Linux");
+        assert_eq!(chromeos.dispatch(size).unwrap().0, b"-p host --get-size\n");
+        chromeos.dispatch(fmap().build().unwrap()).unwrap();
+
+        let v1_0 = cmd("flashrom v1.0 on Linux");
+        let err = v1_0.dispatch(fmap().build().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(err.to_string(), "flashrom v1.0.0 does not support --fmap");
+        let wp = FlashromOpt::builder().wp_status().build().unwrap();
+        assert_eq!(
+            v1_0.dispatch(wp).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+
+        let wp_region = || {
+            FlashromOpt::builder()
+                .wp_region("WP_RO")
+                .layout(LayoutSource::Fmap)
+                .build()
+                .unwrap()
+        };
+        let v1_3 = cmd("flashrom v1.3.0 on Linux");
+        assert_eq!(
+            v1_3.dispatch(wp_region()).unwrap_err().to_string(),
+            "flashrom v1.3.0 does not support --wp-region"
+        );
+        let v1_4 = cmd("flashrom 1.4.0 on Linux");
+        assert_eq!(
+            v1_4.dispatch(wp_region()).unwrap().0,
+            b"-p host --wp-region WP_RO --fmap\n"
+        );
+    }
+
+    #[test]
+    fn parse_probe() {
+        use super::parse_probe;
+        use crate::ChipInfo;
+
+        let w25q128 = |name: &str, id| ChipInfo {
+            vendor: "Winbond".into(),
+            name: name.into(),
+            size: 16 << 20,
+            id,
+        };
+
+        assert_eq!(
+            parse_probe(
+                "Found persistent image /tmp/flashrom.bin, 16777216 B matches.\n\
+                 Found Winbond flash chip \"W25Q128.V\" (16384 kB, SPI) on dummy.\n\
+                 No operations were specified.\n"
             ),
-            Ok(8388608)
+            vec![w25q128("W25Q128.V", None)]
         );
 
         assert_eq!(
-            flashrom_extract_size("There was a catastrophic error."),
-            Err("Found no purely-numeric lines in flashrom output".into())
+            parse_probe(
+                "Probing for Winbond W25Q128.V, 16384 kB: compare_id: id1 0xef, id2 0x4018\n\
+                 Found Winbond flash chip \"W25Q128.V\" (16384 kB, SPI) on dummy.\n\
+                 Probing for Winbond W25Q20.W, 256 kB: compare_id: id1 0xef, id2 0x4018\n\
+                 Found Winbond flash chip \"W25Q128.V\" (16384 kB, SPI).\n"
+            ),
+            vec![w25q128("W25Q128.V", Some((0xef, 0x4018)))]
+        );
+
+        assert_eq!(
+            parse_probe(
+                "Probing for Programmer Opaque flash chip, 0 kB: Probing for AMD \
+                 Am29F010, 128 kB: probe_jedec_common: id1 0xff, id2 0xff, id1 parity violation\n\
+                 Probing for Winbond W25Q128.V, 16384 kB: compare_id: id1 0xef, id2 0x4018\n\
+                 Added layout entry 00000000 - 00ffffff named complete flash\n\
+                 Found Winbond flash chip \"W25Q128.V\" (16384 kB, SPI) on dummy.\n\
+                 Chip status register is 0x00.\n\
+                 Probing for Winbond W25Q128.V..M, 16384 kB: compare_id: id1 0xef, id2 0x4018\n\
+                 Found Winbond flash chip \"W25Q128.V..M\" (16384 kB, SPI) on dummy.\n\
+                 Probing for Winbond W25Q20.W, 256 kB: compare_id: id1 0xef, id2 0x4018\n\
+                 Multiple flash chip definitions match the detected chip(s): \
+                 \"W25Q128.V\", \"W25Q128.V..M\"\n"
+            ),
+            vec![
+                w25q128("W25Q128.V", Some((0xef, 0x4018))),
+                w25q128("W25Q128.V..M", Some((0xef, 0x4018))),
+            ]
+        );
+
+        assert_eq!(
+            parse_probe("Found chipset \"Intel Braswell\".\nNo EEPROM/flash device found.\n"),
+            vec![]
         );
     }
 
@@ -625,4 +2181,51 @@ mod tests {
             None
         )
     }
+
+    /// File names of any bytes but NUL, which cannot appear in arguments.
+    fn file_name() -> impl Strategy<Value = OsString> {
+        proptest::collection::vec(1u8.., 1..64).prop_map(OsString::from_vec)
+    }
+
+    proptest! {
+        #[test]
+        fn decode_file_names(name in file_name()) {
+            let path = Path::new(&name);
+            let opts = FlashromOpt::builder()
+                .write(path)
+                .layout(LayoutSource::File(path))
+                .image([RegionFile::with_file("RO_VPD", path).to_arg()])
+                .output(path)
+                .build()
+                .unwrap();
+
+            let mut region = OsString::from("RO_VPD:");
+            region.push(&name);
+            prop_assert_eq!(
+                flashrom_decode_opts(opts),
+                vec![
+                    "-w".into(),
+                    name.clone(),
+                    "-l".into(),
+                    name.clone(),
+                    "-i".into(),
+                    region,
+                    "-o".into(),
+                    name.clone(),
+                ]
+            );
+        }
+
+        #[test]
+        fn run_command_file_names(name in file_name()) {
+            // The shell prints its argument back exactly as it was passed.
+            let args = [
+                "-c".as_ref(),
+                "printf %s \"$0\"".as_ref(),
+                name.as_os_str(),
+            ];
+            let (stdout, _) = super::run_command("sh", &args, Default::default()).unwrap();
+            prop_assert_eq!(stdout, name.as_bytes());
+        }
+    }
 }
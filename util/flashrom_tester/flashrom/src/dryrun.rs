@@ -0,0 +1,268 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use crate::cmd::{check_wp_toggled, Op};
+use crate::{
+    Capabilities, ChipDatabase, Flashrom, FlashromCmd, FlashromError, LayoutSource, Programmer,
+    ProgressCallback, ROMWriteSpecifics, WpRange, WpStatus,
+};
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// Set once a `DryRun` is created, after which commands controlling the
+/// hardware write protect are only planned.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Commands which would have been run, in order.
+static PLAN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Return true if commands are only planned rather than run, because a
+/// `DryRun` has been created.
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/// Return the commands planned so far, in the order they would have run.
+pub fn dry_run_plan() -> Vec<String> {
+    PLAN.lock().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+/// Add `step`, such as a command line, to the plan instead of taking it.
+pub fn dry_run_step(step: String) {
+    let mut plan = PLAN.lock().unwrap_or_else(PoisonError::into_inner);
+    plan.push(step);
+    info!("Dry run step {}: {}", plan.len(), plan[plan.len() - 1]);
+}
+
+/// A `Flashrom` which never runs flashrom, but plans the commands a
+/// `FlashromCmd` would run and answers from a simulated flash instead.
+///
+/// Creating a `DryRun` puts the whole process into dry run mode, so commands
+/// `dut_ctrl_toggle_wp` and `shell_toggle_wp` would run are planned as well. The plan can be
+/// retrieved with `dry_run_plan`.
+pub struct DryRun {
+    cmd: FlashromCmd,
+    sim: Box<dyn Flashrom>,
+}
+
+impl DryRun {
+    /// Plan the commands `cmd` would run, answering with the results of the
+    /// same operations on `sim`.
+    pub fn new(cmd: FlashromCmd, sim: Box<dyn Flashrom>) -> Self {
+        DRY_RUN.store(true, Ordering::SeqCst);
+        DryRun { cmd, sim }
+    }
+
+    fn plan(&self, op: Op) -> Result<(), FlashromError> {
+        dry_run_step(self.cmd.command_line(&op)?);
+        Ok(())
+    }
+}
+
+impl Flashrom for DryRun {
+    fn get_size(&self) -> Result<i64, FlashromError> {
+        self.plan(Op::Size)?;
+        self.sim.get_size()
+    }
+
+    fn name(&self) -> Result<(String, String), FlashromError> {
+        self.plan(Op::Name)?;
+        self.sim.name()
+    }
+
+    fn probe(&self) -> Result<Vec<crate::ChipInfo>, FlashromError> {
+        self.plan(Op::Probe)?;
+        self.sim.probe()
+    }
+
+    fn write_file_with_layout(&self, rws: &ROMWriteSpecifics) -> Result<bool, FlashromError> {
+        self.plan(Op::WriteLayout(rws))?;
+        self.sim.write_file_with_layout(rws)
+    }
+
+    fn wp_range(&self, range: (i64, i64), wp_enable: bool) -> Result<bool, FlashromError> {
+        self.plan(Op::WpRange(range, wp_enable))?;
+        self.sim.wp_range(range, wp_enable)
+    }
+
+    fn wp_region(
+        &self,
+        name: &str,
+        layout: LayoutSource,
+        wp_enable: bool,
+    ) -> Result<bool, FlashromError> {
+        self.plan(Op::WpRegion(name, layout, wp_enable))?;
+        self.sim.wp_region(name, layout, wp_enable)
+    }
+
+    fn wp_list(&self) -> Result<Vec<WpRange>, FlashromError> {
+        self.plan(Op::WpList)?;
+        self.sim.wp_list()
+    }
+
+    fn get_wp_status(&self) -> Result<WpStatus, FlashromError> {
+        self.plan(Op::WpStatus)?;
+        self.sim.get_wp_status()
+    }
+
+    fn wp_toggle(&self, en: bool) -> Result<bool, FlashromError> {
+        // Plan the same runs as FlashromCmd::wp_toggle.
+        let rom_sz = if en { Some(self.get_size()?) } else { None };
+        self.plan(Op::WpToggle(rom_sz))?;
+        self.sim.wp_toggle(en)?;
+        check_wp_toggled(self, en)
+    }
+
+    fn read_region(
+        &self,
+        layout: LayoutSource,
+        regions: &[&str],
+        path: &Path,
+    ) -> Result<(), FlashromError> {
+        self.plan(Op::ReadRegion(layout, regions, path))?;
+        self.sim.read_region(layout, regions, path)
+    }
+
+    fn erase_region(&self, layout: LayoutSource, regions: &[&str]) -> Result<(), FlashromError> {
+        self.plan(Op::EraseRegion(layout, regions))?;
+        self.sim.erase_region(layout, regions)
+    }
+
+    fn read(&self, path: &Path) -> Result<(), FlashromError> {
+        self.plan(Op::Read(path))?;
+        self.sim.read(path)
+    }
+
+    fn write(&self, path: &Path) -> Result<(), FlashromError> {
+        self.plan(Op::Write(path))?;
+        self.sim.write(path)
+    }
+
+    fn verify(&self, path: &Path) -> Result<(), FlashromError> {
+        self.plan(Op::Verify(path))?;
+        self.sim.verify(path)
+    }
+
+    fn erase(&self) -> Result<(), FlashromError> {
+        self.plan(Op::Erase)?;
+        self.sim.erase()
+    }
+
+    /// The programmer of the commands, rather than of the simulated flash.
+    fn programmer(&self) -> &Programmer {
+        self.cmd.programmer()
+    }
+
+    fn emulated_hw_wp(&self) -> Option<bool> {
+        self.sim.emulated_hw_wp()
+    }
+
+    fn set_emulated_hw_wp(&self, en: bool) -> Result<(), FlashromError> {
+        // Planned runs of the dummy programmer pass the signal as well.
+        if self.cmd.emulated_hw_wp().is_some() {
+            self.cmd.set_emulated_hw_wp(en)?;
+        }
+        self.sim.set_emulated_hw_wp(en)
+    }
+
+    /// What the flashrom binary supports, which it is safe to ask as the chip
+    /// is not accessed.
+    fn capabilities(&self) -> Capabilities {
+        self.cmd.capabilities()
+    }
+
+    fn supported_chips(&self) -> Result<ChipDatabase, FlashromError> {
+        // Listing the chips doesn't touch the flash, so it is safe to run.
+        self.cmd.supported_chips()
+    }
+
+    fn set_progress(&mut self, callback: ProgressCallback) {
+        self.sim.set_progress(callback);
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::{dry_run, dry_run_plan, DryRun};
+    use crate::{
+        dut_ctrl_toggle_wp, Capabilities, Flashrom, FlashromCmd, MockFlashrom, Programmer,
+    };
+
+    #[test]
+    fn plan() {
+        let cmd = FlashromCmd {
+            path: "/nonexistent/flashrom".into(),
+            programmer: Programmer::new("raiden_debug_spi").with_param("target", "AP"),
+            chip: Some("W25Q128.V".into()),
+            progress: Default::default(),
+            timeouts: Default::default(),
+            terminate: None,
+            capabilities: Capabilities::ALL.into(),
+            emulated_hw_wp: Default::default(),
+        };
+        let dry = DryRun::new(cmd, Box::new(MockFlashrom::new(vec![0; 0x1000])));
+        assert!(dry_run());
+
+        // Results come from the simulated flash.
+        assert_eq!(dry.get_size().unwrap(), 0x1000);
+        assert!(dry.wp_toggle(true).unwrap());
+        dut_ctrl_toggle_wp(true, None).unwrap();
+        dry.set_emulated_hw_wp(true).unwrap();
+        dry.erase().unwrap_err();
+        dut_ctrl_toggle_wp(false, None).unwrap();
+        dry.set_emulated_hw_wp(false).unwrap();
+        dry.probe().unwrap();
+
+        let prefix = "/nonexistent/flashrom -p raiden_debug_spi:target=AP";
+        assert_eq!(
+            dry_run_plan(),
+            [
+                format!("{} --flash-size -c W25Q128.V", prefix),
+                format!("{} --flash-size -c W25Q128.V", prefix),
+                format!(
+                    "{} --wp-range 0x000000,0x001000 --wp-enable -c W25Q128.V",
+                    prefix
+                ),
+                format!("{} --wp-status -c W25Q128.V", prefix),
+                "dut-control fw_wp_en:off fw_wp:on".into(),
+                format!("{} -E -c W25Q128.V", prefix),
+                "dut-control fw_wp_en:on fw_wp:off".into(),
+                format!("{} -V", prefix),
+            ]
+        );
+    }
+}
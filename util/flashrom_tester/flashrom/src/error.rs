@@ -0,0 +1,356 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use std::time::Duration;
+use std::{error, fmt, io};
+
+/// An error from running flashrom or interpreting its results.
+#[derive(Debug)]
+pub enum FlashromError {
+    /// flashrom could not be started.
+    Spawn { cmdline: String, source: io::Error },
+    /// flashrom ran but exited with a non-zero status.
+    Exit {
+        cmdline: String,
+        code: i32,
+        stdout: String,
+        stderr: String,
+    },
+    /// flashrom was terminated by a signal.
+    Signal {
+        cmdline: String,
+        signal: i32,
+        stdout: String,
+        stderr: String,
+    },
+    /// flashrom ran for longer than allowed and was killed.
+    Timeout {
+        cmdline: String,
+        timeout: Duration,
+        stdout: String,
+        stderr: String,
+    },
+    /// flashrom was killed because the operation was cancelled.
+    Cancelled {
+        cmdline: String,
+        stdout: String,
+        stderr: String,
+    },
+    /// A libflashrom function returned an error code.
+    Lib { function: &'static str, code: i32 },
+    /// The output of flashrom could not be understood.
+    Parse(String),
+    /// The operation is not supported by the programmer, chip or backend.
+    Unsupported(String),
+    /// An I/O error not from flashrom itself, such as on an image file.
+    Io(io::Error),
+    /// Any other failure.
+    Other(String),
+}
+
+/// Broad reasons for flashrom to fail, for callers which need to tell them apart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The programmer could not be found or initialized.
+    ProgrammerInit,
+    /// No flash chip was found, or several were and none could be chosen.
+    ChipNotFound,
+    /// The operation was refused because the flash is write protected.
+    WriteProtected,
+    /// The operation is not supported.
+    Unsupported,
+    /// The operation took too long.
+    Timeout,
+    /// The operation was cancelled.
+    Cancelled,
+    Other,
+}
+
+/// Messages printed by flashrom, in order of precedence, identifying the kind
+/// of a failure.
+const OUTPUT_KINDS: &[(&str, ErrorKind)] = &[
+    ("Unknown programmer", ErrorKind::ProgrammerInit),
+    ("Unhandled programmer parameters", ErrorKind::ProgrammerInit),
+    (
+        "Programmer initialization failed",
+        ErrorKind::ProgrammerInit,
+    ),
+    ("No EEPROM/flash device found", ErrorKind::ChipNotFound),
+    (
+        "Multiple flash chip definitions match",
+        ErrorKind::ChipNotFound,
+    ),
+    ("is write protected", ErrorKind::WriteProtected),
+    (
+        "Block protection could not be disabled",
+        ErrorKind::WriteProtected,
+    ),
+    ("Hardware protection is active", ErrorKind::WriteProtected),
+    (
+        "hardware status register protection is enabled",
+        ErrorKind::WriteProtected,
+    ),
+    (
+        "cannot disable permanent write-protection",
+        ErrorKind::WriteProtected,
+    ),
+    ("write protect is not supported", ErrorKind::Unsupported),
+    ("WP operations are not implemented", ErrorKind::Unsupported),
+    (
+        "requested protection mode is not supported",
+        ErrorKind::Unsupported,
+    ),
+    (
+        "requested protection range is not supported",
+        ErrorKind::Unsupported,
+    ),
+    (
+        "--wp-list is not currently implemented",
+        ErrorKind::Unsupported,
+    ),
+    (
+        "could not determine what protection ranges are available",
+        ErrorKind::Unsupported,
+    ),
+];
+
+impl FlashromError {
+    /// Classify this error, inspecting the output of flashrom where needed.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FlashromError::Exit { stdout, stderr, .. }
+            | FlashromError::Signal { stdout, stderr, .. } => OUTPUT_KINDS
+                .iter()
+                .find(|(msg, _)| stderr.contains(msg) || stdout.contains(msg))
+                .map_or(ErrorKind::Other, |&(_, kind)| kind),
+            FlashromError::Lib { function, code } => match (*function, *code) {
+                ("flashrom_programmer_init", _) => ErrorKind::ProgrammerInit,
+                ("flashrom_flash_probe", _) => ErrorKind::ChipNotFound,
+                // FLASHROM_WP_ERR_{CHIP,RANGE,MODE}_UNSUPPORTED and
+                // FLASHROM_WP_ERR_RANGE_LIST_UNAVAILABLE.
+                (f, 1 | 6 | 7 | 8) if f.starts_with("flashrom_wp_") => ErrorKind::Unsupported,
+                _ => ErrorKind::Other,
+            },
+            FlashromError::Unsupported(_) => ErrorKind::Unsupported,
+            FlashromError::Timeout { .. } => ErrorKind::Timeout,
+            FlashromError::Cancelled { .. } => ErrorKind::Cancelled,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for FlashromError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlashromError::Spawn { cmdline, source } => {
+                write!(f, "Failed to run `{}`: {}", cmdline, source)
+            }
+            FlashromError::Exit {
+                cmdline,
+                code,
+                stderr,
+                ..
+            } => write!(
+                f,
+                "`{}` exited with error code {}\n{}",
+                cmdline, code, stderr
+            ),
+            FlashromError::Signal {
+                cmdline,
+                signal,
+                stderr,
+                ..
+            } => write!(
+                f,
+                "`{}` was terminated by signal {}\n{}",
+                cmdline, signal, stderr
+            ),
+            FlashromError::Timeout {
+                cmdline,
+                timeout,
+                stderr,
+                ..
+            } => write!(f, "`{}` timed out after {:?}\n{}", cmdline, timeout, stderr),
+            FlashromError::Cancelled {
+                cmdline, stderr, ..
+            } => write!(f, "`{}` was cancelled\n{}", cmdline, stderr),
+            FlashromError::Lib { function, code } => {
+                write!(f, "{}() failed with error code {}", function, code)
+            }
+            FlashromError::Parse(msg)
+            | FlashromError::Unsupported(msg)
+            | FlashromError::Other(msg) => write!(f, "{}", msg),
+            FlashromError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for FlashromError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            FlashromError::Spawn { source, .. } | FlashromError::Io(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FlashromError {
+    fn from(e: io::Error) -> Self {
+        FlashromError::Io(e)
+    }
+}
+
+impl From<String> for FlashromError {
+    fn from(msg: String) -> Self {
+        FlashromError::Other(msg)
+    }
+}
+
+impl From<&str> for FlashromError {
+    fn from(msg: &str) -> Self {
+        FlashromError::Other(msg.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorKind, FlashromError};
+    use std::error::Error;
+    use std::io;
+
+    fn exit_error(stdout: &str, stderr: &str) -> FlashromError {
+        FlashromError::Exit {
+            cmdline: "flashrom -p host -w image.bin".into(),
+            code: 1,
+            stdout: stdout.into(),
+            stderr: stderr.into(),
+        }
+    }
+
+    #[test]
+    fn kind() {
+        assert_eq!(
+            exit_error("", "Error: Programmer initialization failed.\n").kind(),
+            ErrorKind::ProgrammerInit
+        );
+        assert_eq!(
+            exit_error("No EEPROM/flash device found.\n", "").kind(),
+            ErrorKind::ChipNotFound
+        );
+        assert_eq!(
+            exit_error("", "At least part of the write range is write protected!\n").kind(),
+            ErrorKind::WriteProtected
+        );
+        assert_eq!(
+            exit_error(
+                "",
+                "Error: write protect is not supported on this flash chip.\n"
+            )
+            .kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(
+            exit_error(
+                "",
+                "Failed to get list of protection ranges: \
+                 WP operations are not implemented for this chip\n"
+            )
+            .kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(
+            exit_error("", "--wp-list is not currently implemented for MTD.\n").kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(
+            exit_error(
+                "",
+                "Note: hardware status register protection is enabled. The chip's WP# pin \
+                 must be set to an inactive voltage level to be able to change the WP settings.\n"
+            )
+            .kind(),
+            ErrorKind::WriteProtected
+        );
+        assert_eq!(exit_error("", "Erase failed\n").kind(), ErrorKind::Other);
+        assert_eq!(
+            exit_error(
+                "Warning: Setting the SPI clock rate is not supported!\n",
+                "Erase failed\n"
+            )
+            .kind(),
+            ErrorKind::Other
+        );
+        assert_eq!(
+            FlashromError::Lib {
+                function: "flashrom_wp_get_available_ranges",
+                code: 8
+            }
+            .kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(
+            FlashromError::Lib {
+                function: "flashrom_flash_probe",
+                code: 2
+            }
+            .kind(),
+            ErrorKind::ChipNotFound
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            exit_error("", "Erase failed\n").to_string(),
+            "`flashrom -p host -w image.bin` exited with error code 1\nErase failed\n"
+        );
+        assert_eq!(FlashromError::from("oops").to_string(), "oops");
+    }
+
+    #[test]
+    fn source() {
+        let e = FlashromError::Spawn {
+            cmdline: "flashrom".into(),
+            source: io::Error::from(io::ErrorKind::NotFound),
+        };
+        assert_eq!(
+            e.source()
+                .and_then(|s| s.downcast_ref::<io::Error>())
+                .map(io::Error::kind),
+            Some(io::ErrorKind::NotFound)
+        );
+        assert!(exit_error("", "").source().is_none());
+    }
+}
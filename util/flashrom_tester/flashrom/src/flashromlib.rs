@@ -0,0 +1,1032 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use crate::chips::is_generic;
+use crate::cmd::parse_probe;
+use crate::layout::{self, parse_layout_file};
+use crate::{
+    ChipDatabase, ChipInfo, FlashromError, LayoutSource, Programmer, Progress, ProgressCallback,
+    ProgressStage, ROMWriteSpecifics, Region, SupportedChip, TestStatus, Tested, WpMode, WpRange,
+    WpStatus,
+};
+
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// Raw bindings to the symbols exported by libflashrom (see libflashrom.map).
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_uint, c_void};
+
+    #[repr(C)]
+    pub struct flashrom_programmer {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct flashrom_flashctx {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct flashrom_layout {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct flashrom_wp_cfg {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct flashrom_wp_ranges {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    #[derive(Debug)]
+    pub struct flashrom_progress {
+        pub stage: c_int,
+        pub current: usize,
+        pub total: usize,
+        pub user_data: *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct flashrom_tested {
+        pub probe: c_int,
+        pub read: c_int,
+        pub erase: c_int,
+        pub write: c_int,
+    }
+
+    #[repr(C)]
+    pub struct flashrom_flashchip_info {
+        pub vendor: *const c_char,
+        pub name: *const c_char,
+        pub total_size: c_uint,
+        pub tested: flashrom_tested,
+    }
+
+    pub type flashrom_progress_callback = extern "C" fn(flashctx: *mut flashrom_flashctx);
+
+    /// The `va_list` is taken as a pointer, which is how it is passed on the
+    /// platforms flashrom_tester runs on.
+    pub type flashrom_log_callback =
+        unsafe extern "C" fn(level: c_int, format: *const c_char, args: *mut c_void) -> c_int;
+
+    // enum flashrom_log_level
+    pub const FLASHROM_MSG_ERROR: c_int = 0;
+    pub const FLASHROM_MSG_WARN: c_int = 1;
+    pub const FLASHROM_MSG_DEBUG: c_int = 3;
+
+    // enum flashrom_progress_stage
+    pub const FLASHROM_PROGRESS_READ: c_int = 0;
+    pub const FLASHROM_PROGRESS_WRITE: c_int = 1;
+    pub const FLASHROM_PROGRESS_ERASE: c_int = 2;
+
+    // enum flashrom_test_state
+    pub const FLASHROM_TESTED_OK: c_int = 0;
+    pub const FLASHROM_TESTED_BAD: c_int = 2;
+    pub const FLASHROM_TESTED_NA: c_int = 4;
+
+    // enum flashrom_flag
+    pub const FLASHROM_FLAG_VERIFY_AFTER_WRITE: c_int = 2;
+
+    // enum flashrom_wp_mode
+    pub const FLASHROM_WP_MODE_DISABLED: c_int = 0;
+    pub const FLASHROM_WP_MODE_HARDWARE: c_int = 1;
+    pub const FLASHROM_WP_MODE_POWER_CYCLE: c_int = 2;
+    pub const FLASHROM_WP_MODE_PERMANENT: c_int = 3;
+
+    // enum flashrom_wp_result
+    pub const FLASHROM_WP_OK: c_int = 0;
+
+    #[link(name = "flashrom")]
+    extern "C" {
+        pub fn flashrom_init(perform_selfcheck: c_int) -> c_int;
+        pub fn flashrom_shutdown() -> c_int;
+        pub fn flashrom_set_log_callback(log_callback: Option<flashrom_log_callback>);
+        pub fn flashrom_supported_flash_chips() -> *mut flashrom_flashchip_info;
+        pub fn flashrom_data_free(p: *mut c_void) -> c_int;
+
+        pub fn flashrom_programmer_init(
+            flashprog: *mut *mut flashrom_programmer,
+            prog_name: *const c_char,
+            prog_params: *const c_char,
+        ) -> c_int;
+        pub fn flashrom_programmer_shutdown(flashprog: *mut flashrom_programmer) -> c_int;
+
+        pub fn flashrom_flash_probe(
+            flashctx: *mut *mut flashrom_flashctx,
+            flashprog: *const flashrom_programmer,
+            chip_name: *const c_char,
+        ) -> c_int;
+        pub fn flashrom_flash_getsize(flashctx: *const flashrom_flashctx) -> usize;
+        pub fn flashrom_flash_erase(flashctx: *mut flashrom_flashctx) -> c_int;
+        pub fn flashrom_flash_release(flashctx: *mut flashrom_flashctx);
+        pub fn flashrom_flag_set(flashctx: *mut flashrom_flashctx, flag: c_int, value: bool);
+        pub fn flashrom_set_progress_callback(
+            flashctx: *mut flashrom_flashctx,
+            progress_callback: Option<flashrom_progress_callback>,
+            progress_state: *mut flashrom_progress,
+        );
+
+        pub fn flashrom_image_read(
+            flashctx: *mut flashrom_flashctx,
+            buffer: *mut c_void,
+            buffer_len: usize,
+        ) -> c_int;
+        pub fn flashrom_image_write(
+            flashctx: *mut flashrom_flashctx,
+            buffer: *mut c_void,
+            buffer_len: usize,
+            refbuffer: *const c_void,
+        ) -> c_int;
+        pub fn flashrom_image_verify(
+            flashctx: *mut flashrom_flashctx,
+            buffer: *const c_void,
+            buffer_len: usize,
+        ) -> c_int;
+
+        pub fn flashrom_layout_new(layout: *mut *mut flashrom_layout) -> c_int;
+        pub fn flashrom_layout_add_region(
+            layout: *mut flashrom_layout,
+            start: usize,
+            end: usize,
+            name: *const c_char,
+        ) -> c_int;
+        pub fn flashrom_layout_include_region(
+            layout: *mut flashrom_layout,
+            name: *const c_char,
+        ) -> c_int;
+        pub fn flashrom_layout_read_fmap_from_rom(
+            layout: *mut *mut flashrom_layout,
+            flashctx: *mut flashrom_flashctx,
+            offset: usize,
+            len: usize,
+        ) -> c_int;
+        pub fn flashrom_layout_read_fmap_from_buffer(
+            layout: *mut *mut flashrom_layout,
+            flashctx: *mut flashrom_flashctx,
+            buf: *const u8,
+            len: usize,
+        ) -> c_int;
+        pub fn flashrom_layout_read_from_ifd(
+            layout: *mut *mut flashrom_layout,
+            flashctx: *mut flashrom_flashctx,
+            dump: *const c_void,
+            len: usize,
+        ) -> c_int;
+        pub fn flashrom_layout_get_region_range(
+            layout: *mut flashrom_layout,
+            name: *const c_char,
+            start: *mut c_uint,
+            len: *mut c_uint,
+        ) -> c_int;
+        pub fn flashrom_layout_release(layout: *mut flashrom_layout);
+        pub fn flashrom_layout_set(
+            flashctx: *mut flashrom_flashctx,
+            layout: *const flashrom_layout,
+        );
+
+        pub fn flashrom_wp_cfg_new(cfg: *mut *mut flashrom_wp_cfg) -> c_int;
+        pub fn flashrom_wp_cfg_release(cfg: *mut flashrom_wp_cfg);
+        pub fn flashrom_wp_set_mode(cfg: *mut flashrom_wp_cfg, mode: c_int);
+        pub fn flashrom_wp_get_mode(cfg: *const flashrom_wp_cfg) -> c_int;
+        pub fn flashrom_wp_set_range(cfg: *mut flashrom_wp_cfg, start: usize, len: usize);
+        pub fn flashrom_wp_get_range(
+            start: *mut usize,
+            len: *mut usize,
+            cfg: *const flashrom_wp_cfg,
+        );
+        pub fn flashrom_wp_read_cfg(
+            cfg: *mut flashrom_wp_cfg,
+            flash: *mut flashrom_flashctx,
+        ) -> c_int;
+        pub fn flashrom_wp_write_cfg(
+            flash: *mut flashrom_flashctx,
+            cfg: *const flashrom_wp_cfg,
+        ) -> c_int;
+        pub fn flashrom_wp_get_available_ranges(
+            ranges: *mut *mut flashrom_wp_ranges,
+            flash: *mut flashrom_flashctx,
+        ) -> c_int;
+        pub fn flashrom_wp_ranges_get_count(ranges: *const flashrom_wp_ranges) -> usize;
+        pub fn flashrom_wp_ranges_get_range(
+            start: *mut usize,
+            len: *mut usize,
+            ranges: *const flashrom_wp_ranges,
+            index: c_uint,
+        ) -> c_int;
+        pub fn flashrom_wp_ranges_release(ranges: *mut flashrom_wp_ranges);
+    }
+
+    // From the C library, to format messages passed to the log callback.
+    extern "C" {
+        pub fn vsnprintf(
+            s: *mut c_char,
+            n: usize,
+            format: *const c_char,
+            args: *mut c_void,
+        ) -> c_int;
+    }
+}
+
+/// A flash chip accessed through libflashrom rather than the flashrom binary.
+///
+/// The programmer is initialized and the chip probed once when this is created,
+/// and that session stays open until it is dropped. libflashrom only supports a
+/// single initialized programmer at a time, so only one `FlashromLib` may be live.
+#[derive(Debug)]
+pub struct FlashromLib {
+    programmer: Programmer,
+    /// The chip found by probing, as reported in the log of libflashrom.
+    chip: Option<ChipInfo>,
+    flashprog: *mut ffi::flashrom_programmer,
+    flashctx: *mut ffi::flashrom_flashctx,
+    progress: Box<ProgressState>,
+}
+
+/// Progress written by libflashrom, and the callback to pass it on to.
+#[derive(Debug)]
+struct ProgressState {
+    ffi: ffi::flashrom_progress,
+    callback: ProgressCallback,
+}
+
+/// The progress state of the live `FlashromLib`, since libflashrom only passes
+/// the flash context to the progress callback.
+static PROGRESS: AtomicPtr<ProgressState> = AtomicPtr::new(ptr::null_mut());
+
+extern "C" fn progress_callback(_flashctx: *mut ffi::flashrom_flashctx) {
+    let state = PROGRESS.load(Ordering::Acquire);
+    if !state.is_null() {
+        report_progress(unsafe { &*state });
+    }
+}
+
+/// Pass the progress libflashrom wrote to `state` on to its callback.
+fn report_progress(state: &ProgressState) {
+    let stage = match state.ffi.stage {
+        ffi::FLASHROM_PROGRESS_READ => ProgressStage::Read,
+        ffi::FLASHROM_PROGRESS_WRITE => ProgressStage::Write,
+        ffi::FLASHROM_PROGRESS_ERASE => ProgressStage::Erase,
+        _ => return,
+    };
+    state.callback.report(Progress {
+        stage,
+        current: state.ffi.current,
+        total: state.ffi.total,
+    });
+}
+
+/// Messages logged by libflashrom, which arrive in pieces of lines.
+struct LogState {
+    /// The start of a line not yet ended.
+    partial: String,
+    /// The most severe level of the pieces of `partial`.
+    level: c_int,
+    /// Complete lines are added here while it is set.
+    capture: Option<String>,
+}
+
+static LOG: Mutex<LogState> = Mutex::new(LogState {
+    partial: String::new(),
+    level: c_int::MAX,
+    capture: None,
+});
+
+unsafe extern "C" fn log_callback(level: c_int, format: *const c_char, args: *mut c_void) -> c_int {
+    // More verbose levels dump the data transferred a byte at a time, which
+    // would slow every operation down to a crawl.
+    if level > ffi::FLASHROM_MSG_DEBUG {
+        return 0;
+    }
+    let mut buf = [0 as c_char; 1024];
+    let len = ffi::vsnprintf(buf.as_mut_ptr(), buf.len(), format, args);
+    if len > 0 {
+        log_message(level, &CStr::from_ptr(buf.as_ptr()).to_string_lossy());
+    }
+    len
+}
+
+/// Log each line completed by `message`, at the most severe level of the
+/// messages it was made from.
+fn log_message(level: c_int, message: &str) {
+    let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    log.partial.push_str(message);
+    log.level = log.level.min(level);
+    while let Some(end) = log.partial.find('\n') {
+        let line: String = log.partial.drain(..=end).collect();
+        if let Some(capture) = &mut log.capture {
+            capture.push_str(&line);
+        }
+        let level = match log.level {
+            ffi::FLASHROM_MSG_ERROR => log::Level::Error,
+            ffi::FLASHROM_MSG_WARN => log::Level::Warn,
+            _ => log::Level::Debug,
+        };
+        log.level = c_int::MAX;
+        if !line.trim().is_empty() {
+            log!(level, "libflashrom: {}", line.trim_end());
+        }
+    }
+}
+
+/// Start or stop keeping the lines logged by libflashrom, returning those
+/// kept since it was last started.
+fn capture_log(start: bool) -> Option<String> {
+    let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    std::mem::replace(
+        &mut log.capture,
+        if start { Some(String::new()) } else { None },
+    )
+}
+
+/// Convert a protection mode from libflashrom.
+fn wp_mode(mode: c_int) -> Result<WpMode, FlashromError> {
+    Ok(match mode {
+        ffi::FLASHROM_WP_MODE_DISABLED => WpMode::Disabled,
+        ffi::FLASHROM_WP_MODE_HARDWARE => WpMode::Hardware,
+        ffi::FLASHROM_WP_MODE_POWER_CYCLE => WpMode::PowerCycle,
+        ffi::FLASHROM_WP_MODE_PERMANENT => WpMode::Permanent,
+        _ => return Err(format!("Unknown write protect mode {}", mode).into()),
+    })
+}
+
+/// The libflashrom protection mode for enabling or disabling write protect,
+/// matching what flashrom's `--wp-enable` and `--wp-disable` set.
+fn ffi_wp_mode(enable: bool) -> c_int {
+    if enable {
+        ffi::FLASHROM_WP_MODE_HARDWARE
+    } else {
+        ffi::FLASHROM_WP_MODE_DISABLED
+    }
+}
+
+/// Convert a range as (start, len) for libflashrom.
+fn ffi_range((start, len): (i64, i64)) -> Result<(usize, usize), FlashromError> {
+    match (usize::try_from(start), usize::try_from(len)) {
+        (Ok(start), Ok(len)) => Ok((start, len)),
+        _ => Err(format!("Invalid write protect range {:#x?}", (start, len)).into()),
+    }
+}
+
+/// Turn a libflashrom return code into a Result, naming the failed call on error.
+fn check(ret: c_int, function: &'static str) -> Result<(), FlashromError> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(FlashromError::Lib {
+            function,
+            code: ret,
+        })
+    }
+}
+
+/// Wrap an I/O error on `path`, keeping its kind but naming the file.
+fn file_error(action: &str, path: &Path, e: std::io::Error) -> FlashromError {
+    let msg = format!("Failed to {} {}: {}", action, path.display(), e);
+    FlashromError::Io(std::io::Error::new(e.kind(), msg))
+}
+
+fn test_status(state: c_int) -> TestStatus {
+    match state {
+        ffi::FLASHROM_TESTED_OK => TestStatus::Ok,
+        ffi::FLASHROM_TESTED_BAD => TestStatus::Bad,
+        ffi::FLASHROM_TESTED_NA => TestStatus::NotApplicable,
+        _ => TestStatus::Untested,
+    }
+}
+
+fn to_cstring(s: &str) -> Result<CString, FlashromError> {
+    CString::new(s).map_err(|_| format!("String {:?} contains a NUL byte", s).into())
+}
+
+impl FlashromLib {
+    /// Initialize libflashrom and `programmer`, then probe for its flash chip.
+    pub fn new(programmer: Programmer) -> Result<FlashromLib, FlashromError> {
+        // The dummy programmer reads its emulated hardware write protect only
+        // here, so it cannot be changed and is left as the parameters give it.
+        let programmer_str = programmer.to_string();
+        let name = to_cstring(&programmer.name)?;
+        let params = to_cstring(
+            programmer_str
+                .split_once(':')
+                .map(|(_, params)| params)
+                .unwrap_or_default(),
+        )?;
+
+        unsafe { ffi::flashrom_set_log_callback(Some(log_callback)) };
+        check(unsafe { ffi::flashrom_init(1) }, "flashrom_init")?;
+
+        let mut flashprog = ptr::null_mut();
+        let ret = unsafe {
+            ffi::flashrom_programmer_init(&mut flashprog, name.as_ptr(), params.as_ptr())
+        };
+        if let Err(e) = check(ret, "flashrom_programmer_init") {
+            unsafe { ffi::flashrom_shutdown() };
+            return Err(e);
+        }
+
+        // libflashrom only logs which chip it found.
+        capture_log(true);
+        let mut flashctx = ptr::null_mut();
+        let ret = unsafe { ffi::flashrom_flash_probe(&mut flashctx, flashprog, ptr::null()) };
+        let chip = capture_log(false).and_then(|log| parse_probe(&log).into_iter().next());
+        if ret != 0 {
+            unsafe {
+                ffi::flashrom_programmer_shutdown(flashprog);
+                ffi::flashrom_shutdown();
+            }
+            match ret {
+                2 => error!("No flash chip found"),
+                3 => error!("Multiple flash chips found; cannot choose one to use"),
+                _ => {}
+            }
+            return Err(FlashromError::Lib {
+                function: "flashrom_flash_probe",
+                code: ret,
+            });
+        }
+
+        // Match the defaults of the flashrom CLI.
+        unsafe { ffi::flashrom_flag_set(flashctx, ffi::FLASHROM_FLAG_VERIFY_AFTER_WRITE, true) };
+
+        Ok(FlashromLib {
+            programmer,
+            chip,
+            flashprog,
+            flashctx,
+            progress: Box::new(ProgressState {
+                ffi: ffi::flashrom_progress {
+                    stage: 0,
+                    current: 0,
+                    total: 0,
+                    user_data: ptr::null_mut(),
+                },
+                callback: ProgressCallback::default(),
+            }),
+        })
+    }
+
+    fn size(&self) -> usize {
+        unsafe { ffi::flashrom_flash_getsize(self.flashctx) }
+    }
+
+    fn read_image(&self) -> Result<Vec<u8>, FlashromError> {
+        let mut buf = vec![0u8; self.size()];
+        let ret =
+            unsafe { ffi::flashrom_image_read(self.flashctx, buf.as_mut_ptr() as _, buf.len()) };
+        check(ret, "flashrom_image_read")?;
+        Ok(buf)
+    }
+
+    fn write_image(&self, mut buf: Vec<u8>) -> Result<(), FlashromError> {
+        let ret = unsafe {
+            ffi::flashrom_image_write(self.flashctx, buf.as_mut_ptr() as _, buf.len(), ptr::null())
+        };
+        check(ret, "flashrom_image_write")
+    }
+
+    /// Run `op` with only the `regions` of the layout from `source` included.
+    fn with_layout<T, F>(
+        &self,
+        source: LayoutSource,
+        regions: &[&str],
+        op: F,
+    ) -> Result<T, FlashromError>
+    where
+        F: FnOnce(&Layout) -> Result<T, FlashromError>,
+    {
+        let layout = Layout::from_source(source, self.flashctx)?;
+        for name in regions {
+            layout.include_region(name)?;
+        }
+
+        unsafe { ffi::flashrom_layout_set(self.flashctx, layout.0) };
+        let out = op(&layout);
+        unsafe { ffi::flashrom_layout_set(self.flashctx, ptr::null()) };
+        out
+    }
+
+    fn read_cfg(&self) -> Result<WpCfg, FlashromError> {
+        let cfg = WpCfg::new()?;
+        let ret = unsafe { ffi::flashrom_wp_read_cfg(cfg.0, self.flashctx) };
+        check(ret, "flashrom_wp_read_cfg")?;
+        Ok(cfg)
+    }
+
+    /// Apply a protection mode and optionally a new range, keeping the rest
+    /// of the chip's configuration.
+    fn write_cfg(&self, range: Option<(i64, i64)>, enable: bool) -> Result<(), FlashromError> {
+        let cfg = self.read_cfg()?;
+        unsafe {
+            if let Some(range) = range {
+                let (start, len) = ffi_range(range)?;
+                ffi::flashrom_wp_set_range(cfg.0, start, len);
+            }
+            ffi::flashrom_wp_set_mode(cfg.0, ffi_wp_mode(enable));
+        }
+        let ret = unsafe { ffi::flashrom_wp_write_cfg(self.flashctx, cfg.0) };
+        check(ret, "flashrom_wp_write_cfg")
+    }
+}
+
+impl Drop for FlashromLib {
+    fn drop(&mut self) {
+        PROGRESS.store(ptr::null_mut(), Ordering::Release);
+        unsafe {
+            ffi::flashrom_flash_release(self.flashctx);
+            if ffi::flashrom_programmer_shutdown(self.flashprog) != 0 {
+                warn!("Failed to shut down the {} programmer", self.programmer);
+            }
+            ffi::flashrom_shutdown();
+            ffi::flashrom_set_log_callback(None);
+        }
+    }
+}
+
+/// Owned `struct flashrom_wp_cfg`.
+struct WpCfg(*mut ffi::flashrom_wp_cfg);
+
+impl WpCfg {
+    fn new() -> Result<WpCfg, FlashromError> {
+        let mut cfg = ptr::null_mut();
+        check(
+            unsafe { ffi::flashrom_wp_cfg_new(&mut cfg) },
+            "flashrom_wp_cfg_new",
+        )?;
+        Ok(WpCfg(cfg))
+    }
+}
+
+impl Drop for WpCfg {
+    fn drop(&mut self) {
+        unsafe { ffi::flashrom_wp_cfg_release(self.0) }
+    }
+}
+
+/// Owned `struct flashrom_layout`.
+struct Layout(*mut ffi::flashrom_layout);
+
+impl Layout {
+    /// Build a layout from `source`, reading any FMAP through `flashctx`.
+    fn from_source(
+        source: LayoutSource,
+        flashctx: *mut ffi::flashrom_flashctx,
+    ) -> Result<Layout, FlashromError> {
+        let mut layout = ptr::null_mut();
+        match source {
+            LayoutSource::File(path) => return Layout::from_file(path),
+            LayoutSource::Fmap => {
+                let len = unsafe { ffi::flashrom_flash_getsize(flashctx) };
+                let ret = unsafe {
+                    ffi::flashrom_layout_read_fmap_from_rom(&mut layout, flashctx, 0, len)
+                };
+                check(ret, "flashrom_layout_read_fmap_from_rom")?;
+            }
+            LayoutSource::FmapFile(path) => {
+                let buf = std::fs::read(path).map_err(|e| file_error("read", path, e))?;
+                let ret = unsafe {
+                    ffi::flashrom_layout_read_fmap_from_buffer(
+                        &mut layout,
+                        flashctx,
+                        buf.as_ptr(),
+                        buf.len(),
+                    )
+                };
+                check(ret, "flashrom_layout_read_fmap_from_buffer")?;
+            }
+            LayoutSource::Ifd => {
+                let ret = unsafe {
+                    ffi::flashrom_layout_read_from_ifd(&mut layout, flashctx, ptr::null(), 0)
+                };
+                check(ret, "flashrom_layout_read_from_ifd")?;
+            }
+        }
+        Ok(Layout(layout))
+    }
+
+    /// Build a layout from the contents of a flashrom layout file.
+    fn from_file(path: &Path) -> Result<Layout, FlashromError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| file_error("read layout file", path, e))?;
+
+        let mut layout = ptr::null_mut();
+        check(
+            unsafe { ffi::flashrom_layout_new(&mut layout) },
+            "flashrom_layout_new",
+        )?;
+        let layout = Layout(layout);
+
+        for (start, end, name) in parse_layout_file(&contents)? {
+            let name = to_cstring(name)?;
+            let ret =
+                unsafe { ffi::flashrom_layout_add_region(layout.0, start, end, name.as_ptr()) };
+            check(ret, "flashrom_layout_add_region")?;
+        }
+        Ok(layout)
+    }
+
+    fn include_region(&self, name: &str) -> Result<(), FlashromError> {
+        let cname = to_cstring(name)?;
+        let ret = unsafe { ffi::flashrom_layout_include_region(self.0, cname.as_ptr()) };
+        check(ret, "flashrom_layout_include_region")
+            .map_err(|_| format!("Region {} is not in the layout", name).into())
+    }
+
+    /// Return the range of region `name` as (start, len).
+    fn region_range(&self, name: &str) -> Result<(usize, usize), FlashromError> {
+        let cname = to_cstring(name)?;
+        let (mut start, mut len) = (0, 0);
+        let ret = unsafe {
+            ffi::flashrom_layout_get_region_range(self.0, cname.as_ptr(), &mut start, &mut len)
+        };
+        check(ret, "flashrom_layout_get_region_range")
+            .map_err(|_| format!("Region {} is not in the layout", name))?;
+        Ok((start as usize, len as usize))
+    }
+}
+
+impl Drop for Layout {
+    fn drop(&mut self) {
+        unsafe { ffi::flashrom_layout_release(self.0) }
+    }
+}
+
+impl crate::Flashrom for FlashromLib {
+    fn get_size(&self) -> Result<i64, FlashromError> {
+        Ok(self.size() as i64)
+    }
+
+    fn name(&self) -> Result<(String, String), FlashromError> {
+        match &self.chip {
+            Some(chip) => Ok((chip.vendor.clone(), chip.name.clone())),
+            None => Err(FlashromError::Parse(
+                "Didn't find chip vendor/name in libflashrom log".into(),
+            )),
+        }
+    }
+
+    fn probe(&self) -> Result<Vec<ChipInfo>, FlashromError> {
+        // The chip was probed when the programmer was initialized.
+        let (vendor, name) = self.name()?;
+        Ok(vec![ChipInfo {
+            vendor,
+            name,
+            size: self.size() as i64,
+            id: self.chip.as_ref().and_then(|c| c.id),
+        }])
+    }
+
+    fn write_file_with_layout(&self, rws: &ROMWriteSpecifics) -> Result<bool, FlashromError> {
+        let mut buf = match rws.write_file {
+            Some(path) => std::fs::read(path).map_err(|e| file_error("read", path, e))?,
+            // Only the regions are written, so the rest of the image is unused.
+            None => vec![0xff; self.size()],
+        };
+
+        let source = match rws.layout {
+            None => {
+                if rws.write_file.is_none() {
+                    return Err("No file to write from was specified".into());
+                }
+                return self.write_image(buf).map(|_| true);
+            }
+            Some(source) => source,
+        };
+        let names: Vec<&str> = rws.regions.iter().map(|r| r.name).collect();
+        self.with_layout(source, &names, |layout| {
+            // Like the flashrom CLI, region files override the whole image.
+            for region in rws.regions {
+                let path = match (region.file, rws.write_file) {
+                    (Some(path), _) => path,
+                    (None, Some(_)) => continue,
+                    (None, None) => {
+                        return Err(format!("No file to write region {} from", region.name).into())
+                    }
+                };
+                let (start, len) = layout.region_range(region.name)?;
+                let contents = std::fs::read(path).map_err(|e| file_error("read", path, e))?;
+                if contents.len() != len {
+                    return Err(format!(
+                        "{} is {} bytes but region {} is {} bytes",
+                        path.display(),
+                        contents.len(),
+                        region.name,
+                        len
+                    )
+                    .into());
+                }
+                buf.get_mut(start..start + len)
+                    .ok_or_else(|| format!("Region {} is outside the image", region.name))?
+                    .copy_from_slice(&contents);
+            }
+            self.write_image(buf)
+        })
+        .map(|_| true)
+    }
+
+    fn layout_regions(&self, source: LayoutSource) -> Result<Vec<Region>, FlashromError> {
+        layout::read_regions(source, || self.read_to_vec())
+    }
+
+    fn wp_range(&self, range: (i64, i64), wp_enable: bool) -> Result<bool, FlashromError> {
+        self.write_cfg(Some(range), wp_enable)?;
+        Ok(true)
+    }
+
+    fn wp_list(&self) -> Result<Vec<WpRange>, FlashromError> {
+        let mut ranges = ptr::null_mut();
+        let ret = unsafe { ffi::flashrom_wp_get_available_ranges(&mut ranges, self.flashctx) };
+        check(ret, "flashrom_wp_get_available_ranges")?;
+
+        let mut out = Vec::new();
+        let count = unsafe { ffi::flashrom_wp_ranges_get_count(ranges) };
+        for i in 0..count {
+            let (mut start, mut len) = (0usize, 0usize);
+            let ret =
+                unsafe { ffi::flashrom_wp_ranges_get_range(&mut start, &mut len, ranges, i as _) };
+            if ret == ffi::FLASHROM_WP_OK {
+                out.push(WpRange {
+                    start: start as i64,
+                    len: len as i64,
+                    mode: None,
+                });
+            }
+        }
+        unsafe { ffi::flashrom_wp_ranges_release(ranges) };
+        Ok(out)
+    }
+
+    fn get_wp_status(&self) -> Result<WpStatus, FlashromError> {
+        let cfg = self.read_cfg()?;
+        let mode = unsafe { ffi::flashrom_wp_get_mode(cfg.0) };
+        let (mut start, mut len) = (0usize, 0usize);
+        unsafe { ffi::flashrom_wp_get_range(&mut start, &mut len, cfg.0) };
+        debug!(
+            "get_wp_status(): mode={} start={:#x} len={:#x}",
+            mode, start, len
+        );
+
+        Ok(WpStatus {
+            mode: wp_mode(mode)?,
+            range: (start as i64, len as i64),
+        })
+    }
+
+    fn wp_toggle(&self, en: bool) -> Result<bool, FlashromError> {
+        let status = if en { "en" } else { "dis" };
+
+        // Protect the whole chip when enabling, like FlashromCmd does.
+        let range = if en {
+            Some((0, self.get_size()?))
+        } else {
+            None
+        };
+        match self.write_cfg(range, en) {
+            Ok(()) => {
+                info!("Successfully {}abled write-protect", status);
+                Ok(true)
+            }
+            Err(e) => {
+                error!("Cannot {}able write-protect: {}", status, e);
+                Err(e)
+            }
+        }
+    }
+
+    fn read_region(
+        &self,
+        layout: LayoutSource,
+        regions: &[&str],
+        path: &Path,
+    ) -> Result<(), FlashromError> {
+        let buf = self.with_layout(layout, regions, |_| self.read_image())?;
+        std::fs::write(path, buf).map_err(|e| file_error("write", path, e))
+    }
+
+    fn erase_region(&self, layout: LayoutSource, regions: &[&str]) -> Result<(), FlashromError> {
+        self.with_layout(layout, regions, |_| {
+            check(
+                unsafe { ffi::flashrom_flash_erase(self.flashctx) },
+                "flashrom_flash_erase",
+            )
+        })
+    }
+
+    fn read(&self, path: &Path) -> Result<(), FlashromError> {
+        let buf = self.read_image()?;
+        std::fs::write(path, buf).map_err(|e| file_error("write", path, e))
+    }
+
+    fn write(&self, path: &Path) -> Result<(), FlashromError> {
+        let buf = std::fs::read(path).map_err(|e| file_error("read", path, e))?;
+        self.write_image(buf)
+    }
+
+    fn verify(&self, path: &Path) -> Result<(), FlashromError> {
+        let buf = std::fs::read(path).map_err(|e| file_error("read", path, e))?;
+        self.verify_slice(&buf)
+    }
+
+    fn read_to_vec(&self) -> Result<Vec<u8>, FlashromError> {
+        self.read_image()
+    }
+
+    fn write_from_slice(&self, contents: &[u8]) -> Result<(), FlashromError> {
+        self.write_image(contents.to_vec())
+    }
+
+    fn verify_slice(&self, contents: &[u8]) -> Result<(), FlashromError> {
+        let ret = unsafe {
+            ffi::flashrom_image_verify(self.flashctx, contents.as_ptr() as _, contents.len())
+        };
+        check(ret, "flashrom_image_verify")
+    }
+
+    fn erase(&self) -> Result<(), FlashromError> {
+        check(
+            unsafe { ffi::flashrom_flash_erase(self.flashctx) },
+            "flashrom_flash_erase",
+        )
+    }
+
+    fn programmer(&self) -> &Programmer {
+        &self.programmer
+    }
+
+    fn supported_chips(&self) -> Result<ChipDatabase, FlashromError> {
+        let list = unsafe { ffi::flashrom_supported_flash_chips() };
+        if list.is_null() {
+            return Err("flashrom_supported_flash_chips failed".into());
+        }
+
+        // The list ends with a chip without a name.
+        let mut chips = Vec::new();
+        for i in 0.. {
+            let info = unsafe { &*list.add(i) };
+            if info.name.is_null() {
+                break;
+            }
+            let vendor = unsafe { CStr::from_ptr(info.vendor) }.to_string_lossy();
+            let name = unsafe { CStr::from_ptr(info.name) }.to_string_lossy();
+            if is_generic(&vendor, &name) {
+                continue;
+            }
+            chips.push(SupportedChip {
+                vendor: vendor.into_owned(),
+                name: name.into_owned(),
+                size: info.total_size as i64 * 1024,
+                // libflashrom doesn't tell the buses.
+                buses: Vec::new(),
+                tested: Tested {
+                    probe: test_status(info.tested.probe),
+                    read: test_status(info.tested.read),
+                    erase: test_status(info.tested.erase),
+                    write: test_status(info.tested.write),
+                    wp: None,
+                },
+                id: None,
+            });
+        }
+        unsafe { ffi::flashrom_data_free(list as _) };
+        Ok(ChipDatabase::new(chips))
+    }
+
+    fn set_progress(&mut self, callback: ProgressCallback) {
+        let set = callback.is_set();
+        self.progress.callback = callback;
+        if set {
+            PROGRESS.store(&mut *self.progress, Ordering::Release);
+            unsafe {
+                ffi::flashrom_set_progress_callback(
+                    self.flashctx,
+                    Some(progress_callback),
+                    &mut self.progress.ffi,
+                )
+            };
+        } else {
+            unsafe { ffi::flashrom_set_progress_callback(self.flashctx, None, ptr::null_mut()) };
+            PROGRESS.store(ptr::null_mut(), Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn wp_modes() {
+        assert_eq!(wp_mode(ffi_wp_mode(false)).unwrap(), WpMode::Disabled);
+        assert_eq!(wp_mode(ffi_wp_mode(true)).unwrap(), WpMode::Hardware);
+        assert_eq!(
+            wp_mode(ffi::FLASHROM_WP_MODE_POWER_CYCLE).unwrap(),
+            WpMode::PowerCycle
+        );
+        assert_eq!(
+            wp_mode(ffi::FLASHROM_WP_MODE_PERMANENT).unwrap(),
+            WpMode::Permanent
+        );
+        assert!(wp_mode(4).is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(ffi_range((0x1000, 0x2000)).unwrap(), (0x1000, 0x2000));
+        assert_eq!(ffi_range((0, 0)).unwrap(), (0, 0));
+        assert!(ffi_range((-1, 0x1000)).is_err());
+        assert!(ffi_range((0, -0x1000)).is_err());
+    }
+
+    #[test]
+    fn progress() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let mut state = ProgressState {
+            ffi: ffi::flashrom_progress {
+                stage: ffi::FLASHROM_PROGRESS_WRITE,
+                current: 0x100,
+                total: 0x1000,
+                user_data: ptr::null_mut(),
+            },
+            callback: ProgressCallback::new(move |p| sink.lock().unwrap().push(p)),
+        };
+        report_progress(&state);
+        // Stages added to libflashrom later are not reported.
+        state.ffi.stage = 3;
+        report_progress(&state);
+
+        assert_eq!(
+            *reports.lock().unwrap(),
+            vec![Progress {
+                stage: ProgressStage::Write,
+                current: 0x100,
+                total: 0x1000,
+            }]
+        );
+    }
+
+    #[test]
+    fn log_lines() {
+        capture_log(true);
+        log_message(ffi::FLASHROM_MSG_WARN + 1, "Found Winbond flash chip ");
+        log_message(
+            ffi::FLASHROM_MSG_WARN + 1,
+            "\"W25Q128.V\" (16384 kB, SPI) on dummy.\nProbing",
+        );
+        let log = capture_log(false).unwrap();
+
+        assert_eq!(
+            log,
+            "Found Winbond flash chip \"W25Q128.V\" (16384 kB, SPI) on dummy.\n"
+        );
+        let chips = parse_probe(&log);
+        assert_eq!(chips.len(), 1);
+        assert_eq!(
+            (chips[0].vendor.as_str(), chips[0].name.as_str()),
+            ("Winbond", "W25Q128.V")
+        );
+
+        // The rest of the line is kept for the next message.
+        log_message(ffi::FLASHROM_MSG_WARN + 1, " for chips\n");
+        assert!(capture_log(false).is_none());
+    }
+}
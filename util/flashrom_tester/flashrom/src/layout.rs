@@ -0,0 +1,357 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use crate::{FlashromError, LayoutSource, Region};
+
+/// Size of the FMAP header: signature, version, base, size, name and nareas.
+const FMAP_HEADER_LEN: usize = 8 + 2 + 8 + 4 + FMAP_STRLEN + 2;
+/// Size of an FMAP area: offset, size, name and flags.
+const FMAP_AREA_LEN: usize = 4 + 4 + FMAP_STRLEN + 2;
+const FMAP_STRLEN: usize = 32;
+const FMAP_SIGNATURE: &[u8] = b"__FMAP__";
+const FMAP_VER_MAJOR: u8 = 1;
+
+const IFD_SIGNATURE: u32 = 0x0ff0_a55a;
+/// Size of the descriptor region at the bottom of the flash.
+const IFD_LEN: usize = 0x1000;
+/// Region names as used by flashrom, indexed by FLREG number.
+const IFD_REGIONS: [&str; 16] = [
+    "fd", "bios", "me", "gbe", "pd", "reg5", "bios2", "reg7", "ec", "reg9", "ie", "10gbe", "reg12",
+    "reg13", "reg14", "reg15",
+];
+
+/// List the regions described by `source`, calling `read_flash` to get the
+/// flash contents if the layout is stored there.
+pub(crate) fn read_regions<F, T>(
+    source: LayoutSource,
+    read_flash: F,
+) -> Result<Vec<Region>, FlashromError>
+where
+    F: FnOnce() -> Result<T, FlashromError>,
+    T: AsRef<[u8]>,
+{
+    match source {
+        LayoutSource::File(path) => {
+            let contents = std::fs::read_to_string(path).map_err(|e| {
+                FlashromError::Io(std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to read layout file {}: {}", path.display(), e),
+                ))
+            })?;
+            Ok(parse_layout_file(&contents)?
+                .into_iter()
+                .map(|(start, end, name)| Region {
+                    name: name.into(),
+                    start: start as i64,
+                    len: (end - start + 1) as i64,
+                })
+                .collect())
+        }
+        LayoutSource::Fmap => parse_fmap(read_flash()?.as_ref()),
+        LayoutSource::FmapFile(path) => parse_fmap(&std::fs::read(path)?),
+        LayoutSource::Ifd => parse_ifd(read_flash()?.as_ref()),
+    }
+}
+
+/// Parse a flashrom layout file, where each line is `start:end name` with
+/// inclusive hexadecimal addresses.
+pub(crate) fn parse_layout_file(
+    contents: &str,
+) -> Result<Vec<(usize, usize, &str)>, FlashromError> {
+    let mut out = Vec::new();
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        let mut split = line.split_whitespace();
+        let (range, name) = match (split.next(), split.next()) {
+            (Some(range), Some(name)) => (range, name),
+            _ => {
+                return Err(FlashromError::Parse(format!(
+                    "Malformed layout line: {:?}",
+                    line
+                )))
+            }
+        };
+        let parse = |s: &str| {
+            usize::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|e| {
+                FlashromError::Parse(format!("Bad address in layout line {:?}: {}", line, e))
+            })
+        };
+        let (start, end) = match range.split_once(':') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => {
+                return Err(FlashromError::Parse(format!(
+                    "Malformed layout line: {:?}",
+                    line
+                )))
+            }
+        };
+        out.push((start, end, name));
+    }
+    Ok(out)
+}
+
+/// Find the first valid FMAP in `image` and return its areas.
+///
+/// Like flashrom, candidate signatures are checked for a supported version,
+/// a size that can hold the areas and a NUL-terminated name, since the
+/// signature alone also turns up in code referring to it.
+pub(crate) fn parse_fmap(image: &[u8]) -> Result<Vec<Region>, FlashromError> {
+    let mut offset = 0;
+    while let Some(found) = find(&image[offset..], FMAP_SIGNATURE) {
+        let fmap = &image[offset + found..];
+        if let Some(regions) = parse_fmap_at(fmap) {
+            return Ok(regions);
+        }
+        offset += found + 1;
+    }
+    Err(FlashromError::Parse("No valid FMAP found".into()))
+}
+
+fn parse_fmap_at(fmap: &[u8]) -> Option<Vec<Region>> {
+    let header = fmap.get(..FMAP_HEADER_LEN)?;
+    let (ver_major, size) = (header[8], le_u32(&header[18..22]));
+    let nareas = u16::from_le_bytes([header[54], header[55]]) as usize;
+    let fmap_len = FMAP_HEADER_LEN + nareas * FMAP_AREA_LEN;
+    if ver_major != FMAP_VER_MAJOR || (size as usize) < fmap_len {
+        return None;
+    }
+    fmap_name(&header[22..22 + FMAP_STRLEN])?;
+
+    fmap.get(FMAP_HEADER_LEN..fmap_len)?
+        .chunks(FMAP_AREA_LEN)
+        .map(|area| {
+            Some(Region {
+                name: fmap_name(&area[8..8 + FMAP_STRLEN])?,
+                start: le_u32(&area[0..4]) as i64,
+                len: le_u32(&area[4..8]) as i64,
+            })
+        })
+        .collect()
+}
+
+/// Read a NUL-terminated FMAP name, which must be a single printable word.
+fn fmap_name(field: &[u8]) -> Option<String> {
+    let len = field.iter().position(|&c| c == 0)?;
+    let name = &field[..len];
+    if name.iter().all(u8::is_ascii_graphic) {
+        String::from_utf8(name.to_vec()).ok()
+    } else {
+        None
+    }
+}
+
+/// Parse the regions of the Intel flash descriptor at the start of `image`.
+///
+/// How many regions a descriptor defines depends on the chipset, which is
+/// not recorded in the descriptor itself. All 16 possible regions are
+/// checked instead, skipping those flashrom also treats as unused (a limit
+/// below the base) and those reaching past the end of `image`.
+pub(crate) fn parse_ifd(image: &[u8]) -> Result<Vec<Region>, FlashromError> {
+    let desc = &image[..std::cmp::min(image.len(), IFD_LEN)];
+    let dword = |offset: usize| desc.get(offset..offset + 4).map(le_u32);
+
+    // Some old chipsets put the signature at the very start of the flash.
+    let sig_offset = [0x10, 0]
+        .iter()
+        .copied()
+        .find(|&offset| dword(offset) == Some(IFD_SIGNATURE))
+        .ok_or_else(|| FlashromError::Parse("No Intel flash descriptor found".into()))?;
+    let flmap0 = dword(sig_offset + 4)
+        .ok_or_else(|| FlashromError::Parse("Truncated Intel flash descriptor".into()))?;
+    let frba = ((flmap0 >> 16) & 0xff) as usize * 0x10;
+
+    let mut out = Vec::new();
+    for (i, name) in IFD_REGIONS.iter().enumerate() {
+        let flreg = match dword(frba + i * 4) {
+            Some(flreg) => flreg,
+            None => break,
+        };
+        let base = (flreg << 12) & 0x07ff_f000;
+        let limit = ((flreg >> 4) & 0x07ff_f000) | 0xfff;
+        if limit <= base || limit as usize >= image.len() {
+            continue;
+        }
+        out.push(Region {
+            name: (*name).into(),
+            start: base as i64,
+            len: (limit - base + 1) as i64,
+        });
+    }
+    Ok(out)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FMAP_AREA_LEN, FMAP_HEADER_LEN, FMAP_STRLEN};
+    use crate::{LayoutSource, Region};
+
+    fn fmap_image(areas: &[(u32, u32, &str)]) -> Vec<u8> {
+        fn name(s: &str) -> Vec<u8> {
+            let mut out = s.as_bytes().to_vec();
+            out.resize(FMAP_STRLEN, 0);
+            out
+        }
+
+        let mut fmap = Vec::new();
+        fmap.extend_from_slice(b"__FMAP__");
+        fmap.extend_from_slice(&[1, 1]);
+        fmap.extend_from_slice(&0u64.to_le_bytes());
+        fmap.extend_from_slice(&0x10000u32.to_le_bytes());
+        fmap.extend(name("FLASH"));
+        fmap.extend_from_slice(&(areas.len() as u16).to_le_bytes());
+        for &(offset, size, area) in areas {
+            fmap.extend_from_slice(&offset.to_le_bytes());
+            fmap.extend_from_slice(&size.to_le_bytes());
+            fmap.extend(name(area));
+            fmap.extend_from_slice(&0u16.to_le_bytes());
+        }
+        assert_eq!(fmap.len(), FMAP_HEADER_LEN + areas.len() * FMAP_AREA_LEN);
+        fmap
+    }
+
+    #[test]
+    fn parse_fmap() {
+        use super::parse_fmap;
+
+        let mut image = vec![0xff; 0x1000];
+        // A reference to the signature that is not an FMAP.
+        image[0x10..0x1c].copy_from_slice(b"__FMAP__ ver");
+        let fmap = fmap_image(&[(0, 0x8000, "WP_RO"), (0x8000, 0x8000, "RW_SECTION_A")]);
+        image[0x200..0x200 + fmap.len()].copy_from_slice(&fmap);
+
+        assert_eq!(
+            parse_fmap(&image).ok(),
+            Some(vec![
+                Region {
+                    name: "WP_RO".into(),
+                    start: 0,
+                    len: 0x8000
+                },
+                Region {
+                    name: "RW_SECTION_A".into(),
+                    start: 0x8000,
+                    len: 0x8000
+                },
+            ])
+        );
+
+        assert_eq!(
+            LayoutSource::Fmap.regions_in(&image).ok(),
+            parse_fmap(&image).ok()
+        );
+
+        assert!(parse_fmap(&[0xff; 0x1000]).is_err());
+        // Truncated areas.
+        assert!(parse_fmap(&fmap[..fmap.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn parse_ifd() {
+        use super::parse_ifd;
+
+        let mut image = vec![0xff; 0x800000];
+        let mut put = |offset: usize, value: u32| {
+            image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        put(0x10, 0x0ff0_a55a);
+        // FLMAP0 with FRBA at 0x40.
+        put(0x14, 0x0004_0003);
+        put(0x40, 0x0000_0000); // fd: 0x0-0xfff
+        put(0x44, 0x07ff_0200); // bios: 0x200000-0x7fffff
+        put(0x48, 0x01ff_0003); // me: 0x3000-0x1fffff
+        put(0x4c, 0x0002_0001); // gbe: 0x1000-0x2fff
+        put(0x50, 0x0000_7fff); // pd: unused
+        put(0x54, 0x0fff_0800); // reg5: past the end of the image
+
+        assert_eq!(
+            parse_ifd(&image).ok(),
+            Some(vec![
+                Region {
+                    name: "fd".into(),
+                    start: 0,
+                    len: 0x1000
+                },
+                Region {
+                    name: "bios".into(),
+                    start: 0x200000,
+                    len: 0x600000
+                },
+                Region {
+                    name: "me".into(),
+                    start: 0x3000,
+                    len: 0x1fd000
+                },
+                Region {
+                    name: "gbe".into(),
+                    start: 0x1000,
+                    len: 0x2000
+                },
+            ])
+        );
+
+        assert!(parse_ifd(&[0xff; 0x1000]).is_err());
+    }
+
+    #[test]
+    fn parse_layout_file() {
+        use super::parse_layout_file;
+
+        assert_eq!(
+            parse_layout_file(
+                "000000:3fff BOTTOM_QUAD\n\
+                 000000:7fff BOTTOM_HALF\n\
+                 \n\
+                 0x8000:0xffff TOP_HALF\n"
+            )
+            .ok(),
+            Some(vec![
+                (0, 0x3fff, "BOTTOM_QUAD"),
+                (0, 0x7fff, "BOTTOM_HALF"),
+                (0x8000, 0xffff, "TOP_HALF"),
+            ])
+        );
+
+        assert!(parse_layout_file("000000-3fff BOTTOM_QUAD").is_err());
+        assert!(parse_layout_file("000000:3fff").is_err());
+        assert!(parse_layout_file("000000:xyz BOTTOM_QUAD").is_err());
+    }
+}
@@ -36,79 +36,161 @@
 #[macro_use]
 extern crate log;
 
+mod chips;
 mod cmd;
+mod dryrun;
+mod error;
+#[cfg(feature = "libflashrom")]
+mod flashromlib;
+mod layout;
+#[cfg(feature = "mock")]
+mod mock;
+mod programmer;
+mod progress;
+mod temp;
+mod version;
 
-use std::{error, fmt};
+pub use chips::{ChipDatabase, SupportedChip, TestStatus, Tested};
+pub use cmd::{
+    dut_ctrl_toggle_wp, shell_toggle_wp, FlashromCmd, FlashromOpt, FlashromOptBuilder, Timeouts,
+};
+pub use dryrun::{dry_run, dry_run_plan, dry_run_step, DryRun};
+pub use error::{ErrorKind, FlashromError};
+#[cfg(feature = "libflashrom")]
+pub use flashromlib::FlashromLib;
+#[cfg(feature = "mock")]
+pub use mock::{Fault, MockFlashrom, MockOp};
+pub use programmer::{Programmer, ProgrammerCaps};
+pub use progress::{Progress, ProgressCallback, ProgressStage};
+pub use temp::TempFile;
+pub use version::{Capabilities, FlashromVersion};
 
-pub use cmd::{dut_ctrl_toggle_wp, FlashromCmd};
+use std::ffi::OsString;
+use std::path::Path;
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-pub enum FlashChip {
-    EC,
-    HOST,
-    SERVO,
-    DEDIPROG,
+/// Write protect mode of a flash chip, as in libflashrom's `enum flashrom_wp_mode`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WpMode {
+    Disabled,
+    /// The status register is protected while the hardware WP signal is asserted.
+    Hardware,
+    /// The status register is protected until the next power cycle.
+    PowerCycle,
+    /// The status register can never be changed again.
+    Permanent,
 }
 
-impl FlashChip {
-    pub fn from(s: &str) -> Result<FlashChip, &str> {
-        let r = match s {
-            "ec" => Ok(FlashChip::EC),
-            "host" => Ok(FlashChip::HOST),
-            "servo" => Ok(FlashChip::SERVO),
-            "dediprog" => Ok(FlashChip::DEDIPROG),
-            _ => Err("cannot convert str to enum"),
-        };
-        return r;
-    }
-    pub fn to(fc: FlashChip) -> &'static str {
-        let r = match fc {
-            FlashChip::EC => "ec",
-            FlashChip::HOST => "host",
-            FlashChip::SERVO => "ft2231_spi:type=servo-v2",
-            FlashChip::DEDIPROG => "dediprog",
-        };
-        return r;
-    }
-
-    /// Return whether the hardware write protect signal can be controlled.
-    ///
-    /// Servo and dediprog adapters are assumed to always have hardware write protect
-    /// disabled.
-    pub fn can_control_hw_wp(&self) -> bool {
-        match self {
-            FlashChip::HOST | FlashChip::EC => true,
-            FlashChip::SERVO | FlashChip::DEDIPROG => false,
-        }
+/// Write protect configuration of a flash chip.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WpStatus {
+    pub mode: WpMode,
+    /// Protected range as (start, len).
+    pub range: (i64, i64),
+}
+
+impl WpStatus {
+    /// Return true if write protection is enabled in any mode.
+    pub fn enabled(&self) -> bool {
+        self.mode != WpMode::Disabled
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct FlashromError {
-    msg: String,
+/// A protection range supported by a flash chip.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WpRange {
+    pub start: i64,
+    pub len: i64,
+    /// The mode this range applies to, if flashrom reports one.
+    pub mode: Option<WpMode>,
 }
 
-impl fmt::Display for FlashromError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.msg)
+/// A flash chip definition which matches the flash, as found by probing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChipInfo {
+    pub vendor: String,
+    pub name: String,
+    /// Size of the chip in bytes.
+    pub size: i64,
+    /// JEDEC manufacturer and model ID, if flashrom reported it.
+    pub id: Option<(u32, u32)>,
+}
+
+/// Where flashrom finds the layout naming the regions of the flash.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LayoutSource<'a> {
+    /// A flashrom layout file (`-l <file>`).
+    File(&'a Path),
+    /// The FMAP stored in the flash (`--fmap`).
+    Fmap,
+    /// The FMAP stored in an image file (`--fmap-file <file>`).
+    FmapFile(&'a Path),
+    /// The Intel flash descriptor stored in the flash (`--ifd`).
+    Ifd,
+}
+
+impl LayoutSource<'_> {
+    /// List the regions of this layout for a flash holding `image`, finding
+    /// an FMAP or IFD in `image` rather than reading the flash.
+    pub fn regions_in(self, image: &[u8]) -> Result<Vec<Region>, FlashromError> {
+        layout::read_regions(self, || Ok(image))
     }
 }
 
-impl error::Error for FlashromError {}
+/// A named region of the flash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub start: i64,
+    pub len: i64,
+}
+
+/// A region of a layout, with the file holding its contents if it has its
+/// own (`-i <region>[:<file>]`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegionFile<'a> {
+    pub name: &'a str,
+    pub file: Option<&'a Path>,
+}
 
-impl<T> From<T> for FlashromError
-where
-    T: Into<String>,
-{
-    fn from(msg: T) -> Self {
-        FlashromError { msg: msg.into() }
+impl<'a> RegionFile<'a> {
+    pub fn new(name: &'a str) -> Self {
+        RegionFile { name, file: None }
+    }
+
+    pub fn with_file<P: AsRef<Path> + ?Sized>(name: &'a str, file: &'a P) -> Self {
+        RegionFile {
+            name,
+            file: Some(file.as_ref()),
+        }
+    }
+
+    /// Format the region as an argument to flashrom's `-i`, keeping the file
+    /// name as it is rather than converting it to a string.
+    pub fn to_arg(&self) -> OsString {
+        let mut arg = OsString::from(self.name);
+        if let Some(file) = self.file {
+            arg.push(":");
+            arg.push(file);
+        }
+        arg
+    }
+}
+
+impl std::fmt::Display for RegionFile<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.file {
+            None => write!(f, "{}", self.name),
+            Some(file) => write!(f, "{}:{}", self.name, file.display()),
+        }
     }
 }
 
 pub struct ROMWriteSpecifics<'a> {
-    pub layout_file: Option<&'a str>,
-    pub write_file: Option<&'a str>,
-    pub name_file: Option<&'a str>,
+    pub layout: Option<LayoutSource<'a>>,
+    /// The contents of the whole flash, which regions without their own file
+    /// are written from.
+    pub write_file: Option<&'a Path>,
+    pub regions: &'a [RegionFile<'a>],
 }
 
 pub trait Flashrom {
@@ -118,33 +200,150 @@ pub trait Flashrom {
     /// Returns the vendor name and the flash name.
     fn name(&self) -> Result<(String, String), FlashromError>;
 
-    /// Write only a region of the flash.
+    /// Probe for the flash, returning every chip definition which matches it.
+    ///
+    /// flashrom refuses to operate on a flash matched by several definitions
+    /// until one is chosen, as with `FlashromCmd::chip`. By default only the
+    /// chip in use is returned.
+    fn probe(&self) -> Result<Vec<ChipInfo>, FlashromError> {
+        let (vendor, name) = self.name()?;
+        Ok(vec![ChipInfo {
+            vendor,
+            name,
+            size: self.get_size()?,
+            id: None,
+        }])
+    }
+
+    /// Write only the regions of the flash in `rws`, all in one operation.
     fn write_file_with_layout(&self, rws: &ROMWriteSpecifics) -> Result<bool, FlashromError>;
 
+    /// List the regions of the layout from `source`, such as the FMAP areas
+    /// of the firmware or the descriptor regions of the flash.
+    fn layout_regions(&self, source: LayoutSource) -> Result<Vec<Region>, FlashromError> {
+        layout::read_regions(source, || self.read_to_vec())
+    }
+
     /// Set write protect status for a range.
     fn wp_range(&self, range: (i64, i64), wp_enable: bool) -> Result<bool, FlashromError>;
 
-    /// Read the write protect regions for the flash.
-    fn wp_list(&self) -> Result<String, FlashromError>;
+    /// Set write protect status for the range of the region `name` of the
+    /// layout from `layout`.
+    fn wp_region(
+        &self,
+        name: &str,
+        layout: LayoutSource,
+        wp_enable: bool,
+    ) -> Result<bool, FlashromError> {
+        let region = self
+            .layout_regions(layout)?
+            .into_iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| format!("Region {} is not in the layout", name))?;
+        self.wp_range((region.start, region.len), wp_enable)
+    }
+
+    /// List the ranges the flash can write protect.
+    ///
+    /// Fails with an error of kind `ErrorKind::Unsupported` if the ranges
+    /// cannot be listed for this flash.
+    fn wp_list(&self) -> Result<Vec<WpRange>, FlashromError>;
+
+    /// Read the write protect mode and range of the flash.
+    fn get_wp_status(&self) -> Result<WpStatus, FlashromError>;
 
     /// Return true if the flash write protect status matches `en`.
-    fn wp_status(&self, en: bool) -> Result<bool, FlashromError>;
+    fn wp_status(&self, en: bool) -> Result<bool, FlashromError> {
+        let status = self.get_wp_status()?;
+        info!(
+            "See if chip write protect is {}abled",
+            if en { "en" } else { "dis" }
+        );
+        Ok(status.enabled() == en)
+    }
 
     /// Set write protect status.
     fn wp_toggle(&self, en: bool) -> Result<bool, FlashromError>;
 
+    /// Read the `regions` of the layout from `layout` to the file specified
+    /// by `path`.
+    ///
+    /// The file is as large as the flash, but only the contents of `regions`
+    /// are read into it.
+    fn read_region(
+        &self,
+        layout: LayoutSource,
+        regions: &[&str],
+        path: &Path,
+    ) -> Result<(), FlashromError>;
+
+    /// Erase the `regions` of the layout from `layout`.
+    fn erase_region(&self, layout: LayoutSource, regions: &[&str]) -> Result<(), FlashromError>;
+
     /// Read the whole flash to the file specified by `path`.
-    fn read(&self, path: &str) -> Result<(), FlashromError>;
+    fn read(&self, path: &Path) -> Result<(), FlashromError>;
 
     /// Write the whole flash to the file specified by `path`.
-    fn write(&self, path: &str) -> Result<(), FlashromError>;
+    fn write(&self, path: &Path) -> Result<(), FlashromError>;
 
     /// Verify the whole flash against the file specified by `path`.
-    fn verify(&self, path: &str) -> Result<(), FlashromError>;
+    fn verify(&self, path: &Path) -> Result<(), FlashromError>;
+
+    /// Read the whole flash into memory.
+    fn read_to_vec(&self) -> Result<Vec<u8>, FlashromError> {
+        let file = TempFile::new("read")?;
+        self.read(file.path())?;
+        Ok(file.read()?)
+    }
+
+    /// Write `contents` to the whole flash.
+    fn write_from_slice(&self, contents: &[u8]) -> Result<(), FlashromError> {
+        let file = TempFile::with_contents("write", contents)?;
+        self.write(file.path())
+    }
+
+    /// Verify the whole flash against `contents`.
+    fn verify_slice(&self, contents: &[u8]) -> Result<(), FlashromError> {
+        let file = TempFile::with_contents("verify", contents)?;
+        self.verify(file.path())
+    }
 
     /// Erase the whole flash.
     fn erase(&self) -> Result<(), FlashromError>;
 
-    /// Return true if the hardware write protect of this flash can be controlled.
-    fn can_control_hw_wp(&self) -> bool;
+    /// Return the programmer used to access the flash.
+    fn programmer(&self) -> &Programmer;
+
+    /// Return the hardware write protect signal emulated for the flash, or
+    /// `None` if the signal is real or cannot be changed.
+    fn emulated_hw_wp(&self) -> Option<bool> {
+        None
+    }
+
+    /// Assert or deassert the emulated hardware write protect, taking effect
+    /// from the next operation.
+    fn set_emulated_hw_wp(&self, _en: bool) -> Result<(), FlashromError> {
+        Err(FlashromError::Unsupported(
+            "the hardware write protect is not emulated".into(),
+        ))
+    }
+
+    /// Return the features of flashrom which are available.
+    ///
+    /// Operations needing a missing feature fail with an error of kind
+    /// `ErrorKind::Unsupported`. Everything is available by default.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::ALL
+    }
+
+    /// Return the flash chips flashrom supports, and how well tested each
+    /// operation on them is. This doesn't access the flash.
+    fn supported_chips(&self) -> Result<ChipDatabase, FlashromError> {
+        Err(FlashromError::Unsupported(
+            "listing supported chips is not supported".into(),
+        ))
+    }
+
+    /// Report the progress of later reads, writes and erases to `callback`.
+    fn set_progress(&mut self, callback: ProgressCallback);
 }
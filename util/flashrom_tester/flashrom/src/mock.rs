@@ -0,0 +1,631 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use crate::{
+    layout, Capabilities, ChipDatabase, ErrorKind, Flashrom, FlashromError, LayoutSource,
+    Programmer, Progress, ProgressCallback, ProgressStage, ROMWriteSpecifics, RegionFile, WpMode,
+    WpRange, WpStatus,
+};
+
+use std::cmp::{max, min};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// The kinds of operation on a `MockFlashrom` that faults can be injected into.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MockOp {
+    /// Finding the name and size of the flash.
+    Probe,
+    Read,
+    Write,
+    Erase,
+    Verify,
+    /// Reading the write protect status or ranges.
+    WpRead,
+    /// Changing the write protect mode or range.
+    WpWrite,
+}
+
+/// A fault to inject into operations on a `MockFlashrom`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails with an error of the given kind, as flashrom
+    /// would report it.
+    Fail(ErrorKind),
+    /// The operation reports success without changing anything, like a
+    /// programmer which silently drops writes.
+    Ignore,
+}
+
+/// An emulated flash chip, which implements `Flashrom` in memory for testing
+/// without hardware.
+///
+/// Like flashrom with a real chip, write protection only refuses changes while
+/// it is enabled and the hardware write protect is asserted. The hardware
+/// write protect is emulated, and set with `set_emulated_hw_wp`.
+pub struct MockFlashrom {
+    vendor: String,
+    name: String,
+    programmer: Programmer,
+    ranges: Vec<WpRange>,
+    capabilities: Capabilities,
+    supported_chips: Option<ChipDatabase>,
+    progress: ProgressCallback,
+    state: Mutex<State>,
+}
+
+struct State {
+    contents: Vec<u8>,
+    wp: WpStatus,
+    hw_wp: bool,
+    faults: Vec<(MockOp, Fault)>,
+}
+
+impl MockFlashrom {
+    /// Emulate a flash holding `contents`, with write protection disabled.
+    ///
+    /// The chip can protect nothing, the whole flash and its top and bottom
+    /// halves and quarters. The hardware write protect is deasserted.
+    ///
+    /// The programmer is reported as the dummy programmer, with an image
+    /// naming this mock so that every mock is told apart as a separate flash.
+    pub fn new(contents: Vec<u8>) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let size = contents.len() as i64;
+        let ranges = [
+            (0, 0),
+            (0, size),
+            (0, size / 2),
+            (size / 2, size / 2),
+            (0, size / 4),
+            (size - size / 4, size / 4),
+        ]
+        .iter()
+        .map(|&(start, len)| WpRange {
+            start,
+            len,
+            mode: None,
+        })
+        .collect();
+
+        MockFlashrom {
+            vendor: "Mock".into(),
+            name: "MOCK".into(),
+            programmer: Programmer::new("dummy").with_param(
+                "image",
+                format!("mock{}", NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            ),
+            ranges,
+            capabilities: Capabilities::ALL,
+            supported_chips: None,
+            progress: ProgressCallback::default(),
+            state: Mutex::new(State {
+                contents,
+                wp: WpStatus {
+                    mode: WpMode::Disabled,
+                    range: (0, 0),
+                },
+                hw_wp: false,
+                faults: Vec::new(),
+            }),
+        }
+    }
+
+    /// Set the ranges the chip can write protect.
+    pub fn with_ranges(mut self, ranges: Vec<WpRange>) -> Self {
+        self.ranges = ranges;
+        self
+    }
+
+    /// Emulate a flashrom with only the features in `capabilities`, failing
+    /// write protect operations and FMAP layouts without them as it would.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Report `chips` as the chips flashrom supports. Listing them fails
+    /// otherwise.
+    pub fn with_supported_chips(mut self, chips: ChipDatabase) -> Self {
+        self.supported_chips = Some(chips);
+        self
+    }
+
+    /// Return the current contents of the flash.
+    pub fn contents(&self) -> Vec<u8> {
+        self.state().contents.clone()
+    }
+
+    /// Replace the contents of the flash, ignoring write protection.
+    pub fn set_contents(&self, contents: &[u8]) {
+        self.state().contents.copy_from_slice(contents)
+    }
+
+    /// Inject `fault` into every later operation of kind `op`, until
+    /// `clear_faults` is called.
+    pub fn inject(&self, op: MockOp, fault: Fault) {
+        self.state().faults.push((op, fault))
+    }
+
+    pub fn clear_faults(&self) {
+        self.state().faults.clear()
+    }
+
+    fn size(&self) -> i64 {
+        self.state().contents.len() as i64
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Start an operation of kind `op`, returning the state to operate on, or
+    /// None if the operation should be ignored.
+    fn start(&self, op: MockOp) -> Result<Option<MutexGuard<'_, State>>, FlashromError> {
+        if matches!(op, MockOp::WpRead | MockOp::WpWrite) {
+            self.require(Capabilities {
+                wp: true,
+                ..Capabilities::NONE
+            })?;
+        }
+        let state = self.state();
+        match state.faults.iter().find(|(o, _)| *o == op) {
+            None => Ok(Some(state)),
+            Some((_, Fault::Ignore)) => Ok(None),
+            Some((_, Fault::Fail(kind))) => Err(fault_error(op, *kind)),
+        }
+    }
+
+    /// Fail like flashrom if any of the `needed` capabilities are missing.
+    fn require(&self, needed: Capabilities) -> Result<(), FlashromError> {
+        match self.capabilities.missing(&needed).first() {
+            None => Ok(()),
+            Some(missing) => Err(FlashromError::Unsupported(format!(
+                "{} does not support {}",
+                self.capabilities, missing
+            ))),
+        }
+    }
+
+    fn report(&self, stage: ProgressStage, len: usize) {
+        if self.progress.is_set() {
+            for current in [0, len] {
+                self.progress.report(Progress {
+                    stage,
+                    current,
+                    total: len,
+                });
+            }
+        }
+    }
+
+    /// Find the (start, len) of `regions` of the layout from `source`.
+    fn find_regions<'r, I>(
+        &self,
+        source: LayoutSource,
+        regions: I,
+    ) -> Result<Vec<(i64, i64)>, FlashromError>
+    where
+        I: IntoIterator<Item = &'r str>,
+    {
+        self.require(Capabilities {
+            fmap: matches!(source, LayoutSource::Fmap | LayoutSource::FmapFile(_)),
+            ..Capabilities::NONE
+        })?;
+        let layout = layout::read_regions(source, || Ok(self.contents()))?;
+        regions
+            .into_iter()
+            .map(|name| {
+                layout
+                    .iter()
+                    .find(|r| r.name == name)
+                    .map(|r| (r.start, r.len))
+                    .ok_or_else(|| {
+                        mock_error(MockOp::Read, format!("Error: No region {} in layout", name))
+                    })
+            })
+            .collect()
+    }
+}
+
+impl State {
+    /// Check that the flash can be changed between `start` and `start + len`.
+    fn check_writable(&self, op: MockOp, (start, len): (i64, i64)) -> Result<(), FlashromError> {
+        let (wp_start, wp_len) = self.wp.range;
+        let overlaps = max(start, wp_start) < min(start + len, wp_start + wp_len);
+        if overlaps && self.wp_locked() {
+            return Err(mock_error(
+                op,
+                "At least part of the write range is write protected!".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return true if the write protect configuration cannot be changed.
+    fn wp_locked(&self) -> bool {
+        self.wp.enabled() && self.hw_wp
+    }
+
+    fn set_wp(&mut self, wp: WpStatus) -> Result<(), FlashromError> {
+        if self.wp_locked() {
+            return Err(mock_error(
+                MockOp::WpWrite,
+                "Hardware protection is active, cannot change the WP settings".into(),
+            ));
+        }
+        self.wp = wp;
+        Ok(())
+    }
+}
+
+/// Create an error like flashrom would return for a failed `op`.
+fn mock_error(op: MockOp, message: String) -> FlashromError {
+    FlashromError::Exit {
+        cmdline: format!("mock {:?}", op),
+        code: 1,
+        stdout: String::new(),
+        stderr: message,
+    }
+}
+
+/// Create an error of `kind` for an injected fault.
+fn fault_error(op: MockOp, kind: ErrorKind) -> FlashromError {
+    let cmdline = format!("mock {:?}", op);
+    let message = match kind {
+        ErrorKind::ProgrammerInit => "Error: Programmer initialization failed.",
+        ErrorKind::ChipNotFound => "No EEPROM/flash device found.",
+        ErrorKind::WriteProtected => "Hardware protection is active",
+        ErrorKind::Unsupported => "Error: write protect is not supported on this flash chip.",
+        ErrorKind::Timeout => {
+            return FlashromError::Timeout {
+                cmdline,
+                timeout: Duration::from_secs(0),
+                stdout: String::new(),
+                stderr: String::new(),
+            }
+        }
+        ErrorKind::Cancelled => {
+            return FlashromError::Cancelled {
+                cmdline,
+                stdout: String::new(),
+                stderr: String::new(),
+            }
+        }
+        ErrorKind::Other => "Injected fault",
+    };
+    mock_error(op, message.into())
+}
+
+/// Read an image of `len` bytes for `op` from `path`.
+fn read_image(op: MockOp, path: &Path, len: i64) -> Result<Vec<u8>, FlashromError> {
+    let image = std::fs::read(path)?;
+    if image.len() as i64 != len {
+        return Err(mock_error(
+            op,
+            format!(
+                "Error: Image size ({} B) doesn't match the expected size ({} B)!",
+                image.len(),
+                len
+            ),
+        ));
+    }
+    Ok(image)
+}
+
+impl Flashrom for MockFlashrom {
+    fn get_size(&self) -> Result<i64, FlashromError> {
+        self.start(MockOp::Probe)?;
+        Ok(self.size())
+    }
+
+    fn name(&self) -> Result<(String, String), FlashromError> {
+        self.start(MockOp::Probe)?;
+        Ok((self.vendor.clone(), self.name.clone()))
+    }
+
+    fn write_file_with_layout(&self, rws: &ROMWriteSpecifics) -> Result<bool, FlashromError> {
+        let size = self.size();
+        let targets: Vec<(RegionFile, (i64, i64))> = match rws.layout {
+            // Without regions, the whole flash is written.
+            _ if rws.regions.is_empty() => vec![(RegionFile::new("all"), (0, size))],
+            Some(source) => {
+                let ranges = self.find_regions(source, rws.regions.iter().map(|r| r.name))?;
+                rws.regions.iter().copied().zip(ranges).collect()
+            }
+            None => return Err(mock_error(MockOp::Write, "Error: No layout given".into())),
+        };
+        let whole = rws
+            .write_file
+            .map(|path| read_image(MockOp::Write, path, size))
+            .transpose()?;
+
+        let mut data = Vec::new();
+        for (region, (start, len)) in targets {
+            let image = match (region.file, &whole) {
+                (Some(path), _) => read_image(MockOp::Write, path, len)?,
+                (None, Some(whole)) => whole[start as usize..(start + len) as usize].to_vec(),
+                (None, None) => {
+                    return Err(mock_error(
+                        MockOp::Write,
+                        format!("Error: No file to write region {} from", region),
+                    ))
+                }
+            };
+            data.push((start, image));
+        }
+
+        if let Some(mut state) = self.start(MockOp::Write)? {
+            for &(start, ref image) in &data {
+                state.check_writable(MockOp::Write, (start, image.len() as i64))?;
+            }
+            for (start, image) in data {
+                let start = start as usize;
+                state.contents[start..start + image.len()].copy_from_slice(&image);
+            }
+        }
+        self.report(ProgressStage::Write, size as usize);
+        Ok(true)
+    }
+
+    fn wp_range(&self, range: (i64, i64), wp_enable: bool) -> Result<bool, FlashromError> {
+        if !self.ranges.iter().any(|r| (r.start, r.len) == range) {
+            return Err(mock_error(
+                MockOp::WpWrite,
+                format!("Invalid WP range {:#x?}", range),
+            ));
+        }
+        if let Some(mut state) = self.start(MockOp::WpWrite)? {
+            let mode = if wp_enable {
+                WpMode::Hardware
+            } else {
+                state.wp.mode
+            };
+            state.set_wp(WpStatus { mode, range })?;
+        }
+        Ok(true)
+    }
+
+    fn wp_region(
+        &self,
+        name: &str,
+        layout: LayoutSource,
+        wp_enable: bool,
+    ) -> Result<bool, FlashromError> {
+        self.require(Capabilities {
+            wp_region: true,
+            ..Capabilities::NONE
+        })?;
+        let range = self.find_regions(layout, [name])?[0];
+        self.wp_range(range, wp_enable)
+    }
+
+    fn wp_list(&self) -> Result<Vec<WpRange>, FlashromError> {
+        self.start(MockOp::WpRead)?;
+        Ok(self.ranges.clone())
+    }
+
+    fn get_wp_status(&self) -> Result<WpStatus, FlashromError> {
+        self.start(MockOp::WpRead)?;
+        Ok(self.state().wp)
+    }
+
+    fn wp_toggle(&self, en: bool) -> Result<bool, FlashromError> {
+        let size = self.size();
+        if let Some(mut state) = self.start(MockOp::WpWrite)? {
+            // Like flashrom, disabling leaves the range as it was.
+            let wp = if en {
+                WpStatus {
+                    mode: WpMode::Hardware,
+                    range: (0, size),
+                }
+            } else {
+                WpStatus {
+                    mode: WpMode::Disabled,
+                    range: state.wp.range,
+                }
+            };
+            state.set_wp(wp)?;
+        }
+        Ok(true)
+    }
+
+    fn read_region(
+        &self,
+        layout: LayoutSource,
+        regions: &[&str],
+        path: &Path,
+    ) -> Result<(), FlashromError> {
+        let ranges = self.find_regions(layout, regions.iter().copied())?;
+        let contents = match self.start(MockOp::Read)? {
+            Some(state) => state.contents.clone(),
+            None => return Ok(()),
+        };
+        let mut image = vec![0xff; contents.len()];
+        for (start, len) in ranges {
+            let range = start as usize..(start + len) as usize;
+            image[range.clone()].copy_from_slice(&contents[range]);
+        }
+        std::fs::write(path, image)?;
+        self.report(ProgressStage::Read, contents.len());
+        Ok(())
+    }
+
+    fn erase_region(&self, layout: LayoutSource, regions: &[&str]) -> Result<(), FlashromError> {
+        let ranges = self.find_regions(layout, regions.iter().copied())?;
+        if let Some(mut state) = self.start(MockOp::Erase)? {
+            for &range in &ranges {
+                state.check_writable(MockOp::Erase, range)?;
+            }
+            for (start, len) in ranges {
+                state.contents[start as usize..(start + len) as usize].fill(0xff);
+            }
+        }
+        self.report(ProgressStage::Erase, self.size() as usize);
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> Result<(), FlashromError> {
+        let contents = match self.start(MockOp::Read)? {
+            Some(state) => state.contents.clone(),
+            None => return Ok(()),
+        };
+        std::fs::write(path, &contents)?;
+        self.report(ProgressStage::Read, contents.len());
+        Ok(())
+    }
+
+    fn write(&self, path: &Path) -> Result<(), FlashromError> {
+        let image = read_image(MockOp::Write, path, self.size())?;
+        if let Some(mut state) = self.start(MockOp::Write)? {
+            state.check_writable(MockOp::Write, (0, image.len() as i64))?;
+            state.contents = image;
+        }
+        self.report(ProgressStage::Write, self.size() as usize);
+        Ok(())
+    }
+
+    fn verify(&self, path: &Path) -> Result<(), FlashromError> {
+        let image = read_image(MockOp::Verify, path, self.size())?;
+        if let Some(state) = self.start(MockOp::Verify)? {
+            if let Some(offset) = state.contents.iter().zip(&image).position(|(a, b)| a != b) {
+                return Err(mock_error(
+                    MockOp::Verify,
+                    format!("Verifying flash... FAILED at {:#010x}!", offset),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn erase(&self) -> Result<(), FlashromError> {
+        let size = self.size();
+        if let Some(mut state) = self.start(MockOp::Erase)? {
+            state.check_writable(MockOp::Erase, (0, size))?;
+            state.contents.fill(0xff);
+        }
+        self.report(ProgressStage::Erase, size as usize);
+        Ok(())
+    }
+
+    fn programmer(&self) -> &Programmer {
+        &self.programmer
+    }
+
+    fn emulated_hw_wp(&self) -> Option<bool> {
+        Some(self.state().hw_wp)
+    }
+
+    fn set_emulated_hw_wp(&self, en: bool) -> Result<(), FlashromError> {
+        self.state().hw_wp = en;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn supported_chips(&self) -> Result<ChipDatabase, FlashromError> {
+        self.supported_chips.clone().ok_or_else(|| {
+            FlashromError::Unsupported("the mock has no list of supported chips".into())
+        })
+    }
+
+    fn set_progress(&mut self, callback: ProgressCallback) {
+        self.progress = callback;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, MockFlashrom, MockOp};
+    use crate::{ErrorKind, Flashrom, WpMode, WpStatus};
+
+    #[test]
+    fn write_protect() {
+        let mock = MockFlashrom::new(vec![0; 0x1000]);
+
+        // Protection only refuses changes with hardware write protect asserted.
+        mock.wp_range((0, 0x800), true).unwrap();
+        mock.erase().unwrap();
+        assert_eq!(mock.contents(), [0xff; 0x1000]);
+
+        mock.set_contents(&[0; 0x1000]);
+        mock.set_emulated_hw_wp(true).unwrap();
+        assert_eq!(mock.erase().unwrap_err().kind(), ErrorKind::WriteProtected);
+        assert_eq!(
+            mock.wp_toggle(false).unwrap_err().kind(),
+            ErrorKind::WriteProtected
+        );
+        assert_eq!(mock.contents(), [0; 0x1000]);
+
+        // Writes touching the protected range are refused.
+        mock.write_from_slice(&[1; 0x1000]).unwrap_err();
+        mock.set_emulated_hw_wp(false).unwrap();
+        mock.wp_toggle(false).unwrap();
+        assert_eq!(
+            mock.get_wp_status().unwrap(),
+            WpStatus {
+                mode: WpMode::Disabled,
+                range: (0, 0x800),
+            }
+        );
+        mock.write_from_slice(&[1; 0x1000]).unwrap();
+        mock.verify_slice(&[1; 0x1000]).unwrap();
+        mock.verify_slice(&[0; 0x1000]).unwrap_err();
+
+        // Unsupported ranges are rejected.
+        mock.wp_range((0x100, 0x100), true).unwrap_err();
+    }
+
+    #[test]
+    fn faults() {
+        let mock = MockFlashrom::new(vec![0; 0x1000]);
+
+        mock.inject(MockOp::Erase, Fault::Ignore);
+        mock.inject(MockOp::Probe, Fault::Fail(ErrorKind::ChipNotFound));
+        mock.erase().unwrap();
+        assert_eq!(mock.contents(), [0; 0x1000]);
+        assert_eq!(mock.name().unwrap_err().kind(), ErrorKind::ChipNotFound);
+
+        mock.clear_faults();
+        mock.erase().unwrap();
+        assert_eq!(mock.contents(), [0xff; 0x1000]);
+        mock.name().unwrap();
+    }
+}
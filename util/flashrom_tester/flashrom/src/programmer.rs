@@ -0,0 +1,232 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use crate::FlashromError;
+use std::fmt;
+use std::str::FromStr;
+
+/// A flashrom programmer driver and its parameters, as passed to `-p`.
+///
+/// Parses from and formats to flashrom's `name[:key=value[,key=value]...]`
+/// syntax, keeping parameters in the order they were given.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Programmer {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+}
+
+/// What the tester can do with the flash behind a programmer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProgrammerCaps {
+    /// The hardware write protect signal can be toggled.
+    pub hw_wp: bool,
+    /// Servo holds the hardware write protect, which must be released with
+    /// dut-control while testing.
+    pub dut_control: bool,
+    /// The flash holds the firmware of the machine running the tests.
+    pub host: bool,
+    /// The flash is emulated in software, including its hardware write protect.
+    pub emulated: bool,
+}
+
+impl Programmer {
+    pub fn new<S: Into<String>>(name: S) -> Programmer {
+        Programmer {
+            name: name.into(),
+            params: Vec::new(),
+        }
+    }
+
+    /// Return this programmer with `key` set to `value`, replacing any previous value.
+    pub fn with_param<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        let (key, value) = (key.into(), value.into());
+        match self.params.iter_mut().find(|(k, _)| *k == key) {
+            Some(param) => param.1 = value,
+            None => self.params.push((key, value)),
+        }
+        self
+    }
+
+    /// Return the value of the parameter `key`, if set.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Return the programmer known by the short name `alias`, if any.
+    ///
+    /// These are the targets flashrom_tester has historically accepted, and
+    /// `dummy` emulates a chip with hardware write protect support.
+    pub fn from_alias(alias: &str) -> Option<Programmer> {
+        Some(match alias {
+            "host" | "ec" | "dediprog" => Programmer::new(alias),
+            "servo" => Programmer::new("ft2231_spi").with_param("type", "servo-v2"),
+            "dummy" => Programmer::new("dummy").with_param("emulate", "W25Q128FV"),
+            _ => return None,
+        })
+    }
+
+    /// Return the capabilities of this programmer.
+    ///
+    /// Servo and dediprog adapters are assumed to always have hardware write protect
+    /// disabled, as is any programmer not known to the tester.
+    pub fn caps(&self) -> ProgrammerCaps {
+        let mut caps = ProgrammerCaps::default();
+        match self.name.as_str() {
+            "host" => {
+                caps.hw_wp = true;
+                caps.host = true;
+            }
+            "ec" => caps.hw_wp = true,
+            "ft2231_spi" => {
+                caps.dut_control = self.param("type").is_some_and(|t| t.starts_with("servo"))
+            }
+            "dummy" => {
+                caps.hw_wp = true;
+                caps.emulated = true;
+            }
+            _ => {}
+        }
+        caps
+    }
+}
+
+impl fmt::Display for Programmer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (i, (k, v)) in self.params.iter().enumerate() {
+            let sep = if i == 0 { ':' } else { ',' };
+            write!(f, "{}{}={}", sep, k, v)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Programmer {
+    type Err = FlashromError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // from man page:
+        //  ' -p, --programmer <name>[:parameter[,parameter[,parameter]]] '
+        let (name, params) = s.split_once(':').unwrap_or((s, ""));
+        if name.is_empty() || name.contains([',', '=']) {
+            return Err(format!("Invalid programmer name in {:?}", s).into());
+        }
+
+        let mut programmer = Programmer::new(name);
+        for param in params.split(',').filter(|p| !p.is_empty()) {
+            match param.split_once('=') {
+                Some((k, v)) if !k.is_empty() => {
+                    programmer.params.push((k.to_string(), v.to_string()))
+                }
+                _ => {
+                    return Err(format!("Programmer parameter {:?} is not key=value", param).into())
+                }
+            }
+        }
+        Ok(programmer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Programmer;
+
+    #[test]
+    fn parse() {
+        let p: Programmer = "linux_spi:dev=/dev/spidev0.0,spispeed=8000"
+            .parse()
+            .unwrap();
+        assert_eq!(p.name, "linux_spi");
+        assert_eq!(p.param("dev"), Some("/dev/spidev0.0"));
+        assert_eq!(p.param("spispeed"), Some("8000"));
+        assert_eq!(p.param("serial"), None);
+
+        assert_eq!(
+            "ch341a_spi".parse().ok(),
+            Some(Programmer::new("ch341a_spi"))
+        );
+        assert_eq!(
+            "ch341a_spi:".parse().ok(),
+            Some(Programmer::new("ch341a_spi"))
+        );
+        assert_eq!(
+            "dummy:image=".parse().ok(),
+            Some(Programmer::new("dummy").with_param("image", ""))
+        );
+
+        assert!("".parse::<Programmer>().is_err());
+        assert!(":dev=x".parse::<Programmer>().is_err());
+        assert!("linux_spi:dev".parse::<Programmer>().is_err());
+        assert!("linux_spi:=x".parse::<Programmer>().is_err());
+    }
+
+    #[test]
+    fn format() {
+        for s in &[
+            "ch341a_spi",
+            "linux_spi:dev=/dev/spidev0.0,spispeed=8000",
+            "raiden_debug_spi:target=AP,serial=123",
+        ] {
+            assert_eq!(&s.parse::<Programmer>().unwrap().to_string(), s);
+        }
+        assert_eq!(
+            Programmer::from_alias("servo").unwrap().to_string(),
+            "ft2231_spi:type=servo-v2"
+        );
+    }
+
+    #[test]
+    fn with_param() {
+        let p = Programmer::new("dummy")
+            .with_param("hwwp", "no")
+            .with_param("emulate", "W25Q128FV")
+            .with_param("hwwp", "yes");
+        assert_eq!(p.to_string(), "dummy:hwwp=yes,emulate=W25Q128FV");
+    }
+
+    #[test]
+    fn caps() {
+        let caps = |s: &str| Programmer::from_alias(s).unwrap().caps();
+        assert!(caps("host").hw_wp && caps("host").host);
+        assert!(caps("ec").hw_wp && !caps("ec").host);
+        assert!(caps("servo").dut_control && !caps("servo").hw_wp);
+        assert!(!caps("dediprog").hw_wp);
+        assert!(caps("dummy").hw_wp && caps("dummy").emulated);
+        assert_eq!(Programmer::new("ch341a_spi").caps(), Default::default());
+    }
+}
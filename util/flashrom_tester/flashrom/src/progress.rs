@@ -0,0 +1,191 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use crate::layout::find;
+
+use std::fmt;
+use std::sync::Arc;
+
+/// The kind of operation progress is reported for, as in libflashrom's
+/// `enum flashrom_progress_stage`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProgressStage {
+    Read,
+    Write,
+    Erase,
+}
+
+impl fmt::Display for ProgressStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ProgressStage::Read => "READ",
+            ProgressStage::Write => "WRITE",
+            ProgressStage::Erase => "ERASE",
+        })
+    }
+}
+
+/// Progress of a long operation on the flash.
+///
+/// `current` counts up to `total` in units chosen by the backend: bytes for
+/// libflashrom, but only percent for the flashrom binary.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub stage: ProgressStage,
+    pub current: usize,
+    pub total: usize,
+}
+
+/// A function to report progress to, if any.
+#[derive(Clone, Default)]
+pub struct ProgressCallback(Option<Arc<dyn Fn(Progress) + Send + Sync>>);
+
+impl ProgressCallback {
+    pub fn new<F: Fn(Progress) + Send + Sync + 'static>(f: F) -> Self {
+        ProgressCallback(Some(Arc::new(f)))
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn report(&self, progress: Progress) {
+        if let Some(f) = &self.0 {
+            f(progress)
+        }
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            None => f.write_str("ProgressCallback(None)"),
+            Some(_) => f.write_str("ProgressCallback(Some(..))"),
+        }
+    }
+}
+
+impl PartialEq for ProgressCallback {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (None, None) => true,
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Parses the messages printed by `flashrom --progress`, like
+/// `[WRITE] 45% complete... `, from output read in arbitrary pieces.
+#[derive(Default)]
+pub(crate) struct ProgressParser {
+    pending: Vec<u8>,
+}
+
+impl ProgressParser {
+    /// The most of an incomplete message to keep between calls to `feed`.
+    const MAX_PENDING: usize = 64;
+
+    /// Parse more output, passing each complete message to `report`.
+    pub(crate) fn feed<F: FnMut(Progress)>(&mut self, data: &[u8], mut report: F) {
+        const END: &[u8] = b"% complete...";
+
+        self.pending.extend_from_slice(data);
+        while let Some(end) = find(&self.pending, END) {
+            if let Some(progress) = parse_message(&self.pending[..end]) {
+                report(progress);
+            }
+            self.pending.drain(..end + END.len());
+        }
+        if self.pending.len() > Self::MAX_PENDING {
+            self.pending.drain(..self.pending.len() - Self::MAX_PENDING);
+        }
+    }
+}
+
+/// Parse the end of `text`, up to but not including `% complete...`.
+fn parse_message(text: &[u8]) -> Option<Progress> {
+    let text = std::str::from_utf8(&text[text.iter().rposition(|&b| b == b'[')?..]).ok()?;
+    let (stage, percent) = text.strip_prefix('[')?.split_once("] ")?;
+    let stage = match stage {
+        "READ" => ProgressStage::Read,
+        "WRITE" => ProgressStage::Write,
+        "ERASE" => ProgressStage::Erase,
+        _ => return None,
+    };
+    Some(Progress {
+        stage,
+        current: percent.parse().ok()?,
+        total: 100,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Progress, ProgressParser, ProgressStage};
+
+    #[test]
+    fn parse_progress() {
+        let output: &[u8] = b"Reading old flash chip contents... [READ] 0% complete... \
+            [READ] 50% complete... [READ] 100% complete... done.\n\
+            Erasing and writing flash chip... [ERASE] 3% complete... [WRITE] 3% complete... \
+            [UNKNOWN] 7% complete... Erase/write done.\n";
+        let expected = [
+            (ProgressStage::Read, 0),
+            (ProgressStage::Read, 50),
+            (ProgressStage::Read, 100),
+            (ProgressStage::Erase, 3),
+            (ProgressStage::Write, 3),
+        ];
+        let expected: Vec<Progress> = expected
+            .iter()
+            .map(|&(stage, current)| Progress {
+                stage,
+                current,
+                total: 100,
+            })
+            .collect();
+
+        // Messages must be found however the output is split up.
+        for size in &[1, 7, output.len()] {
+            let mut parser = ProgressParser::default();
+            let mut found = Vec::new();
+            for chunk in output.chunks(*size) {
+                parser.feed(chunk, |p| found.push(p));
+            }
+            assert_eq!(found, expected, "chunks of {} bytes", size);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use std::fmt;
+
+/// A version of the flashrom command line tool.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FlashromVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// Built from ChromeOS's fork of flashrom, which has its own dialect and
+    /// always reports itself as v0.9.9.
+    pub chromeos: bool,
+}
+
+impl FlashromVersion {
+    /// Parse the version from the output of `flashrom --version`, which
+    /// starts with a line like one of:
+    ///
+    /// ```text
+    /// flashrom 1.4.0 on Linux 6.1.0 (x86_64)
+    /// flashrom v1.2-1012-g2d2a3c2 on Linux 5.10.0 (x86_64)
+    /// flashrom v0.9.9  : 8c7a2e4 : Jun 10 2021 12:00:00 UTC on Linux 5.4.0 (x86_64)
+    /// ```
+    ///
+    /// Anything after the release number, such as `-rc1` or the commits since
+    /// the release, is ignored. Returns None if there is no release number, as
+    /// for a build from a checkout without tags.
+    pub fn parse(output: &str) -> Option<FlashromVersion> {
+        let line = output
+            .lines()
+            .map(str::trim)
+            .find(|l| l.starts_with("flashrom "))?;
+        let rest = line["flashrom ".len()..].trim_start();
+        let token = rest.split_whitespace().next()?;
+        let release = token.strip_prefix('v').unwrap_or(token);
+        let release = &release[..release
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(release.len())];
+
+        let mut numbers = release.split('.').map(|n| n.parse::<u32>().ok());
+        let major = numbers.next()??;
+        let minor = numbers.next()??;
+        let patch = numbers.next().unwrap_or(Some(0))?;
+        Some(FlashromVersion {
+            major,
+            minor,
+            patch,
+            // Only the fork follows the version with the commit and build time.
+            chromeos: rest[token.len()..].trim_start().starts_with(": "),
+        })
+    }
+
+    fn at_least(&self, major: u32, minor: u32) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
+impl fmt::Display for FlashromVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}.{}.{}", self.major, self.minor, self.patch)?;
+        if self.chromeos {
+            write!(f, " (ChromeOS)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The features of the flashrom command line which the tester depends on,
+/// which vary with the version of flashrom.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The version these were derived from, if it is known.
+    pub version: Option<FlashromVersion>,
+    /// The size is printed with `--flash-size` rather than `--get-size`.
+    pub flash_size: bool,
+    /// Layouts can be read with `--fmap` and `--fmap-file`.
+    pub fmap: bool,
+    /// Write protection can be read and changed with the `--wp-*` options.
+    pub wp: bool,
+    /// Write protect status and ranges are reported with their modes, rather
+    /// than only whether protection is enabled.
+    pub wp_modes: bool,
+    /// The range of a layout region can be protected with `--wp-region`.
+    pub wp_region: bool,
+    /// Progress of reads, writes and erases is reported with `--progress`.
+    pub progress: bool,
+}
+
+impl Capabilities {
+    /// Every capability, as of flashrom versions newer than the tester knows.
+    pub const ALL: Capabilities = Capabilities {
+        version: None,
+        flash_size: true,
+        fmap: true,
+        wp: true,
+        wp_modes: true,
+        wp_region: true,
+        progress: true,
+    };
+
+    /// No capabilities, for listing only those which are needed.
+    pub const NONE: Capabilities = Capabilities {
+        version: None,
+        flash_size: false,
+        fmap: false,
+        wp: false,
+        wp_modes: false,
+        wp_region: false,
+        progress: false,
+    };
+
+    /// Return the capabilities of `version` of flashrom.
+    ///
+    /// An unknown version is most likely a development build, so is assumed
+    /// to have every capability.
+    pub fn of_version(version: Option<FlashromVersion>) -> Capabilities {
+        let v = match version {
+            None => return Capabilities::ALL,
+            Some(v) => v,
+        };
+        if v.chromeos {
+            // Later builds of the fork accept --get-size as an alias of
+            // --flash-size, so it is the only way to work with all of them.
+            return Capabilities {
+                version,
+                fmap: true,
+                wp: true,
+                ..Capabilities::NONE
+            };
+        }
+        Capabilities {
+            version,
+            flash_size: true,
+            fmap: v.at_least(1, 1),
+            wp: v.at_least(1, 3),
+            wp_modes: v.at_least(1, 3),
+            wp_region: v.at_least(1, 4),
+            progress: v.at_least(1, 4),
+        }
+    }
+
+    /// Return descriptions of the capabilities set in `needed` which are
+    /// missing from these.
+    pub fn missing(&self, needed: &Capabilities) -> Vec<&'static str> {
+        [
+            (needed.flash_size && !self.flash_size, "--flash-size"),
+            (needed.fmap && !self.fmap, "--fmap"),
+            (needed.wp && !self.wp, "write protection"),
+            (needed.wp_modes && !self.wp_modes, "write protect modes"),
+            (needed.wp_region && !self.wp_region, "--wp-region"),
+            (needed.progress && !self.progress, "--progress"),
+        ]
+        .iter()
+        .filter(|(missing, _)| *missing)
+        .map(|(_, name)| *name)
+        .collect()
+    }
+}
+
+impl fmt::Display for Capabilities {
+    /// Describe the flashrom these capabilities belong to.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.version {
+            Some(v) => write!(f, "flashrom {}", v),
+            None => write!(f, "flashrom of unknown version"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, FlashromVersion};
+
+    #[test]
+    fn parse_version() {
+        let version = |major, minor, patch, chromeos| {
+            Some(FlashromVersion {
+                major,
+                minor,
+                patch,
+                chromeos,
+            })
+        };
+        assert_eq!(
+            FlashromVersion::parse("flashrom 1.4.0 on Linux 6.1.0 (x86_64)\nflashrom is free"),
+            version(1, 4, 0, false)
+        );
+        assert_eq!(
+            FlashromVersion::parse("flashrom v1.2-1012-g2d2a3c2 on Linux 5.10.0 (x86_64)"),
+            version(1, 2, 0, false)
+        );
+        assert_eq!(
+            FlashromVersion::parse("flashrom v1.3.0-rc1 on Linux 5.10.0 (x86_64)"),
+            version(1, 3, 0, false)
+        );
+        assert_eq!(
+            FlashromVersion::parse(
+                "flashrom v0.9.9  : 8c7a2e4 : Jun 10 2021 12:00:00 UTC on Linux 5.4.0 (x86_64)"
+            ),
+            version(0, 9, 9, true)
+        );
+        assert_eq!(
+            FlashromVersion::parse("flashrom  on Linux 6.1.0 (x86_64)"),
+            None
+        );
+        assert_eq!(FlashromVersion::parse("sh: flashrom: not found"), None);
+    }
+
+    #[test]
+    fn capabilities() {
+        let of = |s| Capabilities::of_version(FlashromVersion::parse(s));
+
+        assert_eq!(of("flashrom  on Linux"), Capabilities::ALL);
+        let v1_2 = of("flashrom v1.2 on Linux");
+        assert!(v1_2.flash_size && v1_2.fmap && !v1_2.wp && !v1_2.progress);
+        assert_eq!(
+            v1_2.missing(&Capabilities {
+                wp: true,
+                wp_region: true,
+                fmap: true,
+                ..Capabilities::NONE
+            }),
+            ["write protection", "--wp-region"]
+        );
+        assert_eq!(v1_2.to_string(), "flashrom v1.2.0");
+
+        let v1_3 = of("flashrom v1.3.0 on Linux");
+        assert!(v1_3.wp && v1_3.wp_modes && !v1_3.wp_region);
+        assert!(of("flashrom 1.4.0 on Linux").wp_region);
+
+        let chromeos = of("flashrom v0.9.9  : 8c7a2e4 : Jun 10 2021 on Linux");
+        assert!(chromeos.wp && !chromeos.wp_modes && !chromeos.flash_size);
+        assert!(chromeos
+            .missing(&Capabilities::ALL)
+            .contains(&"--flash-size"));
+    }
+}
@@ -0,0 +1,406 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+//! Ways of sensing and toggling the hardware write protect signal of the
+//! flash, which flashrom cannot do itself.
+
+use super::utils;
+use flashrom::{Flashrom, Programmer};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Senses and possibly sets the hardware write protect of the flash under
+/// test, which is accessed through `cmd`.
+pub trait HwWpController {
+    /// Describe how the signal is controlled, for logs.
+    fn name(&self) -> &str;
+
+    /// Return true if the hardware write protect is asserted.
+    fn get(&self, cmd: &dyn Flashrom) -> Result<bool, String>;
+
+    /// Return true if `set` can change the hardware write protect.
+    fn can_set(&self, cmd: &dyn Flashrom) -> bool;
+
+    /// Assert or deassert the hardware write protect.
+    fn set(&self, cmd: &dyn Flashrom, enable: bool) -> Result<(), String>;
+
+    /// Prepare the signal for a test to run.
+    fn begin_test(&self, _cmd: &dyn Flashrom) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Return the signal to how it is kept between tests.
+    fn end_test(&self, _cmd: &dyn Flashrom) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The names accepted by `by_name`.
+pub const NAMES: &[&str] = &[
+    "auto",
+    "manual",
+    "dut-control",
+    "crossystem",
+    "dummy",
+    "none",
+];
+
+/// Return the controller called `name`, one of `NAMES`, where `auto` picks
+/// one suiting `programmer`. Commands it runs are killed if `terminate`
+/// becomes set.
+pub fn by_name(
+    name: &str,
+    programmer: &Programmer,
+    terminate: Option<&'static AtomicBool>,
+) -> Option<Box<dyn HwWpController>> {
+    Some(match name {
+        "auto" => for_programmer(programmer, terminate),
+        "manual" => Box::new(Manual),
+        "dut-control" => Box::new(DutControl { terminate }),
+        "crossystem" => Box::new(Crossystem),
+        "dummy" => Box::new(Dummy),
+        "none" => Box::new(Unavailable),
+        _ => return None,
+    })
+}
+
+/// Return the controller suiting `programmer`.
+///
+/// The write protect of the dummy programmer is emulated. Servo holds it
+/// deasserted with dut-control while tests run. The write protect of the host
+/// and EC is toggled by hand, and other programmers are assumed to have it
+/// deasserted.
+///
+/// In a dry run the same controller is chosen, but only plans the steps it
+/// would take and changes the signal emulated for the simulated flash.
+pub fn for_programmer(
+    programmer: &Programmer,
+    terminate: Option<&'static AtomicBool>,
+) -> Box<dyn HwWpController> {
+    let caps = programmer.caps();
+    if caps.emulated {
+        Box::new(Dummy)
+    } else if caps.dut_control {
+        Box::new(DutControl { terminate })
+    } else if caps.hw_wp {
+        Box::new(Manual)
+    } else {
+        Box::new(Unavailable)
+    }
+}
+
+/// Ask someone to disconnect the battery or open the WP screw, checking the
+/// result with `crossystem`.
+pub struct Manual;
+
+impl HwWpController for Manual {
+    fn name(&self) -> &str {
+        "manual"
+    }
+
+    fn get(&self, cmd: &dyn Flashrom) -> Result<bool, String> {
+        if flashrom::dry_run() {
+            return Ok(dry_run_hw_wp(cmd));
+        }
+        utils::get_hardware_wp()
+    }
+
+    fn can_set(&self, _cmd: &dyn Flashrom) -> bool {
+        true
+    }
+
+    fn set(&self, cmd: &dyn Flashrom, enable: bool) -> Result<(), String> {
+        if flashrom::dry_run() {
+            let s = if enable { "" } else { "dis" };
+            flashrom::dry_run_step(format!(
+                "Prompt to {}connect the battery (and/or open the WP screw)",
+                s
+            ));
+            return set_dry_run_hw_wp(cmd, enable);
+        }
+        utils::toggle_hw_wp(/* dis= */ !enable)
+    }
+}
+
+/// Servo, which deasserts the write protect with `dut-control` while each
+/// test runs and releases it afterwards.
+///
+/// The signal is taken to be deasserted, and cannot be asserted.
+pub struct DutControl {
+    /// Setting this kills `dut-control` if it is running, like flashrom.
+    pub terminate: Option<&'static AtomicBool>,
+}
+
+impl HwWpController for DutControl {
+    fn name(&self) -> &str {
+        "dut-control"
+    }
+
+    fn get(&self, _cmd: &dyn Flashrom) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    fn can_set(&self, _cmd: &dyn Flashrom) -> bool {
+        false
+    }
+
+    fn set(&self, _cmd: &dyn Flashrom, _enable: bool) -> Result<(), String> {
+        Err("dut-control only holds the hardware write protect deasserted".into())
+    }
+
+    fn begin_test(&self, cmd: &dyn Flashrom) -> Result<(), String> {
+        self.toggle_wp(cmd, false)
+    }
+
+    fn end_test(&self, cmd: &dyn Flashrom) -> Result<(), String> {
+        self.toggle_wp(cmd, true)
+    }
+}
+
+impl DutControl {
+    /// Toggle the write protect with `dut-control`.
+    ///
+    /// In a dry run that is only planned, so the signal emulated for the
+    /// simulated flash is set to match instead.
+    fn toggle_wp(&self, cmd: &dyn Flashrom, enable: bool) -> Result<(), String> {
+        flashrom::dut_ctrl_toggle_wp(enable, self.terminate).map_err(|e| e.to_string())?;
+        set_dry_run_hw_wp(cmd, enable)
+    }
+}
+
+/// Read the write protect of the host with `crossystem`, without changing it.
+pub struct Crossystem;
+
+impl HwWpController for Crossystem {
+    fn name(&self) -> &str {
+        "crossystem"
+    }
+
+    fn get(&self, cmd: &dyn Flashrom) -> Result<bool, String> {
+        if flashrom::dry_run() {
+            return Ok(dry_run_hw_wp(cmd));
+        }
+        utils::get_hardware_wp()
+    }
+
+    fn can_set(&self, _cmd: &dyn Flashrom) -> bool {
+        false
+    }
+
+    fn set(&self, _cmd: &dyn Flashrom, _enable: bool) -> Result<(), String> {
+        Err("crossystem cannot change the hardware write protect".into())
+    }
+}
+
+/// Run shell commands to assert and deassert the write protect, such as ones
+/// driving a GPIO wired to the WP pin.
+///
+/// The signal cannot be read back, so it is taken to be deasserted until it
+/// is first set.
+pub struct ShellCommands {
+    enable: String,
+    disable: String,
+    enabled: AtomicBool,
+    terminate: Option<&'static AtomicBool>,
+}
+
+impl ShellCommands {
+    /// Run `enable` and `disable` to set the signal, killing them if they hang
+    /// or `terminate` becomes set.
+    pub fn new<S: Into<String>>(
+        enable: S,
+        disable: S,
+        terminate: Option<&'static AtomicBool>,
+    ) -> Self {
+        ShellCommands {
+            enable: enable.into(),
+            disable: disable.into(),
+            enabled: AtomicBool::new(false),
+            terminate,
+        }
+    }
+}
+
+impl HwWpController for ShellCommands {
+    fn name(&self) -> &str {
+        "shell commands"
+    }
+
+    fn get(&self, _cmd: &dyn Flashrom) -> Result<bool, String> {
+        Ok(self.enabled.load(Ordering::SeqCst))
+    }
+
+    fn can_set(&self, _cmd: &dyn Flashrom) -> bool {
+        true
+    }
+
+    fn set(&self, cmd: &dyn Flashrom, enable: bool) -> Result<(), String> {
+        let command = if enable { &self.enable } else { &self.disable };
+        info!(
+            "Running {:?} to set hardware write protect={}",
+            command, enable
+        );
+        flashrom::shell_toggle_wp(command, self.terminate).map_err(|e| e.to_string())?;
+        self.enabled.store(enable, Ordering::SeqCst);
+        set_dry_run_hw_wp(cmd, enable)
+    }
+}
+
+/// The write protect emulated along with the flash, as by the dummy
+/// programmer.
+///
+/// If the signal cannot be changed, such as when libflashrom accesses the
+/// flash, it is taken to be deasserted.
+pub struct Dummy;
+
+impl HwWpController for Dummy {
+    fn name(&self) -> &str {
+        "emulated"
+    }
+
+    fn get(&self, cmd: &dyn Flashrom) -> Result<bool, String> {
+        Ok(cmd.emulated_hw_wp().unwrap_or(false))
+    }
+
+    fn can_set(&self, cmd: &dyn Flashrom) -> bool {
+        cmd.emulated_hw_wp().is_some()
+    }
+
+    fn set(&self, cmd: &dyn Flashrom, enable: bool) -> Result<(), String> {
+        cmd.set_emulated_hw_wp(enable).map_err(|e| e.to_string())
+    }
+}
+
+/// A flash without a write protect signal the tester can reach, taken to be
+/// deasserted.
+pub struct Unavailable;
+
+impl HwWpController for Unavailable {
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn get(&self, _cmd: &dyn Flashrom) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    fn can_set(&self, _cmd: &dyn Flashrom) -> bool {
+        false
+    }
+
+    fn set(&self, _cmd: &dyn Flashrom, _enable: bool) -> Result<(), String> {
+        Err("The hardware write protect cannot be changed".into())
+    }
+}
+
+/// Return the signal emulated for the simulated flash of a dry run, in which
+/// the real one is not read.
+fn dry_run_hw_wp(cmd: &dyn Flashrom) -> bool {
+    cmd.emulated_hw_wp().unwrap_or(false)
+}
+
+/// In a dry run, where the steps changing the signal are only planned, set
+/// the signal emulated for the simulated flash to match.
+fn set_dry_run_hw_wp(cmd: &dyn Flashrom, enable: bool) -> Result<(), String> {
+    if flashrom::dry_run() && cmd.emulated_hw_wp().is_some() {
+        cmd.set_emulated_hw_wp(enable).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flashrom::MockFlashrom;
+
+    #[test]
+    fn select() {
+        let name = |p: &str| {
+            for_programmer(&Programmer::from_alias(p).unwrap(), None)
+                .name()
+                .to_owned()
+        };
+        assert_eq!(name("dummy"), "emulated");
+        assert_eq!(name("servo"), "dut-control");
+        assert_eq!(name("host"), "manual");
+        assert_eq!(name("dediprog"), "none");
+
+        let host = Programmer::new("host");
+        for n in NAMES {
+            assert!(by_name(n, &host, None).is_some(), "{}", n);
+        }
+        assert!(by_name("gpio", &host, None).is_none());
+    }
+
+    #[test]
+    fn shell_commands() {
+        let cmd = MockFlashrom::new(vec![0; 0x1000]);
+        let hw = ShellCommands::new("true", "exit 3", None);
+        assert!(!hw.get(&cmd).unwrap());
+        hw.set(&cmd, true).unwrap();
+        assert!(hw.get(&cmd).unwrap());
+
+        // A failed command leaves the signal as it was.
+        assert!(hw.set(&cmd, false).is_err());
+        assert!(hw.get(&cmd).unwrap());
+    }
+
+    #[test]
+    fn shell_commands_cancelled() {
+        static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+        let cmd = MockFlashrom::new(vec![0; 0x1000]);
+        let hw = ShellCommands::new("sleep 10", "sleep 10", Some(&TERMINATE));
+        std::thread::spawn(|| {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            TERMINATE.store(true, Ordering::SeqCst);
+        });
+        let err = hw.set(&cmd, true).unwrap_err();
+        assert!(err.contains("cancelled"), "{}", err);
+        assert!(!hw.get(&cmd).unwrap());
+    }
+
+    #[test]
+    fn dummy() {
+        let (a, b) = (
+            MockFlashrom::new(vec![0; 0x1000]),
+            MockFlashrom::new(vec![0; 0x1000]),
+        );
+        assert!(Dummy.can_set(&a));
+        Dummy.set(&a, true).unwrap();
+        assert!(Dummy.get(&a).unwrap());
+        // Each flash has its own signal.
+        assert!(!Dummy.get(&b).unwrap());
+    }
+}
@@ -40,7 +40,9 @@ extern crate log;
 pub mod types;
 
 pub mod cros_sysinfo;
+pub mod hwwp;
 pub mod rand_util;
 pub mod tester;
 pub mod tests;
+pub mod trace;
 pub mod utils;
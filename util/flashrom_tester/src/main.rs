@@ -37,39 +37,117 @@
 extern crate log;
 
 mod logger;
+mod progress_bar;
 
 use clap::{App, Arg};
-use flashrom::{FlashChip, Flashrom, FlashromCmd};
+#[cfg(feature = "libflashrom")]
+use flashrom::FlashromLib;
+#[cfg(feature = "dry-run")]
+use flashrom::{DryRun, MockFlashrom};
+use flashrom::{Flashrom, FlashromCmd, FlashromError, Programmer, TempFile, Timeouts};
+use flashrom_tester::hwwp::{self, HwWpController, ShellCommands};
+use flashrom_tester::trace::{Recorder, Replayer};
 use flashrom_tester::{tester, tests};
-use std::path::PathBuf;
+#[cfg(feature = "dry-run")]
+use rand::Rng;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
 fn main() {
-    let matches = App::new("flashrom_tester")
-        .long_version(&*format!(
-            "{}-{}\n\
-             Target: {}\n\
-             Profile: {}\n\
-             Features: {:?}\n\
-             Build time: {}\n\
-             Compiler: {}",
-            built_info::PKG_VERSION,
-            option_env!("VCSID").unwrap_or("<unknown>"),
-            built_info::TARGET,
-            built_info::PROFILE,
-            built_info::FEATURES,
-            built_info::BUILT_TIME_UTC,
-            built_info::RUSTC_VERSION,
-        ))
+    let long_version = format!(
+        "{}-{}\n\
+         Target: {}\n\
+         Profile: {}\n\
+         Features: {:?}\n\
+         Build time: {}\n\
+         Compiler: {}",
+        built_info::PKG_VERSION,
+        option_env!("VCSID").unwrap_or("<unknown>"),
+        built_info::TARGET,
+        built_info::PROFILE,
+        built_info::FEATURES,
+        built_info::BUILT_TIME_UTC,
+        built_info::RUSTC_VERSION,
+    );
+    let app = App::new("flashrom_tester")
+        .long_version(&*long_version)
         .arg(Arg::with_name("flashrom_binary").required(true))
         .arg(
             Arg::with_name("ccd_target_type")
                 .required(true)
-                .possible_values(&["host", "ec", "servo"]),
+                .validator(|s| parse_programmer(&s).map(|_| ()).map_err(|e| e.to_string()))
+                .help(
+                    "Programmer to test through: one of host, ec, servo, dediprog or dummy, \
+                     or a flashrom programmer such as linux_spi:dev=/dev/spidev0.0",
+                ),
+        )
+        .arg(
+            Arg::with_name("chip")
+                .short("c")
+                .long("chip")
+                .takes_value(true)
+                .help("Flash chip definition to use if several match the flash"),
+        )
+        .arg(timeout_arg(
+            "read-timeout",
+            "Seconds to allow for reading the flash",
+        ))
+        .arg(timeout_arg(
+            "write-timeout",
+            "Seconds to allow for writing the flash",
+        ))
+        .arg(timeout_arg(
+            "erase-timeout",
+            "Seconds to allow for erasing the flash",
+        ))
+        .arg(timeout_arg(
+            "timeout",
+            "Seconds to allow for other flashrom operations",
+        ))
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .takes_value(true)
+                .value_name("TRACE")
+                .help("Record every flashrom operation and its result to a trace file"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .takes_value(true)
+                .value_name("TRACE")
+                .conflicts_with("record")
+                .help(
+                    "Play back the results recorded in a trace file instead of running \
+                     flashrom, which with the programmer is taken from the trace",
+                ),
+        )
+        .arg(
+            Arg::with_name("hw-wp")
+                .long("hw-wp")
+                .takes_value(true)
+                .value_name("METHOD")
+                .possible_values(hwwp::NAMES)
+                .default_value("auto")
+                .help(
+                    "How to control the hardware write protect: prompt someone to toggle \
+                     it manually, hold it with dut-control, only read it with crossystem, \
+                     emulate it for the dummy programmer, assume it is deasserted with none, \
+                     or choose for the programmer with auto",
+                ),
+        )
+        .arg(
+            Arg::with_name("hw-wp-commands")
+                .long("hw-wp-commands")
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["ENABLE", "DISABLE"])
+                .help("Shell commands to assert and deassert the hardware write protect"),
         )
         .arg(
             Arg::with_name("print-layout")
@@ -104,8 +182,28 @@ fn main() {
             Arg::with_name("test_name")
                 .multiple(true)
                 .help("Names of individual tests to run (run all if unspecified)"),
-        )
-        .get_matches();
+        );
+    #[cfg(feature = "libflashrom")]
+    let app = app.arg(
+        Arg::with_name("libflashrom")
+            .long("libflashrom")
+            .conflicts_with_all(&["chip", "replay", "dry-run"])
+            .help(
+                "Access the flash through libflashrom rather than running flashrom_binary, \
+                 which is then unused",
+            ),
+    );
+    #[cfg(feature = "dry-run")]
+    let app = app.arg(
+        Arg::with_name("dry-run")
+            .long("dry-run")
+            .conflicts_with("replay")
+            .help(
+                "Print the flashrom and dut-control commands the tests would run, \
+                 without running them, simulating a flash to answer with",
+            ),
+    );
+    let matches = app.get_matches();
 
     logger::init(
         matches.value_of_os("log-file").map(PathBuf::from),
@@ -114,19 +212,98 @@ fn main() {
     debug!("Args parsed and logging initialized OK");
 
     let flashrom_path = matches
-        .value_of("flashrom_binary")
+        .value_of_os("flashrom_binary")
+        .map(PathBuf::from)
         .expect("flashrom_binary should be required");
-    let ccd_type = FlashChip::from(
+    let mut programmer = parse_programmer(
         matches
             .value_of("ccd_target_type")
             .expect("ccd_target_type should be required"),
     )
-    .expect("ccd_target_type should admit only known types");
+    .expect("ccd_target_type should be validated");
+    // Kept until the tests finish, as flashrom uses them on every run.
+    let _dummy_files = if programmer.caps().emulated {
+        match dummy_files(&mut programmer) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Failed to create files for the dummy programmer: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        Vec::new()
+    };
 
-    let cmd: Box<dyn Flashrom> = Box::new(FlashromCmd {
-        path: flashrom_path.to_string(),
-        fc: ccd_type,
-    });
+    let timeout = |name| matches.value_of(name).map(|s| parse_timeout(s).unwrap());
+    let timeouts = Timeouts {
+        read: timeout("read-timeout"),
+        write: timeout("write-timeout"),
+        erase: timeout("erase-timeout"),
+        other: timeout("timeout"),
+    };
+    let terminate = handle_sigint();
+
+    let flashrom_cmd = |programmer| FlashromCmd {
+        path: flashrom_path,
+        programmer,
+        chip: matches.value_of("chip").map(String::from),
+        progress: Default::default(),
+        timeouts,
+        terminate: Some(terminate),
+        capabilities: Default::default(),
+        emulated_hw_wp: Default::default(),
+    };
+    let mut cmd: Box<dyn Flashrom> = match matches.value_of_os("replay") {
+        Some(trace) => match Replayer::open(Path::new(trace)) {
+            Ok(replayer) => Box::new(replayer),
+            Err(e) => {
+                eprintln!("Failed to open trace to replay: {}", e);
+                std::process::exit(1);
+            }
+        },
+        #[cfg(feature = "libflashrom")]
+        None if matches.is_present("libflashrom") => match FlashromLib::new(programmer) {
+            Ok(lib) => Box::new(lib),
+            Err(e) => {
+                eprintln!("Failed to initialize libflashrom: {}", e);
+                std::process::exit(1);
+            }
+        },
+        #[cfg(feature = "dry-run")]
+        None if matches.is_present("dry-run") => Box::new(DryRun::new(
+            flashrom_cmd(programmer),
+            Box::new(simulated_flash()),
+        )),
+        None => Box::new(flashrom_cmd(programmer)),
+    };
+    if let Some(trace) = matches.value_of_os("record") {
+        cmd = match Recorder::create(cmd, Path::new(trace)) {
+            Ok(recorder) => Box::new(recorder),
+            Err(e) => {
+                eprintln!("Failed to create trace to record: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+    // Only draw progress where someone can watch it.
+    if unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 {
+        cmd.set_progress(progress_bar::callback());
+    }
+
+    let hw: Box<dyn HwWpController> = match matches.values_of("hw-wp-commands") {
+        Some(mut commands) => {
+            let enable = commands.next().expect("hw-wp-commands takes two values");
+            let disable = commands.next().expect("hw-wp-commands takes two values");
+            Box::new(ShellCommands::new(enable, disable, Some(terminate)))
+        }
+        None => {
+            let method = matches
+                .value_of("hw-wp")
+                .expect("hw-wp should have a default value");
+            hwwp::by_name(method, cmd.programmer(), Some(terminate))
+                .expect("hw-wp should be validated")
+        }
+    };
 
     let print_layout = matches.is_present("print-layout");
     let output_format = matches
@@ -138,15 +315,79 @@ fn main() {
 
     if let Err(e) = tests::generic(
         cmd.as_ref(),
-        ccd_type,
+        hw.as_ref(),
         print_layout,
         output_format,
         test_names,
-        Some(handle_sigint()),
+        Some(terminate),
     ) {
         eprintln!("Failed to run tests: {:?}", e);
         std::process::exit(1);
     }
+
+    if flashrom::dry_run() {
+        println!("Dry run plan:");
+        for (i, step) in flashrom::dry_run_plan().iter().enumerate() {
+            println!("{:4}. {}", i + 1, step);
+        }
+    }
+}
+
+/// Create temporary files for the dummy `programmer` to keep the flash
+/// contents and status register in between runs of flashrom, unless it
+/// already names files for them.
+fn dummy_files(programmer: &mut Programmer) -> std::io::Result<Vec<TempFile>> {
+    let mut files = Vec::new();
+    for (param, name) in [
+        ("image", "dummy_image"),
+        ("spi_status_file", "dummy_status"),
+    ] {
+        if programmer.param(param).is_none() {
+            let file = TempFile::new(name)?;
+            *programmer = programmer
+                .clone()
+                .with_param(param, file.path().to_string_lossy());
+            files.push(file);
+        }
+    }
+    Ok(files)
+}
+
+/// Simulate a flash filled with random data for a dry run to answer with, as
+/// a blank one would make a successful erase indistinguishable from an
+/// unmodified flash.
+#[cfg(feature = "dry-run")]
+fn simulated_flash() -> MockFlashrom {
+    const SIZE: usize = 16 * 1024 * 1024;
+
+    let mut contents = vec![0; SIZE];
+    rand::thread_rng().fill(&mut contents[..]);
+    MockFlashrom::new(contents)
+}
+
+/// Parse a programmer given on the command line, which may be a short alias.
+fn parse_programmer(s: &str) -> Result<Programmer, FlashromError> {
+    match Programmer::from_alias(s) {
+        Some(p) => Ok(p),
+        None => s.parse(),
+    }
+}
+
+fn timeout_arg<'a>(name: &'a str, help: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .long(name)
+        .takes_value(true)
+        .value_name("SECONDS")
+        .validator(|s| parse_timeout(&s).map(|_| ()))
+        .help(help)
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs > 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| format!("{:?} is not a positive number of seconds", s))
 }
 
 /// Catch exactly one SIGINT, printing a message in response and setting a flag.
@@ -161,7 +402,10 @@ fn handle_sigint() -> &'static AtomicBool {
     use std::sync::atomic::Ordering;
 
     unsafe {
-        let _ = libc::signal(libc::SIGINT, sigint_handler as libc::sighandler_t);
+        let _ = libc::signal(
+            libc::SIGINT,
+            sigint_handler as extern "C" fn(c_int) as libc::sighandler_t,
+        );
     }
     static TERMINATE_FLAG: AtomicBool = AtomicBool::new(false);
 
@@ -169,8 +413,9 @@ fn handle_sigint() -> &'static AtomicBool {
         const STDERR_FILENO: c_int = 2;
         static MESSAGE: &[u8] = b"
 WARNING: terminating tests prematurely may leave Flash in an inconsistent state,
-rendering your machine unbootable. Testing will end on completion of the current
-test, or press ^C again to exit immediately (possibly bricking your machine).
+rendering your machine unbootable. The running flashrom operation will be stopped
+and testing will end once the Flash has been restored, or press ^C again to exit
+immediately (possibly bricking your machine).
 ";
 
         // Use raw write() because signal-safety is a very hard problem. Safe because this doesn't
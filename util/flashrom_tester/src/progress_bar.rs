@@ -0,0 +1,137 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+use flashrom::{Progress, ProgressCallback, ProgressStage};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const WIDTH: usize = 30;
+
+/// When an operation started, and how far it had got when last reported.
+struct Started {
+    stage: ProgressStage,
+    at: Instant,
+    current: usize,
+}
+
+/// Return a callback drawing progress as a bar on stderr, with an estimate of
+/// the time remaining.
+pub fn callback() -> ProgressCallback {
+    // flashrom interleaves erasing and writing, so each stage is timed apart.
+    let started: Mutex<Vec<Started>> = Mutex::new(Vec::new());
+
+    ProgressCallback::new(move |p| {
+        let mut started = started.lock().unwrap();
+        let now = Instant::now();
+        let i = match started.iter().position(|s| s.stage == p.stage) {
+            // Progress going backwards is a new operation.
+            Some(i) if started[i].current <= p.current => i,
+            found => {
+                if let Some(i) = found {
+                    started.remove(i);
+                }
+                started.push(Started {
+                    stage: p.stage,
+                    at: now,
+                    current: p.current,
+                });
+                started.len() - 1
+            }
+        };
+        started[i].current = p.current;
+
+        let stderr = std::io::stderr();
+        let mut stderr = stderr.lock();
+        // Write errors deliberately ignored
+        let _ = write!(stderr, "\r{}", render(p, now - started[i].at));
+        if p.current >= p.total {
+            let _ = writeln!(stderr);
+            started.remove(i);
+        }
+    })
+}
+
+fn render(p: Progress, elapsed: Duration) -> String {
+    let fraction = if p.total == 0 {
+        1.0
+    } else {
+        (p.current as f64 / p.total as f64).min(1.0)
+    };
+    let filled = (fraction * WIDTH as f64) as usize;
+    let eta = if fraction > 0.0 {
+        let secs = elapsed.as_secs_f64() * (1.0 - fraction) / fraction;
+        format!("{:02}:{:02}", secs as u64 / 60, secs as u64 % 60)
+    } else {
+        "--:--".to_string()
+    };
+    format!(
+        "{:<7} [{}{}] {:>3}% ETA {}",
+        format!("[{}]", p.stage),
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        (fraction * 100.0) as u32,
+        eta
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use flashrom::{Progress, ProgressStage};
+    use std::time::Duration;
+
+    #[test]
+    fn render_progress() {
+        let progress = |current, total| Progress {
+            stage: ProgressStage::Write,
+            current,
+            total,
+        };
+
+        assert_eq!(
+            render(progress(0, 100), Duration::from_secs(0)),
+            "[WRITE] [------------------------------]   0% ETA --:--"
+        );
+        assert_eq!(
+            render(progress(25, 100), Duration::from_secs(30)),
+            "[WRITE] [#######-----------------------]  25% ETA 01:30"
+        );
+        assert_eq!(
+            render(progress(1 << 24, 1 << 24), Duration::from_secs(600)),
+            "[WRITE] [##############################] 100% ETA 00:00"
+        );
+    }
+}
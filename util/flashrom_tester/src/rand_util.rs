@@ -36,22 +36,26 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufWriter;
+use std::path::Path;
 
 use rand::prelude::*;
 
-pub fn gen_rand_testdata(path: &str, size: usize) -> std::io::Result<()> {
+pub fn gen_rand_testdata<P: AsRef<Path>>(path: P, size: usize) -> std::io::Result<()> {
     let mut buf = BufWriter::new(File::create(path)?);
 
-    let mut a: Vec<u8> = Vec::with_capacity(size);
-    // Pad out array to be filled in by Rng::fill().
-    a.resize(size, 0b0);
-    thread_rng().fill(a.as_mut_slice());
-
-    buf.write_all(a.as_slice())?;
+    buf.write_all(gen_rand_data(size).as_slice())?;
 
     Ok(())
 }
 
+/// Return `size` bytes of random data.
+pub fn gen_rand_data(size: usize) -> Vec<u8> {
+    // Pad out array to be filled in by Rng::fill().
+    let mut a: Vec<u8> = vec![0b0; size];
+    thread_rng().fill(a.as_mut_slice());
+    a
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -33,13 +33,15 @@
 // Software Foundation.
 //
 
+use super::hwwp::HwWpController;
 use super::rand_util;
 use super::types;
 use super::utils::{self, LayoutSizes};
-use flashrom::FlashromError;
-use flashrom::{FlashChip, Flashrom};
+use flashrom::{Capabilities, Flashrom, LayoutSource, ProgrammerCaps, TempFile, WpRange};
+use flashrom::{ErrorKind, FlashromError};
 use serde_json::json;
-use std::mem::MaybeUninit;
+use std::fs::File;
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 
@@ -47,51 +49,83 @@ use std::sync::Mutex;
 type TestError = Box<dyn std::error::Error>;
 pub type TestResult = Result<(), TestError>;
 
+/// Returned by a test which cannot run, with the reason why.
+#[derive(Debug)]
+pub struct Skipped(pub String);
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Skipped {}
+
 pub struct TestEnv<'a> {
-    chip_type: FlashChip,
     /// Flashrom instantiation information.
     ///
     /// Where possible, prefer to use methods on the TestEnv rather than delegating
     /// to the raw flashrom functions.
     pub cmd: &'a dyn Flashrom,
+    /// How the hardware write protect is controlled.
+    hw: &'a dyn HwWpController,
     layout: LayoutSizes,
 
     pub wp: WriteProtectState<'a, 'static>,
-    /// The path to a file containing the flash contents at test start.
-    // TODO(pmarheine) migrate this to a PathBuf for clarity
-    original_flash_contents: String,
-    /// The path to a file containing flash-sized random data
-    // TODO(pmarheine) make this a PathBuf too
-    random_data: String,
+    /// The flash contents at test start.
+    original_flash_contents: Vec<u8>,
+    /// A file containing flash-sized random data.
+    random_data: TempFile,
+    /// A file to read regions of the flash into.
+    region_data: TempFile,
+    /// A layout file describing `layout`.
+    layout_file: TempFile,
+    /// The ranges the chip can write protect.
+    wp_ranges: Vec<WpRange>,
 }
 
 impl<'a> TestEnv<'a> {
-    pub fn create(chip_type: FlashChip, cmd: &'a dyn Flashrom) -> Result<Self, FlashromError> {
+    pub fn create(
+        cmd: &'a dyn Flashrom,
+        hw: &'a dyn HwWpController,
+    ) -> Result<Self, FlashromError> {
         let rom_sz = cmd.get_size()?;
+
+        info!("Stashing golden image for verification/recovery on completion");
+        let original_flash_contents = cmd.read_to_vec()?;
+        cmd.verify_slice(&original_flash_contents)?;
+
+        let wp_ranges = cmd.wp_list().unwrap_or_else(|e| {
+            warn!("Unable to list protection ranges, not testing them: {}", e);
+            Vec::new()
+        });
+
         let out = TestEnv {
-            chip_type: chip_type,
-            cmd: cmd,
+            cmd,
+            hw,
             layout: utils::get_layout_sizes(rom_sz)?,
-            wp: WriteProtectState::from_hardware(cmd, chip_type)?,
-            original_flash_contents: "/tmp/flashrom_tester_golden.bin".into(),
-            random_data: "/tmp/random_content.bin".into(),
+            wp: WriteProtectState::from_hardware(cmd, hw)?,
+            original_flash_contents,
+            random_data: TempFile::new("tester_random")?,
+            region_data: TempFile::new("tester_region")?,
+            layout_file: TempFile::new("tester_layout")?,
+            wp_ranges,
         };
 
-        info!("Stashing golden image for verification/recovery on completion");
-        out.cmd.read(&out.original_flash_contents)?;
-        out.cmd.verify(&out.original_flash_contents)?;
+        File::create(out.layout_file.path())
+            .and_then(|f| utils::construct_layout_file(f, &out.layout))
+            .map_err(|io_err| format!("I/O error writing layout file: {:#}", io_err))?;
 
         info!("Generating random flash-sized data");
-        rand_util::gen_rand_testdata(&out.random_data, rom_sz as usize)
+        rand_util::gen_rand_testdata(out.random_data.path(), rom_sz as usize)
             .map_err(|io_err| format!("I/O error writing random data file: {:#}", io_err))?;
 
         Ok(out)
     }
 
     pub fn run_test<T: TestCase>(&mut self, test: T) -> TestResult {
-        let use_dut_control = self.chip_type == FlashChip::SERVO;
-        if use_dut_control && flashrom::dut_ctrl_toggle_wp(false).is_err() {
-            error!("failed to dispatch dut_ctrl_toggle_wp()!");
+        if let Err(e) = self.hw.begin_test(self.cmd) {
+            error!("Failed to prepare hardware write protect: {}", e);
         }
 
         let name = test.get_name();
@@ -99,39 +133,91 @@ impl<'a> TestEnv<'a> {
         let out = test.run(self);
         info!("Completed test: {}; result {:?}", name, out);
 
-        if use_dut_control && flashrom::dut_ctrl_toggle_wp(true).is_err() {
-            error!("failed to dispatch dut_ctrl_toggle_wp()!");
+        if let Err(e) = self.hw.end_test(self.cmd) {
+            error!("Failed to restore hardware write protect: {}", e);
         }
         out
     }
 
-    pub fn chip_type(&self) -> FlashChip {
-        // This field is not public because it should be immutable to tests,
-        // so this getter enforces that it is copied.
-        self.chip_type
+    /// Return the capabilities of the programmer under test.
+    pub fn caps(&self) -> ProgrammerCaps {
+        self.cmd.programmer().caps()
+    }
+
+    /// Check that flashrom has the `needed` capabilities, returning why the
+    /// test must be skipped if it does not.
+    pub fn require(&self, needed: Capabilities) -> Result<(), Skipped> {
+        let caps = self.cmd.capabilities();
+        let missing = caps.missing(&needed);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Skipped(format!(
+                "{} does not support {}",
+                caps,
+                missing.join(", ")
+            )))
+        }
     }
 
     /// Return the path to a file that contains random data and is the same size
     /// as the flash chip.
-    pub fn random_data_file(&self) -> &str {
-        &self.random_data
+    pub fn random_data_file(&self) -> &Path {
+        self.random_data.path()
     }
 
     pub fn layout(&self) -> &LayoutSizes {
         &self.layout
     }
 
+    /// Return the path to a layout file describing the sections of
+    /// [`layout`](Self::layout).
+    pub fn layout_file(&self) -> &Path {
+        self.layout_file.path()
+    }
+
+    /// Return the ranges the chip can write protect, as listed when testing
+    /// started.
+    pub fn wp_ranges(&self) -> &[WpRange] {
+        &self.wp_ranges
+    }
+
     /// Return true if the current Flash contents are the same as the golden image
     /// that was present at the start of testing.
     pub fn is_golden(&self) -> bool {
-        self.cmd.verify(&self.original_flash_contents).is_ok()
+        self.cmd.verify_slice(&self.original_flash_contents).is_ok()
+    }
+
+    /// Return true if `region` of the layout from `layout` is the same as in the
+    /// golden image, reading only that region of the flash.
+    ///
+    /// An FMAP or IFD layout is found in the golden image.
+    pub fn region_is_golden(
+        &self,
+        layout: LayoutSource,
+        region: &str,
+    ) -> Result<bool, FlashromError> {
+        let r = layout
+            .regions_in(&self.original_flash_contents)?
+            .into_iter()
+            .find(|r| r.name == region)
+            .ok_or_else(|| format!("Region {} is not in the layout", region))?;
+        self.cmd
+            .read_region(layout, &[region], self.region_data.path())?;
+
+        let range = r.start as usize..(r.start + r.len) as usize;
+        let current = self.region_data.read()?;
+        Ok(matches!(
+            (self.original_flash_contents.get(range.clone()), current.get(range)),
+            (Some(a), Some(b)) if a == b
+        ))
     }
 
     /// Do whatever is necessary to make the current Flash contents the same as they
     /// were at the start of testing.
     pub fn ensure_golden(&mut self) -> Result<(), FlashromError> {
-        self.wp.set_hw(false)?.set_sw(false)?;
-        self.cmd.write(&self.original_flash_contents)?;
+        self.wp.set_hw(false)?.set_sw(false)?.clear_range()?;
+        self.cmd.write_from_slice(&self.original_flash_contents)?;
         Ok(())
     }
 
@@ -145,7 +231,7 @@ impl<'a> TestEnv<'a> {
     /// path.
     ///
     /// Returns Err if they are not the same.
-    pub fn verify(&self, contents_path: &str) -> Result<(), FlashromError> {
+    pub fn verify(&self, contents_path: &Path) -> Result<(), FlashromError> {
         self.cmd.verify(contents_path)?;
         Ok(())
     }
@@ -182,7 +268,7 @@ pub struct WriteProtectState<'a, 'p> {
     // Tuples are (hardware, software)
     current: (bool, bool),
     cmd: &'a dyn Flashrom,
-    fc: FlashChip,
+    hw: &'a dyn HwWpController,
 }
 
 enum InitialState<'p> {
@@ -202,53 +288,69 @@ impl InitialState<'_> {
 impl<'a> WriteProtectState<'a, 'static> {
     /// Initialize a state from the current state of the hardware.
     ///
-    /// Panics if there is already a live state derived from hardware. In such a situation the
-    /// new state must be derived from the live one, or the live one must be dropped first.
-    pub fn from_hardware(cmd: &'a dyn Flashrom, fc: FlashChip) -> Result<Self, FlashromError> {
+    /// Panics if there is already a live state derived from the hardware of `cmd`. In such a
+    /// situation the new state must be derived from the live one, or the live one must be
+    /// dropped first.
+    pub fn from_hardware(
+        cmd: &'a dyn Flashrom,
+        hw: &'a dyn HwWpController,
+    ) -> Result<Self, FlashromError> {
         let mut lock = Self::get_liveness_lock()
             .lock()
             .expect("Somebody panicked during WriteProtectState init from hardware");
-        if *lock {
+        let key = Self::flash_key(cmd);
+        if lock.contains(&key) {
             drop(lock); // Don't poison the lock
             panic!("Attempted to create a new WriteProtectState when one is already live");
         }
 
-        let hw = Self::get_hw(cmd)?;
+        let hw_wp = hw.get(cmd)?;
         let sw = Self::get_sw(cmd)?;
-        info!("Initial hardware write protect: HW={} SW={}", hw, sw);
+        info!(
+            "Initial hardware write protect: HW={} ({}) SW={}",
+            hw_wp,
+            hw.name(),
+            sw
+        );
 
-        *lock = true;
+        lock.push(key);
         Ok(WriteProtectState {
-            initial: InitialState::Hardware(hw, sw),
-            current: (hw, sw),
+            initial: InitialState::Hardware(hw_wp, sw),
+            current: (hw_wp, sw),
             cmd,
-            fc,
+            hw,
         })
     }
 
-    /// Get the actual hardware write protect state.
-    fn get_hw(cmd: &dyn Flashrom) -> Result<bool, String> {
-        if cmd.can_control_hw_wp() {
-            super::utils::get_hardware_wp()
-        } else {
-            Ok(false)
-        }
-    }
-
     /// Get the actual software write protect state.
+    ///
+    /// This is taken to be disabled if flashrom cannot read it.
     fn get_sw(cmd: &dyn Flashrom) -> Result<bool, FlashromError> {
-        let b = cmd.wp_status(true)?;
-        Ok(b)
+        if !cmd.capabilities().wp {
+            return Ok(false);
+        }
+        match cmd.get_wp_status() {
+            Ok(status) => Ok(status.enabled()),
+            Err(e)
+                if matches!(e, FlashromError::Parse(_)) || e.kind() == ErrorKind::Unsupported =>
+            {
+                warn!(
+                    "Unable to read software write protect, assuming disabled: {}",
+                    e
+                );
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
     }
 }
 
 impl<'a, 'p> WriteProtectState<'a, 'p> {
-    /// Return true if the current programmer supports setting the hardware
-    /// write protect.
+    /// Return true if the hardware write protect controller can set it.
     ///
     /// If false, calls to set_hw() will do nothing.
     pub fn can_control_hw_wp(&self) -> bool {
-        self.cmd.can_control_hw_wp()
+        self.hw.can_set(self.cmd)
     }
 
     /// Set the software write protect.
@@ -261,22 +363,92 @@ impl<'a, 'p> WriteProtectState<'a, 'p> {
         Ok(self)
     }
 
+    /// Enable software write protect over `range` as (start, len), checking
+    /// that exactly that range is reported as protected afterwards.
+    pub fn set_range(&mut self, range: (i64, i64)) -> Result<&mut Self, FlashromError> {
+        info!("request range={:#x?}", range);
+        self.cmd.wp_range(range, /* wp_enable= */ true)?;
+        self.current.1 = true;
+
+        let status = self.cmd.get_wp_status()?;
+        if !status.enabled() || status.range != range {
+            return Err(format!(
+                "Requested protection of range {:#x?} but status is {:#x?}",
+                range, status
+            )
+            .into());
+        }
+        Ok(self)
+    }
+
+    /// Enable software write protect over the region `name` of the layout from
+    /// `layout`, checking that exactly the range of the region is reported as
+    /// protected afterwards.
+    pub fn set_region(
+        &mut self,
+        name: &str,
+        layout: LayoutSource,
+    ) -> Result<&mut Self, FlashromError> {
+        let region = self
+            .cmd
+            .layout_regions(layout)?
+            .into_iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| format!("Region {} is not in the layout", name))?;
+        info!(
+            "request region={} range={:#x?}",
+            name,
+            (region.start, region.len)
+        );
+        self.cmd.wp_region(name, layout, /* wp_enable= */ true)?;
+        self.current.1 = true;
+
+        let status = self.cmd.get_wp_status()?;
+        if !status.enabled() || status.range != (region.start, region.len) {
+            return Err(format!(
+                "Requested protection of region {} at {:#x?} but status is {:#x?}",
+                name,
+                (region.start, region.len),
+                status
+            )
+            .into());
+        }
+        Ok(self)
+    }
+
+    /// Protect an empty range.
+    ///
+    /// Disabling software write protect may leave the protection bits of the
+    /// last range set, which depending on the range can still prevent writing.
+    pub fn clear_range(&mut self) -> Result<&mut Self, FlashromError> {
+        // Nothing can have been protected if flashrom cannot protect it.
+        if self.cmd.capabilities().wp {
+            self.cmd.wp_range((0, 0), /* wp_enable= */ false)?;
+        }
+        Ok(self)
+    }
+
     /// Set the hardware write protect.
     pub fn set_hw(&mut self, enable: bool) -> Result<&mut Self, String> {
         if self.current.0 != enable {
             if self.can_control_hw_wp() {
-                super::utils::toggle_hw_wp(/* dis= */ !enable)?;
+                self.toggle_hw_wp(/* dis= */ !enable)?;
                 self.current.0 = enable;
             } else if enable {
                 info!(
-                    "Ignoring attempt to enable hardware WP with {:?} programmer",
-                    self.fc
+                    "Ignoring attempt to enable hardware WP with {} control",
+                    self.hw.name()
                 );
             }
         }
         Ok(self)
     }
 
+    /// Set the actual hardware write protect through the controller.
+    fn toggle_hw_wp(&self, dis: bool) -> Result<(), String> {
+        self.hw.set(self.cmd, /* enable= */ !dis)
+    }
+
     /// Stack a new write protect state on top of the current one.
     ///
     /// This is useful if you need to temporarily make a change to write protection:
@@ -284,7 +456,8 @@ impl<'a, 'p> WriteProtectState<'a, 'p> {
     /// ```no_run
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// # let cmd: flashrom::FlashromCmd = unimplemented!();
-    /// let wp = flashrom_tester::tester::WriteProtectState::from_hardware(&cmd, flashrom::FlashChip::SERVO)?;
+    /// let hw = flashrom_tester::hwwp::for_programmer(&cmd.programmer, cmd.terminate);
+    /// let wp = flashrom_tester::tester::WriteProtectState::from_hardware(&cmd, hw.as_ref())?;
     /// {
     ///     let mut wp = wp.push();
     ///     wp.set_sw(false)?;
@@ -304,32 +477,32 @@ impl<'a, 'p> WriteProtectState<'a, 'p> {
             initial: InitialState::Previous(self),
             current: self.current,
             cmd: self.cmd,
-            fc: self.fc,
+            hw: self.hw,
         }
     }
 
-    fn get_liveness_lock() -> &'static Mutex<bool> {
-        static INIT: std::sync::Once = std::sync::Once::new();
-        /// Value becomes true when there is a live WriteProtectState derived `from_hardware`,
-        /// blocking duplicate initialization.
+    fn get_liveness_lock() -> &'static Mutex<Vec<String>> {
+        /// Holds the `flash_key` of each flash with a live WriteProtectState derived
+        /// `from_hardware`, blocking duplicate initialization.
         ///
         /// This is required because hardware access is not synchronized; it's possible to leave the
         /// hardware in an unintended state by creating a state handle from it, modifying the state,
         /// creating another handle from the hardware then dropping the first handle- then on drop
         /// of the second handle it will restore the state to the modified one rather than the initial.
         ///
-        /// This flag ensures that a duplicate root state cannot be created.
+        /// This ensures that a duplicate root state cannot be created for a flash.
         ///
-        /// This is a Mutex<bool> rather than AtomicBool because acquiring the flag needs to perform
-        /// several operations that may themselves fail- acquisitions must be fully synchronized.
-        static mut LIVE_FROM_HARDWARE: MaybeUninit<Mutex<bool>> = MaybeUninit::uninit();
+        /// This is a Mutex because acquiring the flag needs to perform several operations that
+        /// may themselves fail- acquisitions must be fully synchronized.
+        static LIVE_FROM_HARDWARE: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
-        unsafe {
-            INIT.call_once(|| {
-                LIVE_FROM_HARDWARE.as_mut_ptr().write(Mutex::new(false));
-            });
-            &*LIVE_FROM_HARDWARE.as_ptr()
-        }
+        &LIVE_FROM_HARDWARE
+    }
+
+    /// Identify the flash `cmd` accesses by its programmer, so that several
+    /// `Flashrom`s wrapping the same one share a root state.
+    fn flash_key(cmd: &dyn Flashrom) -> String {
+        cmd.programmer().to_string()
     }
 
     /// Reset the hardware to what it was when this state was created, reporting errors.
@@ -376,7 +549,7 @@ impl<'a, 'p> WriteProtectState<'a, 'p> {
         if sw != self.current.1 {
             // Is the hw wp currently enabled?
             if self.current.0 {
-                super::utils::toggle_hw_wp(/* dis= */ true).map_err(|e| {
+                self.toggle_hw_wp(/* dis= */ true).map_err(|e| {
                     format!(
                         "Failed to {}able hardware write protect: {}",
                         enable_str(false),
@@ -394,11 +567,11 @@ impl<'a, 'p> WriteProtectState<'a, 'p> {
         }
 
         assert!(
-            self.cmd.can_control_hw_wp() || (!self.current.0 && !hw),
+            self.can_control_hw_wp() || (!self.current.0 && !hw),
             "HW WP must be disabled if it cannot be controlled"
         );
         if hw != self.current.0 {
-            super::utils::toggle_hw_wp(/* dis= */ !hw).map_err(|e| {
+            self.toggle_hw_wp(/* dis= */ !hw).map_err(|e| {
                 format!(
                     "Failed to {}able hardware write protect: {}",
                     enable_str(hw),
@@ -410,7 +583,8 @@ impl<'a, 'p> WriteProtectState<'a, 'p> {
         if let Some(mut lock) = lock {
             // Initial state was constructed via from_hardware, now we can clear the liveness
             // lock since reset is complete.
-            *lock = false;
+            let key = Self::flash_key(self.cmd);
+            lock.retain(|live| *live != key);
         }
         Ok(())
     }
@@ -466,6 +640,8 @@ pub enum TestConclusion {
     Fail,
     UnexpectedPass,
     UnexpectedFail,
+    /// The test could not run, such as for lack of a flashrom feature.
+    Skipped,
 }
 
 pub struct ReportMetaData {
@@ -479,6 +655,7 @@ fn decode_test_result(res: TestResult, con: TestConclusion) -> (TestConclusion,
     use TestConclusion::*;
 
     match (res, con) {
+        (Err(e), _) if e.is::<crate::tester::Skipped>() => (Skipped, Some(e)),
         (Ok(_), Fail) => (UnexpectedPass, None),
         (Err(e), Pass) => (UnexpectedFail, Some(e)),
         _ => (Pass, None),
@@ -486,8 +663,7 @@ fn decode_test_result(res: TestResult, con: TestConclusion) -> (TestConclusion,
 }
 
 pub fn run_all_tests<T, TS>(
-    chip: FlashChip,
-    cmd: &dyn Flashrom,
+    env: &mut TestEnv,
     ts: TS,
     terminate_flag: Option<&AtomicBool>,
 ) -> Vec<(String, (TestConclusion, Option<TestError>))>
@@ -495,8 +671,6 @@ where
     T: TestCase + Copy,
     TS: IntoIterator<Item = T>,
 {
-    let mut env = TestEnv::create(chip, cmd).expect("Failed to set up test environment");
-
     let mut results = Vec::new();
     for t in ts {
         if terminate_flag
@@ -556,7 +730,16 @@ pub fn collate_all_test_runs(
 
             for trun in truns.iter() {
                 let (name, (result, error)) = trun;
-                if *result != TestConclusion::Pass {
+                if *result == TestConclusion::Skipped {
+                    println!(
+                        " {} {}",
+                        style!(format!(" <+> {} test:", name), types::BOLD),
+                        style_dbg!(result, types::YELLOW)
+                    );
+                    if let Some(e) = error {
+                        info!(" - {} skipped: {}", name, e);
+                    }
+                } else if *result != TestConclusion::Pass {
                     println!(
                         " {} {}",
                         style!(format!(" <+> {} test:", name), types::BOLD),
@@ -564,7 +747,7 @@ pub fn collate_all_test_runs(
                     );
                     match error {
                         None => {}
-                        Some(e) => info!(" - {} failure details:\n{}", name, e.to_string()),
+                        Some(e) => info!(" - {} failure details:\n{}", name, e),
                     };
                 } else {
                     println!(
@@ -583,9 +766,11 @@ pub fn collate_all_test_runs(
             let mut tests = Map::<String, Value>::new();
             for (name, (result, error)) in truns {
                 let passed = *result == TestConclusion::Pass;
-                all_pass &= passed;
+                let skipped = *result == TestConclusion::Skipped;
+                all_pass &= passed || skipped;
 
                 let error = match error {
+                    Some(e) if skipped => Value::String(e.to_string()),
                     Some(e) => Value::String(format!("{:#?}", e)),
                     None => Value::Null,
                 };
@@ -599,6 +784,7 @@ pub fn collate_all_test_runs(
                     name.into(),
                     json!({
                         "pass": passed,
+                        "skipped": skipped,
                         "error": error,
                     }),
                 );
@@ -641,6 +827,83 @@ mod tests {
         let (result, err) = decode_test_result(Err("broken".into()), Fail);
         assert_eq!(result, Pass);
         assert!(err.is_none());
+
+        let skipped = super::Skipped("no --wp-region".into());
+        let (result, err) = decode_test_result(Err(skipped.into()), Pass);
+        assert_eq!(result, Skipped);
+        assert_eq!(err.unwrap().to_string(), "no --wp-region");
+    }
+
+    #[test]
+    fn write_protect_state() {
+        use super::WriteProtectState;
+        use crate::hwwp::Dummy;
+        use flashrom::{Flashrom, MockFlashrom};
+
+        let mock = MockFlashrom::new(vec![0; 0x1000]);
+        {
+            let mut wp = WriteProtectState::from_hardware(&mock, &Dummy).unwrap();
+            wp.set_sw(true).unwrap().set_hw(true).unwrap();
+            assert!(mock.get_wp_status().unwrap().enabled());
+            assert_eq!(mock.emulated_hw_wp(), Some(true));
+            {
+                // Software write protect can't change with hardware asserted.
+                let mut wp = wp.push();
+                assert!(wp.set_sw(false).is_err());
+                wp.set_hw(false).unwrap();
+            }
+            assert_eq!(mock.emulated_hw_wp(), Some(true));
+        }
+        // Both are restored, software first while hardware allows it.
+        assert!(!mock.get_wp_status().unwrap().enabled());
+        assert_eq!(mock.emulated_hw_wp(), Some(false));
+
+        let mut wp = WriteProtectState::from_hardware(&mock, &Dummy).unwrap();
+        wp.set_range((0, 0x800)).unwrap();
+        assert!(wp.set_range((0x100, 0x100)).is_err());
+        wp.close().unwrap();
+        assert!(!mock.get_wp_status().unwrap().enabled());
+    }
+
+    #[test]
+    #[should_panic(expected = "already live")]
+    fn one_root_per_programmer() {
+        use super::WriteProtectState;
+        use crate::hwwp::Dummy;
+        use flashrom::{Capabilities, FlashromCmd, Programmer};
+
+        let cmd = || FlashromCmd {
+            path: "false".into(),
+            programmer: Programmer::new("host"),
+            chip: None,
+            progress: Default::default(),
+            timeouts: Default::default(),
+            terminate: None,
+            capabilities: Capabilities::NONE.into(),
+            emulated_hw_wp: Default::default(),
+        };
+        // Each accesses the same flash, so its state must not be taken twice.
+        let (a, b) = (cmd(), cmd());
+        let _wp = WriteProtectState::from_hardware(&a, &Dummy).unwrap();
+        let _ = WriteProtectState::from_hardware(&b, &Dummy);
+    }
+
+    #[test]
+    fn unreadable_sw_wp() {
+        use super::WriteProtectState;
+        use crate::hwwp::Dummy;
+        use flashrom::{ErrorKind, Fault, MockFlashrom, MockOp};
+
+        let mock = MockFlashrom::new(vec![0; 0x1000]);
+        mock.inject(MockOp::WpRead, Fault::Fail(ErrorKind::Unsupported));
+        let wp = WriteProtectState::from_hardware(&mock, &Dummy).unwrap();
+        assert_eq!(wp.current, (false, false));
+        drop(wp);
+
+        // Failing to reach the flash is still an error.
+        mock.clear_faults();
+        mock.inject(MockOp::WpRead, Fault::Fail(ErrorKind::ChipNotFound));
+        assert!(WriteProtectState::from_hardware(&mock, &Dummy).is_err());
     }
 
     #[test]
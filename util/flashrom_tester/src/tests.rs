@@ -34,16 +34,19 @@
 //
 
 use super::cros_sysinfo;
+use super::hwwp::HwWpController;
+use super::rand_util;
 use super::tester::{self, OutputFormat, TestCase, TestEnv, TestResult};
 use super::utils::{self, LayoutNames};
-use flashrom::{FlashChip, Flashrom};
+use flashrom::{
+    Capabilities, ChipInfo, ErrorKind, Flashrom, FlashromError, LayoutSource, RegionFile, TempFile,
+    TestStatus, WpMode, WpRange,
+};
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, Write};
+use std::io::BufRead;
 use std::sync::atomic::AtomicBool;
 
-const LAYOUT_FILE: &'static str = "/tmp/layout.file";
-
 /// Iterate over tests, yielding only those tests with names matching filter_names.
 ///
 /// If filter_names is None, all tests will be run. None is distinct from Some(∅);
@@ -70,6 +73,37 @@ fn filter_tests<'n, 't: 'n, T: TestCase>(
     })
 }
 
+/// Warn about probed `chips` whose write protection flashrom doesn't record
+/// as tested, since failures of the write protect tests may be the chip's.
+fn check_chip_support(cmd: &dyn Flashrom, chips: &[ChipInfo]) {
+    let mut supported = match cmd.supported_chips() {
+        Ok(supported) => supported,
+        Err(e) => {
+            info!("Cannot list the chips flashrom supports: {}", e);
+            return;
+        }
+    };
+    supported.add_probed(chips);
+
+    for chip in chips {
+        match supported.find(chip).map(|c| c.tested.wp) {
+            None => warn!(
+                "{} {} is missing from the chips flashrom supports",
+                chip.vendor, chip.name
+            ),
+            Some(None) => info!(
+                "flashrom doesn't record whether write protection of {} {} is tested",
+                chip.vendor, chip.name
+            ),
+            Some(Some(TestStatus::Ok)) => {}
+            Some(Some(status)) => warn!(
+                "Write protection of {} {} is {}",
+                chip.vendor, chip.name, status
+            ),
+        }
+    }
+}
+
 /// Run tests.
 ///
 /// Only returns an Error if there was an internal error; test failures are Ok.
@@ -79,35 +113,61 @@ fn filter_tests<'n, 't: 'n, T: TestCase>(
 /// as a warning.
 pub fn generic<'a, TN: Iterator<Item = &'a str>>(
     cmd: &dyn Flashrom,
-    fc: FlashChip,
+    hw: &dyn HwWpController,
     print_layout: bool,
     output_format: OutputFormat,
     test_names: Option<TN>,
     terminate_flag: Option<&AtomicBool>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    utils::ac_power_warning();
+    // The dummy programmer emulates flash entirely in software, so there is no
+    // machine to keep powered or to collect system information from. Neither
+    // matters in a dry run, which only plans what would be done.
+    let emulated = cmd.programmer().caps().emulated;
+    let dry_run = flashrom::dry_run();
+    if !emulated && !dry_run {
+        utils::ac_power_warning();
+    }
 
-    info!("Calculate ROM partition sizes & Create the layout file.");
-    let rom_sz: i64 = cmd.get_size()?;
-    let layout_sizes = utils::get_layout_sizes(rom_sz)?;
-    {
-        let mut f = File::create(LAYOUT_FILE)?;
-        let mut buf: Vec<u8> = vec![];
-        utils::construct_layout_file(&mut buf, &layout_sizes)?;
-
-        f.write_all(&buf)?;
-        if print_layout {
-            info!(
-                "Dumping layout file as requested:\n{}",
-                String::from_utf8_lossy(&buf)
-            );
+    // flashrom refuses to use a flash matched by several chip definitions
+    // until one is chosen, so find out before anything else fails.
+    match cmd.probe() {
+        Ok(chips) => {
+            let names: Vec<String> = chips
+                .iter()
+                .map(|c| format!("{} {} ({} kB)", c.vendor, c.name, c.size / 1024))
+                .collect();
+            info!("Chip definitions matching the flash: {}", names.join(", "));
+            check_chip_support(cmd, &chips);
+            if chips.len() > 1 {
+                if let Err(e) = cmd.name() {
+                    return Err(format!(
+                        "Several chip definitions match the flash, choose one with --chip: \
+                         {}\n{}",
+                        names.join(", "),
+                        e
+                    )
+                    .into());
+                }
+            }
         }
+        Err(e) if e.kind() == ErrorKind::Unsupported => warn!("Cannot probe for chips: {}", e),
+        Err(e) => return Err(e.into()),
     }
 
-    info!(
-        "Record crossystem information.\n{}",
-        utils::collect_crosssystem()?
-    );
+    let rom_sz: i64 = cmd.get_size()?;
+    // An erased flash would make a successful erase indistinguishable from an
+    // unmodified one.
+    if emulated && !dry_run && cmd.read_to_vec()?.iter().all(|&b| b == 0xff) {
+        info!("Filling emulated flash with random data");
+        cmd.write_from_slice(&rand_util::gen_rand_data(rom_sz as usize))?;
+    }
+
+    if !emulated && !dry_run {
+        info!(
+            "Record crossystem information.\n{}",
+            utils::collect_crosssystem()?
+        );
+    }
 
     // Register tests to run:
     let tests: &[&dyn TestCase] = &[
@@ -128,16 +188,28 @@ pub fn generic<'a, TN: Iterator<Item = &'a str>>(
             partial_lock_test(LayoutNames::BottomHalf),
         ),
         &("Lock_top_half", partial_lock_test(LayoutNames::TopHalf)),
+        &("Lock_WP_RO", wp_ro_lock_test),
     ];
 
+    let mut env = TestEnv::create(cmd, hw)?;
+    if print_layout {
+        info!(
+            "Dumping layout file as requested:\n{}",
+            std::fs::read_to_string(env.layout_file())?
+        );
+    }
+    let range_tests = range_lock_tests(env.wp_ranges());
+    let tests: Vec<&dyn TestCase> = tests
+        .iter()
+        .copied()
+        .chain(range_tests.iter().map(|t| t as &dyn TestCase))
+        .collect();
+
     // Limit the tests to only those requested, unless none are requested
     // in which case all tests are included.
-    let mut filter_names: Option<HashSet<String>> = if let Some(names) = test_names {
-        Some(names.map(|s| s.to_lowercase()).collect())
-    } else {
-        None
-    };
-    let tests = filter_tests(tests, &mut filter_names);
+    let mut filter_names: Option<HashSet<String>> =
+        test_names.map(|names| names.map(|s| s.to_lowercase()).collect());
+    let tests = filter_tests(&tests, &mut filter_names);
 
     let chip_name = cmd
         .name()
@@ -146,7 +218,9 @@ pub fn generic<'a, TN: Iterator<Item = &'a str>>(
 
     // ------------------------.
     // Run all the tests and collate the findings:
-    let results = tester::run_all_tests(fc, cmd, tests, terminate_flag);
+    let results = tester::run_all_tests(&mut env, tests, terminate_flag);
+    // Restore the flash before reporting.
+    drop(env);
 
     // Any leftover filtered names were specified to be run but don't exist
     for leftover in filter_names.iter().flatten() {
@@ -158,15 +232,21 @@ pub fn generic<'a, TN: Iterator<Item = &'a str>>(
     let bios_info = cros_sysinfo::bios_info().unwrap_or("<Unknown BIOS>".to_string());
 
     let meta_data = tester::ReportMetaData {
-        chip_name: chip_name,
+        chip_name,
         os_release: os_rel,
-        system_info: system_info,
-        bios_info: bios_info,
+        system_info,
+        bios_info,
     };
     tester::collate_all_test_runs(&results, meta_data, output_format);
     Ok(())
 }
 
+/// What tests which change write protection need of flashrom.
+const NEEDS_WP: Capabilities = Capabilities {
+    wp: true,
+    ..Capabilities::NONE
+};
+
 fn get_device_name_test(env: &mut TestEnv) -> TestResult {
     // Success means we got something back, which is good enough.
     env.cmd.name()?;
@@ -174,11 +254,19 @@ fn get_device_name_test(env: &mut TestEnv) -> TestResult {
 }
 
 fn wp_toggle_test(env: &mut TestEnv) -> TestResult {
+    env.require(NEEDS_WP)?;
     // NOTE: This is not strictly a 'test' as it is allowed to fail on some platforms.
     //       However, we will warn when it does fail.
     // List the write-protected regions of flash.
     match env.cmd.wp_list() {
-        Ok(list_str) => info!("\n{}", list_str),
+        Ok(ranges) => {
+            for r in ranges {
+                info!("Protection range: start={:#x} len={:#x}", r.start, r.len);
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::Unsupported => {
+            warn!("Listing protection ranges is not supported: {}", e)
+        }
         Err(e) => warn!("{}", e),
     };
     // Fails if unable to set either one
@@ -188,6 +276,7 @@ fn wp_toggle_test(env: &mut TestEnv) -> TestResult {
 }
 
 fn erase_write_test(env: &mut TestEnv) -> TestResult {
+    env.require(NEEDS_WP)?;
     if !env.is_golden() {
         info!("Memory has been modified; reflashing to ensure erasure can be detected");
         env.ensure_golden()?;
@@ -195,12 +284,15 @@ fn erase_write_test(env: &mut TestEnv) -> TestResult {
 
     // With write protect enabled erase should fail.
     env.wp.set_sw(true)?.set_hw(true)?;
-    if env.erase().is_ok() {
-        info!("Flashrom returned Ok but this may be incorrect; verifying");
-        if !env.is_golden() {
-            return Err("Hardware write protect asserted however can still erase!".into());
+    match env.erase() {
+        Ok(()) => {
+            info!("Flashrom returned Ok but this may be incorrect; verifying");
+            if !env.is_golden() {
+                return Err("Hardware write protect asserted however can still erase!".into());
+            }
+            info!("Erase claimed to succeed but verify is Ok; assume erase failed");
         }
-        info!("Erase claimed to succeed but verify is Ok; assume erase failed");
+        Err(e) => expect_refused(e)?,
     }
 
     // With write protect disabled erase should succeed.
@@ -214,33 +306,48 @@ fn erase_write_test(env: &mut TestEnv) -> TestResult {
 }
 
 fn lock_test(env: &mut TestEnv) -> TestResult {
+    env.require(NEEDS_WP)?;
     if !env.wp.can_control_hw_wp() {
         return Err("Lock test requires ability to control hardware write protect".into());
     }
 
     env.wp.set_hw(false)?.set_sw(true)?;
+    // Enabling software WP should protect the whole chip.
+    let status = env.cmd.get_wp_status()?;
+    let rom_sz = env.cmd.get_size()?;
+    if status.range != (0, rom_sz) {
+        return Err(format!(
+            "Software WP should protect the whole chip, but status is {:#x?}",
+            status
+        )
+        .into());
+    }
     // Toggling software WP off should work when hardware is off.
     // Then enable again for another go.
     env.wp.push().set_sw(false)?;
 
     env.wp.set_hw(true)?;
     // Clearing should fail when hardware is enabled
-    if env.wp.set_sw(false).is_ok() {
-        return Err("Software WP was reset despite hardware WP being enabled".into());
+    match env.wp.set_sw(false) {
+        Ok(_) => Err("Software WP was reset despite hardware WP being enabled".into()),
+        Err(e) => expect_refused(e),
     }
-    Ok(())
 }
 
 fn elog_sanity_test(env: &mut TestEnv) -> TestResult {
     // Check that the elog contains *something*, as an indication that Coreboot
     // is actually able to write to the Flash. Because this invokes elogtool on
     // the host, it doesn't make sense to run for other chips.
-    if env.chip_type() != FlashChip::HOST {
+    if !env.caps().host {
         info!("Skipping ELOG sanity check for non-host chip");
         return Ok(());
     }
     // elogtool reads the flash, it should be back in the golden state
     env.ensure_golden()?;
+    if flashrom::dry_run() {
+        info!("Dry run: would list ELOG events with elogtool");
+        return Ok(());
+    }
     // Output is one event per line, drop empty lines in the interest of being defensive.
     let event_count = cros_sysinfo::eventlog_list()?
         .lines()
@@ -254,10 +361,14 @@ fn elog_sanity_test(env: &mut TestEnv) -> TestResult {
     }
 }
 
-fn host_is_chrome_test(_env: &mut TestEnv) -> TestResult {
+fn host_is_chrome_test(env: &mut TestEnv) -> TestResult {
+    if env.caps().emulated {
+        info!("Skipping host OS check for emulated chip");
+        return Ok(());
+    }
     let release_info = if let Ok(f) = File::open("/etc/os-release") {
         let buf = std::io::BufReader::new(f);
-        parse_os_release(buf.lines().flatten())
+        parse_os_release(buf.lines().map_while(Result::ok))
     } else {
         info!("Unable to read /etc/os-release to probe system information");
         HashMap::new()
@@ -281,6 +392,7 @@ fn host_is_chrome_test(_env: &mut TestEnv) -> TestResult {
 
 fn partial_lock_test(section: LayoutNames) -> impl Fn(&mut TestEnv) -> TestResult {
     move |env: &mut TestEnv| {
+        env.require(NEEDS_WP)?;
         // Need a clean image for verification
         env.ensure_golden()?;
 
@@ -288,22 +400,25 @@ fn partial_lock_test(section: LayoutNames) -> impl Fn(&mut TestEnv) -> TestResul
         // Disable software WP so we can do range protection, but hardware WP
         // must remain enabled for (most) range protection to do anything.
         env.wp.set_hw(false)?.set_sw(false)?;
-        env.cmd.wp_range((start, len), true)?;
+        env.wp.set_range((start, len))?;
         env.wp.set_hw(true)?;
 
         // Check that we cannot write to the protected region.
         let rws = flashrom::ROMWriteSpecifics {
-            layout_file: Some(LAYOUT_FILE),
+            layout: Some(LayoutSource::File(env.layout_file())),
             write_file: Some(env.random_data_file()),
-            name_file: Some(wp_section_name),
+            regions: &[RegionFile::new(wp_section_name)],
         };
-        if env.cmd.write_file_with_layout(&rws).is_ok() {
-            return Err(
-                "Section should be locked, should not have been overwritable with random data"
-                    .into(),
-            );
+        match env.cmd.write_file_with_layout(&rws) {
+            Ok(_) => {
+                return Err(
+                    "Section should be locked, should not have been overwritable with random data"
+                        .into(),
+                )
+            }
+            Err(e) => expect_refused(e)?,
         }
-        if !env.is_golden() {
+        if !env.region_is_golden(LayoutSource::File(env.layout_file()), wp_section_name)? {
             return Err("Section didn't lock, has been overwritten with random data!".into());
         }
 
@@ -311,9 +426,9 @@ fn partial_lock_test(section: LayoutNames) -> impl Fn(&mut TestEnv) -> TestResul
         let (non_wp_section_name, _, _) =
             utils::layout_section(env.layout(), section.get_non_overlapping_section());
         let rws = flashrom::ROMWriteSpecifics {
-            layout_file: Some(LAYOUT_FILE),
+            layout: Some(LayoutSource::File(env.layout_file())),
             write_file: Some(env.random_data_file()),
-            name_file: Some(non_wp_section_name),
+            regions: &[RegionFile::new(non_wp_section_name)],
         };
         env.cmd.write_file_with_layout(&rws)?;
 
@@ -321,6 +436,133 @@ fn partial_lock_test(section: LayoutNames) -> impl Fn(&mut TestEnv) -> TestResul
     }
 }
 
+/// Protect the FMAP's WP_RO region by name, as firmware updaters do, and
+/// check that it cannot be written.
+fn wp_ro_lock_test(env: &mut TestEnv) -> TestResult {
+    env.require(Capabilities {
+        wp: true,
+        wp_region: true,
+        fmap: true,
+        ..Capabilities::NONE
+    })?;
+    // Need a clean image for verification
+    env.ensure_golden()?;
+
+    match env.cmd.layout_regions(LayoutSource::Fmap) {
+        Ok(regions) if regions.iter().any(|r| r.name == "WP_RO") => {}
+        Ok(_) => return Err(tester::Skipped("The FMAP has no WP_RO region".into()).into()),
+        Err(e @ FlashromError::Parse(_)) => {
+            return Err(tester::Skipped(format!("The flash has no FMAP: {}", e)).into())
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    env.wp.set_hw(false)?.set_sw(false)?;
+    env.wp.set_region("WP_RO", LayoutSource::Fmap)?;
+    env.wp.set_hw(true)?;
+
+    let rws = flashrom::ROMWriteSpecifics {
+        layout: Some(LayoutSource::Fmap),
+        write_file: Some(env.random_data_file()),
+        regions: &[RegionFile::new("WP_RO")],
+    };
+    match env.cmd.write_file_with_layout(&rws) {
+        Ok(_) => return Err("WP_RO should be locked, but was written".into()),
+        Err(e) => expect_refused(e)?,
+    }
+    if !env.region_is_golden(LayoutSource::Fmap, "WP_RO")? {
+        return Err("WP_RO didn't lock, has been overwritten with random data!".into());
+    }
+
+    Ok(())
+}
+
+/// Check that a failed operation was refused by the flash rather than failing
+/// to reach it at all, returning `e` if flashrom could not access the flash or
+/// did not finish.
+///
+/// Protected writes and erases fail in ways that cannot be told apart from
+/// other faults, so whether the flash was modified must be checked afterwards.
+fn expect_refused(e: FlashromError) -> TestResult {
+    let unreachable = matches!(
+        e,
+        FlashromError::Spawn { .. } | FlashromError::Signal { .. }
+    ) || matches!(
+        e.kind(),
+        ErrorKind::ProgrammerInit
+            | ErrorKind::ChipNotFound
+            | ErrorKind::Timeout
+            | ErrorKind::Cancelled
+    );
+    if unreachable {
+        return Err(e.into());
+    }
+    info!("Operation was refused as expected: {}", e);
+    Ok(())
+}
+
+/// Create a lock test for every range in `ranges`.
+fn range_lock_tests(ranges: &[WpRange]) -> Vec<(String, impl Fn(&mut TestEnv) -> TestResult)> {
+    let mut tests: Vec<_> = ranges
+        .iter()
+        // Nothing can be locked in an empty range or with protection disabled.
+        .filter(|r| r.len > 0 && r.mode != Some(WpMode::Disabled))
+        .map(|r| {
+            (
+                format!("Lock_range_{:#x}_{:#x}", r.start, r.len),
+                range_lock_test((r.start, r.len)),
+            )
+        })
+        .collect();
+    tests.dedup_by(|a, b| a.0 == b.0);
+    tests
+}
+
+fn range_lock_test(range: (i64, i64)) -> impl Fn(&mut TestEnv) -> TestResult {
+    move |env: &mut TestEnv| {
+        env.require(NEEDS_WP)?;
+        // Need a clean image for verification
+        env.ensure_golden()?;
+
+        let layout = TempFile::new("range_layout")?;
+        let layout_file = layout.path();
+        let unprotected = {
+            let f = File::create(layout_file)?;
+            utils::construct_range_layout_file(f, range, env.cmd.get_size()?)?
+        };
+
+        env.wp.set_hw(false)?.set_sw(false)?;
+        env.wp.set_range(range)?;
+        env.wp.set_hw(true)?;
+
+        // Check that we cannot write to the protected range.
+        let rws = flashrom::ROMWriteSpecifics {
+            layout: Some(LayoutSource::File(layout_file)),
+            write_file: Some(env.random_data_file()),
+            regions: &[RegionFile::new("PROTECTED")],
+        };
+        match env.cmd.write_file_with_layout(&rws) {
+            Ok(_) => return Err("Range should be locked, but was written".into()),
+            Err(e) => expect_refused(e)?,
+        }
+        if !env.region_is_golden(LayoutSource::File(layout_file), "PROTECTED")? {
+            return Err("Range didn't lock, has been overwritten with random data!".into());
+        }
+
+        // Check that we can write everywhere else.
+        for name in unprotected {
+            let rws = flashrom::ROMWriteSpecifics {
+                layout: Some(LayoutSource::File(layout_file)),
+                write_file: Some(env.random_data_file()),
+                regions: &[RegionFile::new(name)],
+            };
+            env.cmd.write_file_with_layout(&rws)?;
+        }
+
+        Ok(())
+    }
+}
+
 fn verify_fail_test(env: &mut TestEnv) -> TestResult {
     // Comparing the flash contents to random data says they're not the same.
     match env.verify(env.random_data_file()) {
@@ -366,7 +608,7 @@ fn test_parse_os_release() {
     ];
     let map = parse_os_release(lines.iter().map(|&s| s.to_owned()));
 
-    fn get<'a, 'b>(m: &'a HashMap<String, String>, k: &'b str) -> Option<&'a str> {
+    fn get<'a>(m: &'a HashMap<String, String>, k: &str) -> Option<&'a str> {
         m.get(k).map(|s| s.as_ref())
     }
 
@@ -396,3 +638,224 @@ fn test_name_filter() {
     // Name got left behind because no test matched it
     assert_eq!(names.unwrap().len(), 1);
 }
+
+/// Set up a test environment for `mock`.
+#[cfg(test)]
+fn mock_env(mock: &dyn Flashrom) -> TestEnv<'_> {
+    TestEnv::create(mock, &super::hwwp::Dummy).unwrap()
+}
+
+#[cfg(test)]
+fn mock_contents() -> Vec<u8> {
+    (0..0x10000).map(|i| i as u8).collect()
+}
+
+#[test]
+fn test_erase_write() {
+    use flashrom::{Fault, MockFlashrom, MockOp};
+
+    let mock = MockFlashrom::new(mock_contents());
+    {
+        let mut env = mock_env(&mock);
+        erase_write_test(&mut env).unwrap();
+
+        // An erase which doesn't erase is caught.
+        mock.inject(MockOp::Erase, Fault::Ignore);
+        assert!(erase_write_test(&mut env).is_err());
+        mock.clear_faults();
+    }
+    // The flash is restored when testing ends.
+    assert_eq!(mock.contents(), mock_contents());
+}
+
+#[test]
+fn test_lock() {
+    use flashrom::{Fault, MockFlashrom, MockOp};
+
+    let mock = MockFlashrom::new(mock_contents());
+    let mut env = mock_env(&mock);
+    lock_test(&mut env).unwrap();
+
+    // Failing to reach the flash isn't the refusal the test expects.
+    env.wp.set_hw(false).unwrap().set_sw(false).unwrap();
+    mock.inject(MockOp::WpWrite, Fault::Fail(ErrorKind::ProgrammerInit));
+    assert!(lock_test(&mut env).is_err());
+    mock.clear_faults();
+}
+
+#[test]
+fn test_expect_refused() {
+    use flashrom::{Fault, MockFlashrom, MockOp};
+
+    let mock = MockFlashrom::new(mock_contents());
+    // A protected chip may fail in any way once flashrom reaches it.
+    for kind in [ErrorKind::WriteProtected, ErrorKind::Other] {
+        mock.inject(MockOp::Erase, Fault::Fail(kind));
+        assert!(expect_refused(mock.erase().unwrap_err()).is_ok());
+        mock.clear_faults();
+    }
+
+    // Not reaching the flash, or not finishing, isn't a refusal.
+    for kind in [
+        ErrorKind::ProgrammerInit,
+        ErrorKind::ChipNotFound,
+        ErrorKind::Timeout,
+        ErrorKind::Cancelled,
+    ] {
+        mock.inject(MockOp::Erase, Fault::Fail(kind));
+        assert!(expect_refused(mock.erase().unwrap_err()).is_err());
+        mock.clear_faults();
+    }
+}
+
+#[test]
+fn test_skip_unsupported() {
+    use flashrom::{FlashromVersion, MockFlashrom};
+
+    // flashrom only gained write protection in v1.3.
+    let version = FlashromVersion::parse("flashrom v1.2 on Linux");
+    let mock =
+        MockFlashrom::new(mock_contents()).with_capabilities(Capabilities::of_version(version));
+    let mut env = mock_env(&mock);
+
+    let err = lock_test(&mut env).unwrap_err();
+    assert!(err.is::<tester::Skipped>());
+    assert_eq!(
+        err.to_string(),
+        "flashrom v1.2.0 does not support write protection"
+    );
+    get_device_name_test(&mut env).unwrap();
+    verify_fail_test(&mut env).unwrap();
+}
+
+/// Mock contents with an FMAP whose WP_RO region is the bottom half.
+#[cfg(test)]
+fn mock_fmap_contents() -> Vec<u8> {
+    fn name(s: &str) -> Vec<u8> {
+        let mut out = s.as_bytes().to_vec();
+        out.resize(32, 0);
+        out
+    }
+
+    let mut fmap = b"__FMAP__\x01\x01".to_vec();
+    fmap.extend_from_slice(&0u64.to_le_bytes());
+    fmap.extend_from_slice(&0x10000u32.to_le_bytes());
+    fmap.extend(name("FLASH"));
+    fmap.extend_from_slice(&2u16.to_le_bytes());
+    for (offset, size, area) in [(0u32, 0x8000u32, "WP_RO"), (0x8000, 0x8000, "RW")] {
+        fmap.extend_from_slice(&offset.to_le_bytes());
+        fmap.extend_from_slice(&size.to_le_bytes());
+        fmap.extend(name(area));
+        fmap.extend_from_slice(&0u16.to_le_bytes());
+    }
+
+    let mut contents = mock_contents();
+    contents[0x100..0x100 + fmap.len()].copy_from_slice(&fmap);
+    contents
+}
+
+#[test]
+fn test_wp_ro_lock() {
+    use flashrom::{Fault, FlashromVersion, MockFlashrom, MockOp};
+
+    let mock = MockFlashrom::new(mock_fmap_contents());
+    let mut env = mock_env(&mock);
+    wp_ro_lock_test(&mut env).unwrap();
+
+    // A lock which doesn't protect the region is caught.
+    env.wp.set_hw(false).unwrap().set_sw(false).unwrap();
+    mock.inject(MockOp::WpWrite, Fault::Ignore);
+    assert!(wp_ro_lock_test(&mut env).is_err());
+    mock.clear_faults();
+    drop(env);
+    drop(mock);
+
+    // Without an FMAP or a flashrom which can protect regions, it is skipped.
+    let mock = MockFlashrom::new(mock_contents());
+    let mut env = mock_env(&mock);
+    assert!(wp_ro_lock_test(&mut env)
+        .unwrap_err()
+        .is::<tester::Skipped>());
+    drop(env);
+    drop(mock);
+
+    let version = FlashromVersion::parse("flashrom v1.3.0 on Linux");
+    let mock = MockFlashrom::new(mock_fmap_contents())
+        .with_capabilities(Capabilities::of_version(version));
+    let mut env = mock_env(&mock);
+    let err = wp_ro_lock_test(&mut env).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "flashrom v1.3.0 does not support --wp-region"
+    );
+}
+
+#[test]
+fn test_partial_lock() {
+    use flashrom::{Fault, MockFlashrom, MockOp};
+
+    let mock = MockFlashrom::new(mock_contents());
+    let mut env = mock_env(&mock);
+    for section in [
+        LayoutNames::TopQuad,
+        LayoutNames::BottomQuad,
+        LayoutNames::TopHalf,
+        LayoutNames::BottomHalf,
+    ] {
+        partial_lock_test(section)(&mut env).unwrap();
+    }
+
+    // A lock which doesn't protect anything is caught.
+    mock.inject(MockOp::WpWrite, Fault::Ignore);
+    assert!(partial_lock_test(LayoutNames::TopQuad)(&mut env).is_err());
+    mock.clear_faults();
+}
+
+#[test]
+fn test_range_lock() {
+    use flashrom::{Fault, MockFlashrom, MockOp};
+
+    let mock = MockFlashrom::new(mock_contents());
+    let mut env = mock_env(&mock);
+    // The ranges were listed when the environment was set up.
+    let tests = range_lock_tests(env.wp_ranges());
+    let names: Vec<&str> = tests.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "Lock_range_0x0_0x10000",
+            "Lock_range_0x0_0x8000",
+            "Lock_range_0x8000_0x8000",
+            "Lock_range_0x0_0x4000",
+            "Lock_range_0xc000_0x4000",
+        ]
+    );
+    for (_, test) in &tests {
+        test(&mut env).unwrap();
+    }
+
+    // A lock which doesn't protect anything is caught.
+    mock.inject(MockOp::WpWrite, Fault::Ignore);
+    assert!(tests[1].1(&mut env).is_err());
+    mock.clear_faults();
+}
+
+#[test]
+fn test_replay() {
+    use crate::trace::{Recorder, Replayer};
+    use flashrom::{MockFlashrom, TempFile};
+
+    // The tester makes the same decisions from a trace as on the flash.
+    let trace = TempFile::new("trace").unwrap();
+    let mock = MockFlashrom::new(mock_contents());
+    let recorder = Recorder::create(Box::new(mock), trace.path()).unwrap();
+    erase_write_test(&mut mock_env(&recorder)).unwrap();
+    let _mock = recorder.into_inner();
+
+    let replayer = Replayer::open(trace.path()).unwrap();
+    erase_write_test(&mut mock_env(&replayer)).unwrap();
+    // Nothing is left over.
+    assert!(replayer.get_size().is_err());
+
+    std::fs::remove_dir_all(format!("{}.files", trace.path().display())).unwrap();
+}
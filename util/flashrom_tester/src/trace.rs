@@ -0,0 +1,876 @@
+//
+// Copyright 2019, Google Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//    * Redistributions of source code must retain the above copyright
+// notice, this list of conditions and the following disclaimer.
+//    * Redistributions in binary form must reproduce the above
+// copyright notice, this list of conditions and the following disclaimer
+// in the documentation and/or other materials provided with the
+// distribution.
+//    * Neither the name of Google Inc. nor the names of its
+// contributors may be used to endorse or promote products derived from
+// this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Alternatively, this software may be distributed under the terms of the
+// GNU General Public License ("GPL") version 2 as published by the Free
+// Software Foundation.
+//
+
+//! Recording calls to a `Flashrom` and replaying them later.
+//!
+//! A trace is a file of JSON lines: a header naming the programmer and the
+//! capabilities of flashrom, then one
+//! line per call with its arguments, its result or error and the SHA-256 of
+//! each file it read or wrote. Files written by calls, such as images read
+//! from the flash, are stored by hash in a directory next to the trace so
+//! they can be replayed.
+
+use flashrom::{
+    Capabilities, ChipDatabase, ChipInfo, Flashrom, FlashromError, FlashromVersion, LayoutSource,
+    Programmer, ProgressCallback, ROMWriteSpecifics, WpMode, WpRange, WpStatus,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const TRACE_VERSION: u64 = 1;
+
+/// The directory holding files written by the calls in the trace at `path`.
+fn files_dir(path: &Path) -> PathBuf {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".files");
+    dir.into()
+}
+
+/// Hash the file at `path`, returning its hash in hex.
+fn hash_file(path: &Path) -> io::Result<String> {
+    let digest = Sha256::digest(fs::read(path)?);
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Records every call to a `Flashrom` in a trace, which a `Replayer` can
+/// play back.
+pub struct Recorder {
+    inner: Box<dyn Flashrom>,
+    trace: Mutex<File>,
+    files_dir: PathBuf,
+}
+
+impl Recorder {
+    /// Record calls to `inner` in a new trace at `path`.
+    pub fn create(inner: Box<dyn Flashrom>, path: &Path) -> Result<Self, FlashromError> {
+        let files_dir = files_dir(path);
+        fs::create_dir_all(&files_dir)?;
+        let mut trace = File::create(path)?;
+        let header = json!({
+            "version": TRACE_VERSION,
+            "programmer": inner.programmer().to_string(),
+            "capabilities": encode_capabilities(&inner.capabilities()),
+        });
+        writeln!(trace, "{}", header)?;
+
+        Ok(Recorder {
+            inner,
+            trace: Mutex::new(trace),
+            files_dir,
+        })
+    }
+
+    /// Stop recording, returning the `Flashrom` calls were made to.
+    pub fn into_inner(self) -> Box<dyn Flashrom> {
+        self.inner
+    }
+
+    /// Call `f` on the inner `Flashrom` and record the call, returning its result.
+    ///
+    /// `inputs` are the files `f` reads and `outputs` the files it writes.
+    fn call<T, F, E>(
+        &self,
+        method: &str,
+        args: Value,
+        (inputs, outputs): (&[&Path], &[&Path]),
+        encode: E,
+        f: F,
+    ) -> Result<T, FlashromError>
+    where
+        F: FnOnce(&dyn Flashrom) -> Result<T, FlashromError>,
+        E: FnOnce(&T) -> Value,
+    {
+        let inputs: Vec<Value> = inputs.iter().map(|&p| json!(hash_file(p).ok())).collect();
+        let result = f(self.inner.as_ref());
+        let (result_value, outputs) = match &result {
+            Ok(value) => {
+                let outputs: Vec<Value> =
+                    outputs.iter().map(|&p| json!(self.store(p).ok())).collect();
+                (json!({ "ok": encode(value) }), outputs)
+            }
+            Err(e) => (json!({ "err": encode_error(e) }), Vec::new()),
+        };
+        let entry = json!({
+            "method": method,
+            "args": args,
+            "inputs": inputs,
+            "outputs": outputs,
+            "result": result_value,
+        });
+
+        let mut trace = self.trace.lock().expect("trace lock poisoned");
+        if let Err(e) = writeln!(trace, "{}", entry).and_then(|_| trace.flush()) {
+            error!("Failed to record {}() in trace: {}", method, e);
+        }
+        result
+    }
+
+    /// Keep a copy of the file at `path`, returning its hash.
+    fn store(&self, path: &Path) -> io::Result<String> {
+        let hash = hash_file(path)?;
+        let copy = self.files_dir.join(&hash);
+        if !copy.exists() {
+            fs::copy(path, copy)?;
+        }
+        Ok(hash)
+    }
+}
+
+impl Flashrom for Recorder {
+    fn get_size(&self) -> Result<i64, FlashromError> {
+        self.call(
+            "get_size",
+            json!({}),
+            (&[], &[]),
+            |v| json!(v),
+            |f| f.get_size(),
+        )
+    }
+
+    fn name(&self) -> Result<(String, String), FlashromError> {
+        self.call("name", json!({}), (&[], &[]), |v| json!(v), |f| f.name())
+    }
+
+    fn probe(&self) -> Result<Vec<ChipInfo>, FlashromError> {
+        let encode = |chips: &Vec<ChipInfo>| chips.iter().map(encode_chip).collect();
+        self.call("probe", json!({}), (&[], &[]), encode, |f| f.probe())
+    }
+
+    fn write_file_with_layout(&self, rws: &ROMWriteSpecifics) -> Result<bool, FlashromError> {
+        let args = json!({
+            "layout": rws.layout.map(encode_layout),
+            "write_file": rws.write_file.is_some(),
+            "regions": rws
+                .regions
+                .iter()
+                .map(|r| json!({ "name": r.name, "file": r.file.is_some() }))
+                .collect::<Vec<_>>(),
+        });
+        let inputs: Vec<&Path> = rws
+            .write_file
+            .into_iter()
+            .chain(rws.regions.iter().filter_map(|r| r.file))
+            .collect();
+        self.call(
+            "write_file_with_layout",
+            args,
+            (&inputs, &[]),
+            |v| json!(v),
+            |f| f.write_file_with_layout(rws),
+        )
+    }
+
+    fn wp_range(&self, range: (i64, i64), wp_enable: bool) -> Result<bool, FlashromError> {
+        let args = json!({ "range": range, "wp_enable": wp_enable });
+        self.call(
+            "wp_range",
+            args,
+            (&[], &[]),
+            |v| json!(v),
+            |f| f.wp_range(range, wp_enable),
+        )
+    }
+
+    fn wp_region(
+        &self,
+        name: &str,
+        layout: LayoutSource,
+        wp_enable: bool,
+    ) -> Result<bool, FlashromError> {
+        let args = json!({
+            "name": name,
+            "layout": encode_layout(layout),
+            "wp_enable": wp_enable,
+        });
+        self.call(
+            "wp_region",
+            args,
+            (&[], &[]),
+            |v| json!(v),
+            |f| f.wp_region(name, layout, wp_enable),
+        )
+    }
+
+    fn wp_list(&self) -> Result<Vec<WpRange>, FlashromError> {
+        let encode = |ranges: &Vec<WpRange>| ranges.iter().map(encode_wp_range).collect();
+        self.call("wp_list", json!({}), (&[], &[]), encode, |f| f.wp_list())
+    }
+
+    fn get_wp_status(&self) -> Result<WpStatus, FlashromError> {
+        self.call(
+            "get_wp_status",
+            json!({}),
+            (&[], &[]),
+            encode_wp_status,
+            |f| f.get_wp_status(),
+        )
+    }
+
+    fn wp_toggle(&self, en: bool) -> Result<bool, FlashromError> {
+        self.call(
+            "wp_toggle",
+            json!({ "en": en }),
+            (&[], &[]),
+            |v| json!(v),
+            |f| f.wp_toggle(en),
+        )
+    }
+
+    fn read_region(
+        &self,
+        layout: LayoutSource,
+        regions: &[&str],
+        path: &Path,
+    ) -> Result<(), FlashromError> {
+        let args = json!({ "layout": encode_layout(layout), "regions": regions });
+        self.call(
+            "read_region",
+            args,
+            (&[], &[path]),
+            |_| json!(null),
+            |f| f.read_region(layout, regions, path),
+        )
+    }
+
+    fn erase_region(&self, layout: LayoutSource, regions: &[&str]) -> Result<(), FlashromError> {
+        let args = json!({ "layout": encode_layout(layout), "regions": regions });
+        self.call(
+            "erase_region",
+            args,
+            (&[], &[]),
+            |_| json!(null),
+            |f| f.erase_region(layout, regions),
+        )
+    }
+
+    fn read(&self, path: &Path) -> Result<(), FlashromError> {
+        self.call(
+            "read",
+            json!({}),
+            (&[], &[path]),
+            |_| json!(null),
+            |f| f.read(path),
+        )
+    }
+
+    fn write(&self, path: &Path) -> Result<(), FlashromError> {
+        self.call(
+            "write",
+            json!({}),
+            (&[path], &[]),
+            |_| json!(null),
+            |f| f.write(path),
+        )
+    }
+
+    fn verify(&self, path: &Path) -> Result<(), FlashromError> {
+        self.call(
+            "verify",
+            json!({}),
+            (&[path], &[]),
+            |_| json!(null),
+            |f| f.verify(path),
+        )
+    }
+
+    fn erase(&self) -> Result<(), FlashromError> {
+        self.call(
+            "erase",
+            json!({}),
+            (&[], &[]),
+            |_| json!(null),
+            |f| f.erase(),
+        )
+    }
+
+    fn programmer(&self) -> &Programmer {
+        self.inner.programmer()
+    }
+
+    fn emulated_hw_wp(&self) -> Option<bool> {
+        self.inner.emulated_hw_wp()
+    }
+
+    fn set_emulated_hw_wp(&self, en: bool) -> Result<(), FlashromError> {
+        self.inner.set_emulated_hw_wp(en)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn supported_chips(&self) -> Result<ChipDatabase, FlashromError> {
+        self.inner.supported_chips()
+    }
+
+    fn set_progress(&mut self, callback: ProgressCallback) {
+        self.inner.set_progress(callback)
+    }
+}
+
+/// Plays back the calls in a trace made by a `Recorder`, in the order they
+/// were recorded, without touching any flash.
+///
+/// A call other than the next one in the trace fails. Files read by a call
+/// which differ from those recorded are only warned about, since they are
+/// usually temporary or random.
+pub struct Replayer {
+    programmer: Programmer,
+    capabilities: Capabilities,
+    calls: Mutex<VecDeque<Value>>,
+    files_dir: PathBuf,
+    /// The emulated hardware write protect, which isn't recorded.
+    hw_wp: AtomicBool,
+}
+
+impl Replayer {
+    /// Play back the trace at `path`.
+    pub fn open(path: &Path) -> Result<Self, FlashromError> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header: Value = match lines.next() {
+            Some(line) => serde_json::from_str(&line?).map_err(malformed)?,
+            None => return Err(FlashromError::Parse("Empty trace".into())),
+        };
+        if header["version"] != TRACE_VERSION {
+            return Err(FlashromError::Parse(format!(
+                "Unsupported trace version {}",
+                header["version"]
+            )));
+        }
+        let programmer = header["programmer"]
+            .as_str()
+            .ok_or_else(|| FlashromError::Parse("Trace names no programmer".into()))?
+            .parse()?;
+        // Traces from before capabilities were recorded had them all.
+        let capabilities =
+            decode_capabilities(&header["capabilities"]).unwrap_or(Capabilities::ALL);
+
+        let calls = lines
+            .map(|line| serde_json::from_str(&line?).map_err(malformed))
+            .collect::<Result<_, FlashromError>>()?;
+
+        Ok(Replayer {
+            programmer,
+            capabilities,
+            calls: Mutex::new(calls),
+            files_dir: files_dir(path),
+            hw_wp: AtomicBool::new(false),
+        })
+    }
+
+    /// Play back the next call, which must be to `method` with `args`.
+    ///
+    /// `inputs` are the files the call reads and `outputs` the files it writes.
+    fn replay<T, D>(
+        &self,
+        method: &str,
+        args: Value,
+        (inputs, outputs): (&[&Path], &[&Path]),
+        decode: D,
+    ) -> Result<T, FlashromError>
+    where
+        D: FnOnce(&Value) -> Option<T>,
+    {
+        let call = self
+            .calls
+            .lock()
+            .expect("trace lock poisoned")
+            .pop_front()
+            .ok_or_else(|| format!("Replayed trace has no call for {}()", method))?;
+        if call["method"] != method || call["args"] != args {
+            return Err(format!(
+                "Replay diverged from trace: {}({}) was called but {}({}) was recorded",
+                method, args, call["method"], call["args"]
+            )
+            .into());
+        }
+
+        for (&path, recorded) in inputs
+            .iter()
+            .zip(call["inputs"].as_array().into_iter().flatten())
+        {
+            if hash_file(path).ok().as_deref() != recorded.as_str() {
+                warn!(
+                    "{} read by {}() differs from the recorded file",
+                    path.display(),
+                    method
+                );
+            }
+        }
+
+        let result = &call["result"];
+        if !result["err"].is_null() {
+            return Err(decode_error(&result["err"]).ok_or_else(|| malformed_entry(method))?);
+        }
+        let value = decode(&result["ok"]).ok_or_else(|| malformed_entry(method))?;
+        for (&path, hash) in outputs
+            .iter()
+            .zip(call["outputs"].as_array().into_iter().flatten())
+        {
+            let hash = hash
+                .as_str()
+                .ok_or_else(|| format!("Trace has no copy of the file written by {}()", method))?;
+            fs::copy(self.files_dir.join(hash), path)?;
+        }
+        Ok(value)
+    }
+}
+
+fn malformed(e: serde_json::Error) -> FlashromError {
+    FlashromError::Parse(format!("Malformed trace: {}", e))
+}
+
+fn malformed_entry(method: &str) -> FlashromError {
+    FlashromError::Parse(format!("Malformed trace entry for {}()", method))
+}
+
+fn decode_unit(v: &Value) -> Option<()> {
+    Some(()).filter(|_| v.is_null())
+}
+
+impl Flashrom for Replayer {
+    fn get_size(&self) -> Result<i64, FlashromError> {
+        self.replay("get_size", json!({}), (&[], &[]), Value::as_i64)
+    }
+
+    fn name(&self) -> Result<(String, String), FlashromError> {
+        self.replay("name", json!({}), (&[], &[]), |v| {
+            Some((v[0].as_str()?.into(), v[1].as_str()?.into()))
+        })
+    }
+
+    fn probe(&self) -> Result<Vec<ChipInfo>, FlashromError> {
+        self.replay("probe", json!({}), (&[], &[]), |v| {
+            v.as_array()?.iter().map(decode_chip).collect()
+        })
+    }
+
+    fn write_file_with_layout(&self, rws: &ROMWriteSpecifics) -> Result<bool, FlashromError> {
+        let args = json!({
+            "layout": rws.layout.map(encode_layout),
+            "write_file": rws.write_file.is_some(),
+            "regions": rws
+                .regions
+                .iter()
+                .map(|r| json!({ "name": r.name, "file": r.file.is_some() }))
+                .collect::<Vec<_>>(),
+        });
+        let inputs: Vec<&Path> = rws
+            .write_file
+            .into_iter()
+            .chain(rws.regions.iter().filter_map(|r| r.file))
+            .collect();
+        self.replay(
+            "write_file_with_layout",
+            args,
+            (&inputs, &[]),
+            Value::as_bool,
+        )
+    }
+
+    fn wp_range(&self, range: (i64, i64), wp_enable: bool) -> Result<bool, FlashromError> {
+        let args = json!({ "range": range, "wp_enable": wp_enable });
+        self.replay("wp_range", args, (&[], &[]), Value::as_bool)
+    }
+
+    fn wp_region(
+        &self,
+        name: &str,
+        layout: LayoutSource,
+        wp_enable: bool,
+    ) -> Result<bool, FlashromError> {
+        let args = json!({
+            "name": name,
+            "layout": encode_layout(layout),
+            "wp_enable": wp_enable,
+        });
+        self.replay("wp_region", args, (&[], &[]), Value::as_bool)
+    }
+
+    fn wp_list(&self) -> Result<Vec<WpRange>, FlashromError> {
+        self.replay("wp_list", json!({}), (&[], &[]), |v| {
+            v.as_array()?.iter().map(decode_wp_range).collect()
+        })
+    }
+
+    fn get_wp_status(&self) -> Result<WpStatus, FlashromError> {
+        self.replay("get_wp_status", json!({}), (&[], &[]), decode_wp_status)
+    }
+
+    fn wp_toggle(&self, en: bool) -> Result<bool, FlashromError> {
+        self.replay("wp_toggle", json!({ "en": en }), (&[], &[]), Value::as_bool)
+    }
+
+    fn read_region(
+        &self,
+        layout: LayoutSource,
+        regions: &[&str],
+        path: &Path,
+    ) -> Result<(), FlashromError> {
+        let args = json!({ "layout": encode_layout(layout), "regions": regions });
+        self.replay("read_region", args, (&[], &[path]), decode_unit)
+    }
+
+    fn erase_region(&self, layout: LayoutSource, regions: &[&str]) -> Result<(), FlashromError> {
+        let args = json!({ "layout": encode_layout(layout), "regions": regions });
+        self.replay("erase_region", args, (&[], &[]), decode_unit)
+    }
+
+    fn read(&self, path: &Path) -> Result<(), FlashromError> {
+        self.replay("read", json!({}), (&[], &[path]), decode_unit)
+    }
+
+    fn write(&self, path: &Path) -> Result<(), FlashromError> {
+        self.replay("write", json!({}), (&[path], &[]), decode_unit)
+    }
+
+    fn verify(&self, path: &Path) -> Result<(), FlashromError> {
+        self.replay("verify", json!({}), (&[path], &[]), decode_unit)
+    }
+
+    fn erase(&self) -> Result<(), FlashromError> {
+        self.replay("erase", json!({}), (&[], &[]), decode_unit)
+    }
+
+    fn programmer(&self) -> &Programmer {
+        &self.programmer
+    }
+
+    fn emulated_hw_wp(&self) -> Option<bool> {
+        if self.programmer.caps().emulated {
+            Some(self.hw_wp.load(Ordering::SeqCst))
+        } else {
+            None
+        }
+    }
+
+    fn set_emulated_hw_wp(&self, en: bool) -> Result<(), FlashromError> {
+        if self.emulated_hw_wp().is_none() {
+            return Err(FlashromError::Unsupported(
+                "the traced programmer has no emulated hardware write protect".into(),
+            ));
+        }
+        self.hw_wp.store(en, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn set_progress(&mut self, _callback: ProgressCallback) {}
+}
+
+fn encode_capabilities(caps: &Capabilities) -> Value {
+    let version = caps.version.map(|v| {
+        json!({
+            "major": v.major,
+            "minor": v.minor,
+            "patch": v.patch,
+            "chromeos": v.chromeos,
+        })
+    });
+    json!({
+        "version": version,
+        "flash_size": caps.flash_size,
+        "fmap": caps.fmap,
+        "wp": caps.wp,
+        "wp_modes": caps.wp_modes,
+        "wp_region": caps.wp_region,
+        "progress": caps.progress,
+    })
+}
+
+fn decode_capabilities(v: &Value) -> Option<Capabilities> {
+    let version = match &v["version"] {
+        Value::Null => None,
+        version => Some(FlashromVersion {
+            major: version["major"].as_u64()? as u32,
+            minor: version["minor"].as_u64()? as u32,
+            patch: version["patch"].as_u64()? as u32,
+            chromeos: version["chromeos"].as_bool()?,
+        }),
+    };
+    Some(Capabilities {
+        version,
+        flash_size: v["flash_size"].as_bool()?,
+        fmap: v["fmap"].as_bool()?,
+        wp: v["wp"].as_bool()?,
+        wp_modes: v["wp_modes"].as_bool()?,
+        wp_region: v["wp_region"].as_bool()?,
+        progress: v["progress"].as_bool()?,
+    })
+}
+
+fn encode_layout(source: LayoutSource) -> Value {
+    match source {
+        LayoutSource::File(path) => json!({ "file": path.to_string_lossy() }),
+        LayoutSource::Fmap => json!("fmap"),
+        LayoutSource::FmapFile(path) => json!({ "fmap_file": path.to_string_lossy() }),
+        LayoutSource::Ifd => json!("ifd"),
+    }
+}
+
+fn encode_chip(chip: &ChipInfo) -> Value {
+    json!({
+        "vendor": chip.vendor,
+        "name": chip.name,
+        "size": chip.size,
+        "id": chip.id,
+    })
+}
+
+fn decode_chip(v: &Value) -> Option<ChipInfo> {
+    let id = match &v["id"] {
+        Value::Null => None,
+        id => Some((id[0].as_u64()? as u32, id[1].as_u64()? as u32)),
+    };
+    Some(ChipInfo {
+        vendor: v["vendor"].as_str()?.into(),
+        name: v["name"].as_str()?.into(),
+        size: v["size"].as_i64()?,
+        id,
+    })
+}
+
+fn encode_wp_mode(mode: WpMode) -> Value {
+    json!(match mode {
+        WpMode::Disabled => "disabled",
+        WpMode::Hardware => "hardware",
+        WpMode::PowerCycle => "power_cycle",
+        WpMode::Permanent => "permanent",
+    })
+}
+
+fn decode_wp_mode(v: &Value) -> Option<WpMode> {
+    Some(match v.as_str()? {
+        "disabled" => WpMode::Disabled,
+        "hardware" => WpMode::Hardware,
+        "power_cycle" => WpMode::PowerCycle,
+        "permanent" => WpMode::Permanent,
+        _ => return None,
+    })
+}
+
+fn encode_wp_range(range: &WpRange) -> Value {
+    json!({
+        "start": range.start,
+        "len": range.len,
+        "mode": range.mode.map(encode_wp_mode),
+    })
+}
+
+fn decode_wp_range(v: &Value) -> Option<WpRange> {
+    let mode = match &v["mode"] {
+        Value::Null => None,
+        mode => Some(decode_wp_mode(mode)?),
+    };
+    Some(WpRange {
+        start: v["start"].as_i64()?,
+        len: v["len"].as_i64()?,
+        mode,
+    })
+}
+
+fn encode_wp_status(status: &WpStatus) -> Value {
+    json!({ "mode": encode_wp_mode(status.mode), "range": status.range })
+}
+
+fn decode_wp_status(v: &Value) -> Option<WpStatus> {
+    Some(WpStatus {
+        mode: decode_wp_mode(&v["mode"])?,
+        range: (v["range"][0].as_i64()?, v["range"][1].as_i64()?),
+    })
+}
+
+fn encode_error(e: &FlashromError) -> Value {
+    match e {
+        FlashromError::Spawn { cmdline, source } => json!({
+            "type": "spawn",
+            "cmdline": cmdline,
+            "message": source.to_string(),
+        }),
+        FlashromError::Exit {
+            cmdline,
+            code,
+            stdout,
+            stderr,
+        } => json!({
+            "type": "exit",
+            "cmdline": cmdline,
+            "code": code,
+            "stdout": stdout,
+            "stderr": stderr,
+        }),
+        FlashromError::Signal {
+            cmdline,
+            signal,
+            stdout,
+            stderr,
+        } => json!({
+            "type": "signal",
+            "cmdline": cmdline,
+            "signal": signal,
+            "stdout": stdout,
+            "stderr": stderr,
+        }),
+        FlashromError::Timeout {
+            cmdline,
+            timeout,
+            stdout,
+            stderr,
+        } => json!({
+            "type": "timeout",
+            "cmdline": cmdline,
+            "timeout": timeout.as_secs_f64(),
+            "stdout": stdout,
+            "stderr": stderr,
+        }),
+        FlashromError::Cancelled {
+            cmdline,
+            stdout,
+            stderr,
+        } => json!({
+            "type": "cancelled",
+            "cmdline": cmdline,
+            "stdout": stdout,
+            "stderr": stderr,
+        }),
+        FlashromError::Parse(message) => json!({ "type": "parse", "message": message }),
+        FlashromError::Unsupported(message) => {
+            json!({ "type": "unsupported", "message": message })
+        }
+        FlashromError::Io(e) => json!({ "type": "io", "message": e.to_string() }),
+        // The function name of a Lib error can't be recreated, but its
+        // message can.
+        e => json!({ "type": "other", "message": e.to_string() }),
+    }
+}
+
+fn decode_error(v: &Value) -> Option<FlashromError> {
+    let string = |key: &str| v[key].as_str().map(String::from);
+    Some(match v["type"].as_str()? {
+        "spawn" => FlashromError::Spawn {
+            cmdline: string("cmdline")?,
+            source: io::Error::other(string("message")?),
+        },
+        "exit" => FlashromError::Exit {
+            cmdline: string("cmdline")?,
+            code: v["code"].as_i64()? as i32,
+            stdout: string("stdout")?,
+            stderr: string("stderr")?,
+        },
+        "signal" => FlashromError::Signal {
+            cmdline: string("cmdline")?,
+            signal: v["signal"].as_i64()? as i32,
+            stdout: string("stdout")?,
+            stderr: string("stderr")?,
+        },
+        "timeout" => FlashromError::Timeout {
+            cmdline: string("cmdline")?,
+            timeout: Duration::from_secs_f64(v["timeout"].as_f64()?),
+            stdout: string("stdout")?,
+            stderr: string("stderr")?,
+        },
+        "cancelled" => FlashromError::Cancelled {
+            cmdline: string("cmdline")?,
+            stdout: string("stdout")?,
+            stderr: string("stderr")?,
+        },
+        "parse" => FlashromError::Parse(string("message")?),
+        "unsupported" => FlashromError::Unsupported(string("message")?),
+        "io" => FlashromError::Io(io::Error::other(string("message")?)),
+        "other" => FlashromError::Other(string("message")?),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recorder, Replayer};
+    use flashrom::{
+        Capabilities, ErrorKind, Fault, Flashrom, FlashromVersion, MockFlashrom, MockOp, TempFile,
+    };
+
+    #[test]
+    fn record_and_replay() {
+        let trace = TempFile::new("trace").unwrap();
+        let image = TempFile::new("image").unwrap();
+
+        let version = FlashromVersion::parse("flashrom v0.9.9  : 8c7a2e4 : Jun 10 2021");
+        let caps = Capabilities::of_version(version);
+        let mock = MockFlashrom::new((0..=255).collect()).with_capabilities(caps);
+        mock.inject(MockOp::Erase, Fault::Fail(ErrorKind::WriteProtected));
+        let recorder = Recorder::create(Box::new(mock), trace.path()).unwrap();
+        recorder.read(image.path()).unwrap();
+        let erase_error = recorder.erase().unwrap_err().to_string();
+        assert!(recorder.verify_slice(&[0; 256]).is_err());
+        let status = recorder.get_wp_status().unwrap();
+        // Keep the mock, which allows only one at a time, until done.
+        let _mock = recorder.into_inner();
+
+        std::fs::write(image.path(), []).unwrap();
+        let replayer = Replayer::open(trace.path()).unwrap();
+        assert_eq!(replayer.programmer().name, "dummy");
+        assert_eq!(replayer.capabilities(), caps);
+        replayer.read(image.path()).unwrap();
+        assert_eq!(image.read().unwrap(), (0..=255).collect::<Vec<u8>>());
+        let e = replayer.erase().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WriteProtected);
+        assert_eq!(e.to_string(), erase_error);
+        // The result is replayed even though the image differs.
+        assert!(replayer
+            .verify_slice(&(0..=255).collect::<Vec<u8>>())
+            .is_err());
+        // Calls out of order fail.
+        assert!(replayer.erase().is_err());
+        assert!(replayer.get_size().is_err());
+
+        let replayer = Replayer::open(trace.path()).unwrap();
+        replayer.read(image.path()).unwrap();
+        replayer.erase().unwrap_err();
+        replayer.verify_slice(&[0; 256]).unwrap_err();
+        assert_eq!(replayer.get_wp_status().unwrap(), status);
+
+        std::fs::remove_dir_all(super::files_dir(trace.path())).unwrap();
+    }
+}
@@ -99,6 +99,41 @@ pub fn construct_layout_file<F: Write>(mut target: F, ls: &LayoutSizes) -> std::
     writeln!(target, "{:x}:{:x} TOP_QUAD", ls.top_quad_bottom, ls.rom_top)
 }
 
+/// Size of the largest erase block flashrom uses short of erasing the whole chip.
+const MAX_ERASE_BLOCK: i64 = 64 << 10;
+
+/// Write a layout with the region `PROTECTED` covering `range` as (start, len)
+/// and regions `BELOW` and `ABOVE` on either side of it, returning the names
+/// of the regions outside `range`.
+///
+/// Writing next to a protected range may require erasing a block that overlaps
+/// it, so `BELOW` and `ABOVE` stop at the erase block boundaries surrounding
+/// `range`. They are also limited to a quarter of the flash, since flashrom
+/// prefers erasing the whole chip when most of it is being written. Regions
+/// which would be empty are left out.
+pub fn construct_range_layout_file<F: Write>(
+    mut target: F,
+    (start, len): (i64, i64),
+    rom_sz: i64,
+) -> std::io::Result<Vec<&'static str>> {
+    let max_len = rom_sz / 4;
+    let mut unprotected = Vec::new();
+    let below_end = start / MAX_ERASE_BLOCK * MAX_ERASE_BLOCK;
+    if below_end > 0 {
+        let below_start = std::cmp::max(0, below_end - max_len);
+        writeln!(target, "{:x}:{:x} BELOW", below_start, below_end - 1)?;
+        unprotected.push("BELOW");
+    }
+    writeln!(target, "{:x}:{:x} PROTECTED", start, start + len - 1)?;
+    let above_start = (start + len + MAX_ERASE_BLOCK - 1) / MAX_ERASE_BLOCK * MAX_ERASE_BLOCK;
+    if above_start < rom_sz {
+        let above_end = std::cmp::min(rom_sz, above_start + max_len);
+        writeln!(target, "{:x}:{:x} ABOVE", above_start, above_end - 1)?;
+        unprotected.push("ABOVE");
+    }
+    Ok(unprotected)
+}
+
 pub fn toggle_hw_wp(dis: bool) -> Result<(), String> {
     // The easist way to toggle the hardware write-protect is
     // to {dis}connect the battery (and/or open the WP screw).
@@ -129,9 +164,9 @@ fn pause() {
     let mut stdout = std::io::stdout();
     // We want the cursor to stay at the end of the line, so we print without a newline
     // and flush manually.
-    stdout.write(b"Press any key to continue...").unwrap();
+    stdout.write_all(b"Press any key to continue...").unwrap();
     stdout.flush().unwrap();
-    std::io::stdin().read(&mut [0]).unwrap();
+    let _ = std::io::stdin().read(&mut [0]).unwrap();
 }
 
 pub fn get_hardware_wp() -> std::result::Result<bool, String> {
@@ -155,10 +190,10 @@ pub fn collect_crosssystem() -> Result<String, String> {
 fn parse_crosssystem(s: &str) -> Result<(Vec<&str>, bool), &'static str> {
     // grep -v 'fwid +=' | grep -v 'hwid +='
     let sysinfo = s
-        .split_terminator("\n")
+        .split_terminator('\n')
         .filter(|s| !s.contains("fwid +=") && !s.contains("hwid +="));
 
-    let state_line = match sysinfo.clone().filter(|s| s.starts_with("wpsw_cur")).next() {
+    let state_line = match sysinfo.clone().find(|s| s.starts_with("wpsw_cur")) {
         None => return Err("No wpsw_cur in system info"),
         Some(line) => line,
     };
@@ -172,21 +207,15 @@ fn parse_crosssystem(s: &str) -> Result<(Vec<&str>, bool), &'static str> {
         .parse::<u32>();
 
     match wp_s_val {
-        Ok(v) => {
-            if v == 1 {
-                return Ok((sysinfo.collect(), true));
-            } else if v == 0 {
-                return Ok((sysinfo.collect(), false));
-            } else {
-                return Err("Unknown state value");
-            }
-        }
-        Err(_) => return Err("Cannot parse state value"),
+        Ok(1) => Ok((sysinfo.collect(), true)),
+        Ok(0) => Ok((sysinfo.collect(), false)),
+        Ok(_) => Err("Unknown state value"),
+        Err(_) => Err("Cannot parse state value"),
     }
 }
 
 pub fn translate_command_error(output: &std::process::Output) -> std::io::Error {
-    use std::io::{Error, ErrorKind};
+    use std::io::Error;
     // There is two cases on failure;
     //  i. ) A bad exit code,
     //  ii.) A SIG killed us.
@@ -197,12 +226,9 @@ pub fn translate_command_error(output: &std::process::Output) -> std::io::Error
                 String::from_utf8_lossy(&output.stderr),
                 code
             );
-            Error::new(ErrorKind::Other, e)
+            Error::other(e)
         }
-        None => Error::new(
-            ErrorKind::Other,
-            "Process terminated by a signal".to_string(),
-        ),
+        None => Error::other("Process terminated by a signal"),
     }
 }
 
@@ -230,6 +256,59 @@ mod tests {
         );
     }
 
+    #[test]
+    fn construct_range_layout_file() {
+        use super::construct_range_layout_file;
+
+        let mut buf = Vec::new();
+        assert_eq!(
+            construct_range_layout_file(&mut buf, (0x40000, 0x40000), 0x100000).unwrap(),
+            vec!["BELOW", "ABOVE"]
+        );
+        assert_eq!(
+            &buf[..],
+            &b"0:3ffff BELOW\n\
+               40000:7ffff PROTECTED\n\
+               80000:bffff ABOVE\n"[..]
+        );
+
+        // Unprotected regions keep clear of erase blocks overlapping the range.
+        let mut buf = Vec::new();
+        assert_eq!(
+            construct_range_layout_file(&mut buf, (0x41000, 0x1000), 0x100000).unwrap(),
+            vec!["BELOW", "ABOVE"]
+        );
+        assert_eq!(
+            &buf[..],
+            &b"0:3ffff BELOW\n\
+               41000:41fff PROTECTED\n\
+               50000:8ffff ABOVE\n"[..]
+        );
+
+        let mut buf = Vec::new();
+        assert_eq!(
+            construct_range_layout_file(&mut buf, (0, 0x1000), 0x100000).unwrap(),
+            vec!["ABOVE"]
+        );
+        assert_eq!(&buf[..], &b"0:fff PROTECTED\n10000:4ffff ABOVE\n"[..]);
+
+        // Unprotected regions are limited to a quarter of the flash.
+        let mut buf = Vec::new();
+        assert_eq!(
+            construct_range_layout_file(&mut buf, (0xf0000, 0x10000), 0x100000).unwrap(),
+            vec!["BELOW"]
+        );
+        assert_eq!(&buf[..], &b"b0000:effff BELOW\nf0000:fffff PROTECTED\n"[..]);
+
+        let mut buf = Vec::new();
+        assert!(
+            construct_range_layout_file(&mut buf, (0, 0x100000), 0x100000)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(&buf[..], &b"0:fffff PROTECTED\n"[..]);
+    }
+
     #[test]
     fn get_layout_sizes() {
         use super::get_layout_sizes;